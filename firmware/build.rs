@@ -1,7 +1,52 @@
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::PathBuf;
+use std::process;
+
+// The firmware's own keycode table and sizes, so the keymap is checked
+// against what the firmware is built with
+#[allow(dead_code)]
+#[path = "src/keycode.rs"]
+mod keycode;
+#[path = "src/matrix_size.rs"]
+mod matrix_size;
+#[allow(dead_code)]
+#[path = "src/recording.rs"]
+mod recording;
+
+#[path = "build/keymap_compiler.rs"]
+mod keymap_compiler;
+
+const DEFAULT_POLL_INTERVAL_MS: u8 = 10;
+const DEFAULT_KEYMAP: &str = "keymap.toml";
+
+// Stops the build with `msg` alone, which is clearer than a panic with
+// its backtrace
+fn build_error(msg: &str) -> ! {
+    eprintln!("error: {}", msg);
+    process::exit(1);
+}
+
+fn poll_interval_ms() -> u8 {
+    // bInterval of a full-speed interrupt endpoint is 1-255 ms (USB 2.0 9.6.6)
+    match env::var("KB789_POLL_INTERVAL_MS") {
+        Ok(value) => match value.parse::<u8>() {
+            Ok(ms) if ms >= 1 => ms,
+            _ => build_error(&format!(
+                "KB789_POLL_INTERVAL_MS must be between 1 and 255, got {:?}",
+                value
+            )),
+        },
+        Err(_) => DEFAULT_POLL_INTERVAL_MS,
+    }
+}
+
+fn layout(path: &str) -> String {
+    let text =
+        fs::read_to_string(path).unwrap_or_else(|err| build_error(&format!("{}: {}", path, err)));
+    keymap_compiler::compile(path, &text).unwrap_or_else(|msg| build_error(&msg))
+}
 
 fn main() {
     // Put the linker script somewhere the linker can find it
@@ -12,7 +57,26 @@ fn main() {
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
-    // Only re-run the build script when memory.x is changed,
-    // instead of when any part of the source code changes.
+    let mut config = File::create(out.join("config.rs")).unwrap();
+    writeln!(
+        config,
+        "pub const POLL_INTERVAL_MS: u8 = {};",
+        poll_interval_ms()
+    )
+    .unwrap();
+
+    // Relative to the package root
+    let keymap = env::var("KB789_KEYMAP").unwrap_or_else(|_| DEFAULT_KEYMAP.to_string());
+    fs::write(out.join("layout.rs"), layout(&keymap)).unwrap();
+
+    // Only re-run the build script when its inputs change, instead of when
+    // any part of the source code changes.
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed={}", keymap);
+    println!("cargo:rerun-if-changed=build/keymap_compiler.rs");
+    println!("cargo:rerun-if-changed=src/keycode.rs");
+    println!("cargo:rerun-if-changed=src/matrix_size.rs");
+    println!("cargo:rerun-if-changed=src/recording.rs");
+    println!("cargo:rerun-if-env-changed=KB789_POLL_INTERVAL_MS");
+    println!("cargo:rerun-if-env-changed=KB789_KEYMAP");
 }
@@ -0,0 +1,855 @@
+// The keymap.toml compiler behind build.rs. The lib includes it too, so
+// its tests run on the host with the others.
+
+use std::fmt::Write as _;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::keycode::Keycode;
+use crate::matrix_size::{COLS, ROWS};
+use crate::recording::SLOTS;
+
+// keymap::MAX_LAYERS
+const MAX_LAYERS: usize = 32;
+// combo::Combo keeps its progress in a u32
+const MAX_COMBO_KEYS: usize = 32;
+// TD(n) and M(n) take a u8
+const MAX_INDEX: usize = 256;
+// leader::MAX_SEQUENCE
+const MAX_LEADER_KEYS: usize = 8;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeymapFile {
+    #[serde(default)]
+    auto_shift: AutoShift,
+    #[serde(default)]
+    repeat: Repeat,
+    layers: Vec<Layer>,
+    #[serde(default)]
+    dances: Vec<Dance>,
+    #[serde(default)]
+    combos: Vec<Combo>,
+    #[serde(default)]
+    macros: Vec<Macro>,
+    #[serde(default)]
+    leader: Leader,
+}
+
+// Used by AS() actions that give no timeout of their own
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AutoShift {
+    timeout_ms: u16,
+}
+
+impl Default for AutoShift {
+    fn default() -> Self {
+        AutoShift { timeout_ms: 200 }
+    }
+}
+
+// Used by RPT() actions that give no rate of their own
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Repeat {
+    delay_ms: u16,
+    interval_ms: u16,
+}
+
+impl Default for Repeat {
+    fn default() -> Self {
+        Repeat {
+            delay_ms: 300,
+            interval_ms: 33,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Layer {
+    name: Option<String>,
+    keys: Spanned<Vec<Spanned<Vec<Spanned<String>>>>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Dance {
+    steps: Spanned<Vec<DanceStep>>,
+    timeout_ms: u16,
+}
+
+// dance::Step; a missing `hold` is No
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DanceStep {
+    tap: Spanned<String>,
+    hold: Option<Spanned<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Combo {
+    keys: Spanned<Vec<Spanned<(usize, usize)>>>,
+    action: Spanned<String>,
+    timeout_ms: u16,
+    release: Release,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Release {
+    AnyKey,
+    AllKeys,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Macro {
+    steps: Vec<Step>,
+}
+
+// leader::Config, and the sequences for leader::Trie
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Leader {
+    #[serde(default = "Leader::default_key_timeout_ms")]
+    key_timeout_ms: u16,
+    #[serde(default = "Leader::default_sequence_timeout_ms")]
+    sequence_timeout_ms: u16,
+    #[serde(default = "Leader::default_fallback")]
+    fallback: Fallback,
+    #[serde(default)]
+    sequences: Vec<LeaderSequence>,
+}
+
+impl Leader {
+    fn default_key_timeout_ms() -> u16 {
+        300
+    }
+
+    fn default_sequence_timeout_ms() -> u16 {
+        1000
+    }
+
+    fn default_fallback() -> Fallback {
+        Fallback::Replay
+    }
+}
+
+impl Default for Leader {
+    fn default() -> Self {
+        Leader {
+            key_timeout_ms: Leader::default_key_timeout_ms(),
+            sequence_timeout_ms: Leader::default_sequence_timeout_ms(),
+            fallback: Leader::default_fallback(),
+            sequences: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Fallback {
+    Drop,
+    Replay,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LeaderSequence {
+    keys: Spanned<Vec<Spanned<String>>>,
+    steps: Vec<Step>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Step {
+    Press(Spanned<String>),
+    Release(Spanned<String>),
+    Tap(Spanned<String>),
+    Text(String),
+    DelayMs(u16),
+}
+
+// The keymap file being compiled, for error messages that point into it
+struct Source<'a> {
+    path: &'a str,
+    text: &'a str,
+}
+
+impl Source<'_> {
+    fn error<T>(&self, at: &Spanned<T>, msg: &str) -> String {
+        let before = &self.text[..at.start()];
+        let line = before.matches('\n').count() + 1;
+        let col = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+        format!("{}:{}:{}: {}", self.path, line, col, msg)
+    }
+}
+
+fn keycode(name: &str) -> Result<Keycode, String> {
+    name.parse()
+        .map_err(|_| format!("unknown keycode `{}`", name))
+}
+
+fn key_expr(key: Keycode) -> String {
+    format!("crate::keycode::Keycode::{:?}", key)
+}
+
+fn number<T: FromStr>(arg: &str) -> Result<T, String> {
+    arg.parse()
+        .map_err(|_| format!("`{}` is not a valid number here", arg))
+}
+
+// Compiles one action, e.g. "A", "MO(1)" or "MT(LeftShift, Z)", to a
+// keymap::Action expression.
+fn action(text: &str, file: &KeymapFile) -> Result<String, String> {
+    let text = text.trim();
+    let (name, inner) = match text.find('(') {
+        Some(open) if text.ends_with(')') => {
+            (text[..open].trim(), Some(&text[open + 1..text.len() - 1]))
+        }
+        Some(_) => return Err(format!("missing `)` in `{}`", text)),
+        None => (text, None),
+    };
+    let args: Vec<&str> = match inner {
+        Some(inner) => inner.split(',').map(str::trim).collect(),
+        None => Vec::new(),
+    };
+    let arity = |counts: &[usize]| -> Result<(), String> {
+        if counts.contains(&args.len()) {
+            Ok(())
+        } else {
+            let counts: Vec<String> = counts.iter().map(|n| n.to_string()).collect();
+            Err(format!(
+                "`{}` takes {} argument(s), got {}",
+                name,
+                counts.join(" or "),
+                args.len()
+            ))
+        }
+    };
+    let layer = |arg: &str| -> Result<u8, String> {
+        let layer: usize = number(arg)?;
+        if layer < file.layers.len() {
+            Ok(layer as u8)
+        } else {
+            Err(format!(
+                "layer {} does not exist, the keymap has {} layer(s)",
+                layer,
+                file.layers.len()
+            ))
+        }
+    };
+    // An index into the keymap's tap dances or macros
+    let index = |arg: &str, what: &str, len: usize| -> Result<usize, String> {
+        let idx: usize = number(arg)?;
+        if idx < len {
+            Ok(idx)
+        } else {
+            Err(format!(
+                "{} {} does not exist, the keymap has {} {}(s)",
+                what, idx, len, what
+            ))
+        }
+    };
+    let expr = match name {
+        "No" | "Trans" | "Leader" | "CapsWord" | "KeyLock" => {
+            arity(&[0])?;
+            name.to_string()
+        }
+        "MO" | "TG" | "TO" | "DF" | "OSL" => {
+            arity(&[1])?;
+            let variant = match name {
+                "MO" => "Momentary",
+                "TG" => "Toggle",
+                "TO" => "To",
+                "DF" => "Default",
+                _ => "OneShot",
+            };
+            format!("{}({})", variant, layer(args[0])?)
+        }
+        "MT" => {
+            arity(&[2])?;
+            let hold = keycode(args[0])?;
+            if !hold.is_modifier() {
+                return Err(format!("`{}` is not a modifier", args[0]));
+            }
+            format!(
+                "ModTap {{ hold: {}, tap: {} }}",
+                key_expr(hold),
+                key_expr(keycode(args[1])?)
+            )
+        }
+        "LT" => {
+            arity(&[2])?;
+            format!(
+                "LayerTap {{ layer: {}, tap: {} }}",
+                layer(args[0])?,
+                key_expr(keycode(args[1])?)
+            )
+        }
+        "TD" => {
+            arity(&[1])?;
+            let idx = index(args[0], "tap dance", file.dances.len())?;
+            format!("TapDance({})", idx)
+        }
+        "M" => {
+            arity(&[1])?;
+            let idx = index(args[0], "macro", file.macros.len())?;
+            format!("Macro({})", idx)
+        }
+        "REC" | "PLAY" => {
+            arity(&[1])?;
+            let slot: usize = number(args[0])?;
+            if slot >= SLOTS {
+                return Err(format!(
+                    "dynamic macro slot {} does not exist, the firmware has {} slot(s)",
+                    slot, SLOTS
+                ));
+            }
+            let variant = if name == "REC" {
+                "RecordMacro"
+            } else {
+                "PlayRecorded"
+            };
+            format!("{}({})", variant, slot)
+        }
+        "AS" => {
+            arity(&[1, 2])?;
+            let timeout_ms = match args.get(1) {
+                Some(arg) => number(arg)?,
+                None => file.auto_shift.timeout_ms,
+            };
+            format!(
+                "AutoShift {{ key: {}, timeout_ms: {} }}",
+                key_expr(keycode(args[0])?),
+                timeout_ms
+            )
+        }
+        "RPT" => {
+            arity(&[1, 3])?;
+            let (delay_ms, interval_ms) = match args.len() {
+                3 => (number(args[1])?, number(args[2])?),
+                _ => (file.repeat.delay_ms, file.repeat.interval_ms),
+            };
+            format!(
+                "Repeat {{ key: {}, rate: crate::keymap::RepeatRate {{ delay_ms: {}, interval_ms: {} }} }}",
+                key_expr(keycode(args[0])?),
+                delay_ms,
+                interval_ms
+            )
+        }
+        "UC" => {
+            // Taken whole, so UC(,) works
+            let mut chars = inner.unwrap_or("").chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => format!("Unicode({:?})", c),
+                _ => return Err(format!("`UC` takes one character, got `{}`", text)),
+            }
+        }
+        "UCMODE" => {
+            arity(&[1])?;
+            match args[0] {
+                "Linux" | "MacOs" | "WinCompose" => {
+                    format!("UnicodeMode(crate::unicode::Mode::{})", args[0])
+                }
+                mode => {
+                    return Err(format!(
+                        "unknown Unicode mode `{}`, expected Linux, MacOs or WinCompose",
+                        mode
+                    ))
+                }
+            }
+        }
+        _ if inner.is_none() => format!("Key({})", key_expr(keycode(name)?)),
+        _ => return Err(format!("unknown action `{}`", name)),
+    };
+    Ok(format!("crate::keymap::Action::{}", expr))
+}
+
+// action() for a string in the file, with errors pointing at it
+fn spanned_action(
+    text: &Spanned<String>,
+    src: &Source,
+    file: &KeymapFile,
+) -> Result<String, String> {
+    action(text.get_ref(), file).map_err(|msg| src.error(text, &msg))
+}
+
+fn layers(out: &mut String, src: &Source, file: &KeymapFile) -> Result<(), String> {
+    if file.layers.is_empty() || file.layers.len() > MAX_LAYERS {
+        return Err(format!(
+            "{}: needs 1 to {} layers, has {}",
+            src.path,
+            MAX_LAYERS,
+            file.layers.len()
+        ));
+    }
+    writeln!(
+        out,
+        "pub static LAYERS: [crate::keymap::Layer<{{ crate::board::ROWS }}, {{ crate::board::COLS }}>; {}] = [",
+        file.layers.len()
+    )
+    .unwrap();
+    for (idx, layer) in file.layers.iter().enumerate() {
+        let name = layer
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("layer {}", idx));
+        if layer.keys.get_ref().len() != ROWS {
+            return Err(src.error(
+                &layer.keys,
+                &format!(
+                    "{} has {} rows, the board has {}",
+                    name,
+                    layer.keys.get_ref().len(),
+                    ROWS
+                ),
+            ));
+        }
+        writeln!(out, "    // {}", name).unwrap();
+        writeln!(out, "    [").unwrap();
+        for row in layer.keys.get_ref() {
+            if row.get_ref().len() != COLS {
+                return Err(src.error(
+                    row,
+                    &format!(
+                        "row has {} keys, the board has {} columns",
+                        row.get_ref().len(),
+                        COLS
+                    ),
+                ));
+            }
+            let actions = row
+                .get_ref()
+                .iter()
+                .map(|key| spanned_action(key, src, file))
+                .collect::<Result<Vec<_>, _>>()?;
+            writeln!(out, "        [{}],", actions.join(", ")).unwrap();
+        }
+        writeln!(out, "    ],").unwrap();
+    }
+    writeln!(out, "];").unwrap();
+    Ok(())
+}
+
+fn dances(out: &mut String, src: &Source, file: &KeymapFile) -> Result<(), String> {
+    if file.dances.len() > MAX_INDEX {
+        return Err(format!(
+            "{}: at most {} tap dances, has {}",
+            src.path,
+            MAX_INDEX,
+            file.dances.len()
+        ));
+    }
+    writeln!(
+        out,
+        "pub static DANCES: [crate::dance::TapDance; {}] = [",
+        file.dances.len()
+    )
+    .unwrap();
+    for dance in &file.dances {
+        if dance.steps.get_ref().is_empty() {
+            return Err(src.error(&dance.steps, "a tap dance needs at least one step"));
+        }
+        writeln!(out, "    crate::dance::TapDance {{").unwrap();
+        writeln!(out, "        steps: &[").unwrap();
+        for step in dance.steps.get_ref() {
+            let tap = spanned_action(&step.tap, src, file)?;
+            let hold = match &step.hold {
+                Some(hold) => spanned_action(hold, src, file)?,
+                None => "crate::keymap::Action::No".to_string(),
+            };
+            writeln!(
+                out,
+                "            crate::dance::Step {{ tap: {}, hold: {} }},",
+                tap, hold
+            )
+            .unwrap();
+        }
+        writeln!(out, "        ],").unwrap();
+        writeln!(out, "        timeout_ms: {},", dance.timeout_ms).unwrap();
+        writeln!(out, "    }},").unwrap();
+    }
+    writeln!(out, "];").unwrap();
+    Ok(())
+}
+
+fn combos(out: &mut String, src: &Source, file: &KeymapFile) -> Result<(), String> {
+    writeln!(
+        out,
+        "pub static COMBOS: [crate::combo::Combo; {}] = [",
+        file.combos.len()
+    )
+    .unwrap();
+    for combo in &file.combos {
+        let keys = combo.keys.get_ref();
+        if keys.is_empty() || keys.len() > MAX_COMBO_KEYS {
+            return Err(src.error(
+                &combo.keys,
+                &format!(
+                    "a combo needs 1 to {} keys, has {}",
+                    MAX_COMBO_KEYS,
+                    keys.len()
+                ),
+            ));
+        }
+        for (idx, key) in keys.iter().enumerate() {
+            let (row, col) = *key.get_ref();
+            if row >= ROWS || col >= COLS {
+                return Err(src.error(
+                    key,
+                    &format!("[{}, {}] is outside the {}x{} matrix", row, col, ROWS, COLS),
+                ));
+            }
+            if keys[..idx]
+                .iter()
+                .any(|prev| prev.get_ref() == key.get_ref())
+            {
+                return Err(src.error(key, &format!("[{}, {}] is listed twice", row, col)));
+            }
+        }
+        let positions: Vec<String> = keys
+            .iter()
+            .map(|key| format!("{:?}", key.get_ref()))
+            .collect();
+        let action = spanned_action(&combo.action, src, file)?;
+        writeln!(out, "    crate::combo::Combo {{").unwrap();
+        writeln!(out, "        keys: &[{}],", positions.join(", ")).unwrap();
+        writeln!(out, "        action: {},", action).unwrap();
+        writeln!(out, "        timeout_ms: {},", combo.timeout_ms).unwrap();
+        writeln!(
+            out,
+            "        release: crate::combo::Release::{:?},",
+            combo.release
+        )
+        .unwrap();
+        writeln!(out, "    }},").unwrap();
+    }
+    writeln!(out, "];").unwrap();
+    Ok(())
+}
+
+// Compiles macro steps to a macros::Macro expression.
+fn steps(steps: &[Step], src: &Source) -> Result<String, String> {
+    let key = |name: &Spanned<String>| -> Result<String, String> {
+        let key = keycode(name.get_ref()).map_err(|msg| src.error(name, &msg))?;
+        Ok(key_expr(key))
+    };
+    let steps = steps
+        .iter()
+        .map(|step| {
+            Ok(match step {
+                Step::Press(name) => format!("crate::macros::Step::Press({})", key(name)?),
+                Step::Release(name) => format!("crate::macros::Step::Release({})", key(name)?),
+                Step::Tap(name) => format!("crate::macros::Step::Tap({})", key(name)?),
+                Step::Text(text) => format!("crate::macros::Step::Text({:?})", text),
+                Step::DelayMs(ms) => format!("crate::macros::Step::Delay {{ ms: {} }}", ms),
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    Ok(format!("&[{}]", steps.join(", ")))
+}
+
+fn macros(out: &mut String, src: &Source, file: &KeymapFile) -> Result<(), String> {
+    if file.macros.len() > MAX_INDEX {
+        return Err(format!(
+            "{}: at most {} macros, has {}",
+            src.path,
+            MAX_INDEX,
+            file.macros.len()
+        ));
+    }
+    writeln!(
+        out,
+        "pub static MACROS: [crate::macros::Macro; {}] = [",
+        file.macros.len()
+    )
+    .unwrap();
+    for m in &file.macros {
+        writeln!(out, "    {},", steps(&m.steps, src)?).unwrap();
+    }
+    writeln!(out, "];").unwrap();
+    Ok(())
+}
+
+// Sequences are checked here; leader::Trie::new() fails the build if they
+// need more trie nodes than it has.
+fn leader(out: &mut String, src: &Source, file: &KeymapFile) -> Result<(), String> {
+    let leader = &file.leader;
+    writeln!(
+        out,
+        "pub const LEADER: crate::leader::Config = crate::leader::Config {{"
+    )
+    .unwrap();
+    writeln!(out, "    key_timeout_ms: {},", leader.key_timeout_ms).unwrap();
+    writeln!(
+        out,
+        "    sequence_timeout_ms: {},",
+        leader.sequence_timeout_ms
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "    fallback: crate::leader::Fallback::{:?},",
+        leader.fallback
+    )
+    .unwrap();
+    writeln!(out, "}};").unwrap();
+    writeln!(
+        out,
+        "pub static LEADER_TRIE: crate::leader::Trie = crate::leader::Trie::new(&["
+    )
+    .unwrap();
+    let mut seen: Vec<Vec<Keycode>> = Vec::new();
+    for sequence in &leader.sequences {
+        let names = sequence.keys.get_ref();
+        if names.is_empty() || names.len() > MAX_LEADER_KEYS {
+            return Err(src.error(
+                &sequence.keys,
+                &format!(
+                    "a leader sequence needs 1 to {} keys, has {}",
+                    MAX_LEADER_KEYS,
+                    names.len()
+                ),
+            ));
+        }
+        let mut keys = Vec::new();
+        for name in names {
+            let key = keycode(name.get_ref()).map_err(|msg| src.error(name, &msg))?;
+            // The leader skips modifiers, so they cannot be part of a sequence
+            if key.is_modifier() || key == Keycode::No {
+                return Err(src.error(
+                    name,
+                    &format!("`{}` cannot be part of a leader sequence", name.get_ref()),
+                ));
+            }
+            keys.push(key);
+        }
+        if seen.contains(&keys) {
+            return Err(src.error(&sequence.keys, "leader sequence is listed twice"));
+        }
+        let exprs: Vec<String> = keys.iter().map(|key| key_expr(*key)).collect();
+        writeln!(out, "    crate::leader::Sequence {{").unwrap();
+        writeln!(out, "        keys: &[{}],", exprs.join(", ")).unwrap();
+        writeln!(out, "        steps: {},", steps(&sequence.steps, src)?).unwrap();
+        writeln!(out, "    }},").unwrap();
+        seen.push(keys);
+    }
+    writeln!(out, "]);").unwrap();
+    Ok(())
+}
+
+// Checks the keymap file `path`, whose contents are `text`, and writes its
+// layers, tap dances, combos, macros and leader sequences out as static
+// tables for main.rs.
+// Errors point into the file as path:line:col where they can.
+pub fn compile(path: &str, text: &str) -> Result<String, String> {
+    let src = Source { path, text };
+    let file: KeymapFile = toml::from_str(text).map_err(|err| match err.line_col() {
+        Some((line, col)) => format!("{}:{}:{}: {}", path, line + 1, col + 1, err),
+        None => format!("{}: {}", path, err),
+    })?;
+    let mut out = format!("// Generated by build.rs from {}\n\n", path);
+    layers(&mut out, &src, &file)?;
+    out.push('\n');
+    dances(&mut out, &src, &file)?;
+    out.push('\n');
+    combos(&mut out, &src, &file)?;
+    out.push('\n');
+    macros(&mut out, &src, &file)?;
+    out.push('\n');
+    leader(&mut out, &src, &file)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sample keymaps are 3x2 like the board; these tests need updating if the
+    // board changes.
+    fn compile_sample(text: &str) -> Result<String, String> {
+        compile("test.toml", text)
+    }
+
+    #[test]
+    fn shipped_keymap_compiles() {
+        let layout = compile("keymap.toml", include_str!("../keymap.toml")).unwrap();
+        assert!(layout.contains("pub static LAYERS: [crate::keymap::Layer<{ crate::board::ROWS }, { crate::board::COLS }>; 4] = ["));
+        assert!(layout.contains("pub static DANCES: [crate::dance::TapDance; 1] = ["));
+        assert!(layout.contains("pub static COMBOS: [crate::combo::Combo; 4] = ["));
+        assert!(layout.contains("pub static MACROS: [crate::macros::Macro; 1] = ["));
+        assert!(layout.contains("keys: &[crate::keycode::Keycode::G, crate::keycode::Keycode::C],\n        steps: &[crate::macros::Step::Text(\"git commit\")],"));
+    }
+
+    #[test]
+    fn leader_compiles_to_a_trie() {
+        let layout = compile_sample(
+            r#"
+    [[layers]]
+    keys = [["Leader", "B"], ["C", "D"], ["E", "F"]]
+
+    [leader]
+    key_timeout_ms = 250
+    fallback = "drop"
+
+    [[leader.sequences]]
+    keys = ["G", "Semicolon"]
+    steps = [{ tap = "Home" }, { delay_ms = 10 }, { text = "//" }]
+    "#,
+        )
+        .unwrap();
+        for expr in &[
+            "pub const LEADER: crate::leader::Config = crate::leader::Config {\n    key_timeout_ms: 250,\n    sequence_timeout_ms: 1000,\n    fallback: crate::leader::Fallback::Drop,\n};",
+            "pub static LEADER_TRIE: crate::leader::Trie = crate::leader::Trie::new(&[",
+            "keys: &[crate::keycode::Keycode::G, crate::keycode::Keycode::Semicolon],",
+            "steps: &[crate::macros::Step::Tap(crate::keycode::Keycode::Home), crate::macros::Step::Delay { ms: 10 }, crate::macros::Step::Text(\"//\")],",
+        ] {
+            assert!(layout.contains(expr), "no `{}` in\n{}", expr, layout);
+        }
+    }
+
+    #[test]
+    fn leader_is_optional() {
+        let layout =
+            compile_sample("[[layers]]\nkeys = [[\"A\", \"B\"], [\"C\", \"D\"], [\"E\", \"F\"]]\n")
+                .unwrap();
+        assert!(layout.contains("fallback: crate::leader::Fallback::Replay,"));
+        assert!(layout.contains("crate::leader::Trie::new(&[\n]);"));
+    }
+
+    #[test]
+    fn actions_compile_to_keymap_actions() {
+        let layout = compile_sample(
+            r#"
+    [auto_shift]
+    timeout_ms = 170
+
+    [[layers]]
+    keys = [
+        ["Left Shift", "MT(LeftCtrl, Z)"],
+        ["AS(A)", "RPT(Up, 400, 20)"],
+        ["TD(0)", "REC(1)"],
+    ]
+
+    [[dances]]
+    timeout_ms = 200
+    steps = [{ tap = "D", hold = "LeftCtrl" }, { tap = "Trans" }]
+    "#,
+        )
+        .unwrap();
+        for expr in &[
+            "crate::keymap::Action::Key(crate::keycode::Keycode::LeftShift)",
+            "crate::keymap::Action::ModTap { hold: crate::keycode::Keycode::LeftCtrl, tap: crate::keycode::Keycode::Z }",
+            "crate::keymap::Action::AutoShift { key: crate::keycode::Keycode::A, timeout_ms: 170 }",
+            "crate::keymap::Action::Repeat { key: crate::keycode::Keycode::Up, rate: crate::keymap::RepeatRate { delay_ms: 400, interval_ms: 20 } }",
+            "crate::keymap::Action::TapDance(0)",
+            "crate::keymap::Action::RecordMacro(1)",
+            "crate::dance::Step { tap: crate::keymap::Action::Key(crate::keycode::Keycode::D), hold: crate::keymap::Action::Key(crate::keycode::Keycode::LeftCtrl) },",
+            "crate::dance::Step { tap: crate::keymap::Action::Trans, hold: crate::keymap::Action::No },",
+        ] {
+            assert!(layout.contains(expr), "no `{}` in\n{}", expr, layout);
+        }
+    }
+
+    // Each keymap with the error it should stop at
+    const ERRORS: &[(&str, &str)] = &[
+        (
+            "[[layers]]\nkeys = [[\"A\", \"B\"], [\"C\", \"Foo\"], [\"E\", \"F\"]]\n",
+            "test.toml:2:27: unknown keycode `Foo`",
+        ),
+        (
+            "[[layers]]\nkeys = [[\"A\", \"B\"], [\"C\", \"D\"]]\n",
+            "test.toml:2:8: layer 0 has 2 rows, the board has 3",
+        ),
+        (
+            "[[layers]]\nkeys = [[\"A\", \"B\"], [\"C\"], [\"E\", \"F\"]]\n",
+            "test.toml:2:21: row has 1 keys, the board has 2 columns",
+        ),
+        (
+            "[[layers]]\nkeys = [[\"A\", \"MO(1)\"], [\"C\", \"D\"], [\"E\", \"F\"]]\n",
+            "test.toml:2:15: layer 1 does not exist, the keymap has 1 layer(s)",
+        ),
+        (
+            "[[layers]]\nkeys = [[\"A\", \"B\"], [\"C\", \"D\"], [\"TD(0)\", \"F\"]]\n",
+            "test.toml:2:34: tap dance 0 does not exist, the keymap has 0 tap dance(s)",
+        ),
+        (
+            "[[layers]]\nkeys = [[\"A\", \"B\"], [\"C\", \"D\"], [\"E\", \"M(0)\"]]\n",
+            "test.toml:2:39: macro 0 does not exist, the keymap has 0 macro(s)",
+        ),
+        (
+            "[[layers]]\nkeys = [[\"REC(0)\", \"PLAY(2)\"], [\"C\", \"D\"], [\"E\", \"F\"]]\n",
+            "test.toml:2:20: dynamic macro slot 2 does not exist, the firmware has 2 slot(s)",
+        ),
+        (
+            "[[layers]]\nkeys = [[\"MT(A, B)\", \"B\"], [\"C\", \"D\"], [\"E\", \"F\"]]\n",
+            "test.toml:2:10: `A` is not a modifier",
+        ),
+        (
+            "[[layers]]\nkeys = [[\"A\", \"B\"], [\"C\", \"D\"], [\"E\", \"TD(0)\"]]\n\n\
+             [[dances]]\ntimeout_ms = 200\nsteps = [{ tap = \"A\", hold = \"TD(1)\" }]\n",
+            "test.toml:6:30: tap dance 1 does not exist, the keymap has 1 tap dance(s)",
+        ),
+        (
+            "[[layers]]\nkeys = [[\"A\", \"B\"], [\"C\", \"D\"], [\"E\", \"F\"]]\n\n\
+             [[dances]]\ntimeout_ms = 200\nsteps = []\n",
+            "test.toml:6:9: a tap dance needs at least one step",
+        ),
+        (
+            "[[layers]]\nkeys = [[\"A\", \"B\"], [\"C\", \"D\"], [\"E\", \"F\"]]\n\n\
+             [[combos]]\nkeys = [[0, 0], [3, 0]]\naction = \"Escape\"\ntimeout_ms = 50\nrelease = \"any_key\"\n",
+            "test.toml:5:17: [3, 0] is outside the 3x2 matrix",
+        ),
+        (
+            "[[layers]]\nkeys = [[\"A\", \"B\"], [\"C\", \"D\"], [\"E\", \"F\"]]\n\n\
+             [[combos]]\nkeys = [[0, 0], [0, 0]]\naction = \"Escape\"\ntimeout_ms = 50\nrelease = \"any_key\"\n",
+            "test.toml:5:17: [0, 0] is listed twice",
+        ),
+        (
+            "[[layers]]\nkeys = [[\"A\", \"B\"], [\"C\", \"D\"], [\"E\", \"F\"]]\n\n\
+             [[macros]]\nsteps = [{ tap = \"Foo\" }]\n",
+            "test.toml:5:18: unknown keycode `Foo`",
+        ),
+        (
+            "[[layers]]\nkeys = [[\"A\", \"B\"], [\"C\", \"D\"], [\"E\", \"F\"]]\n\n\
+             [[leader.sequences]]\nkeys = [\"G\", \"LeftShift\"]\nsteps = []\n",
+            "test.toml:5:14: `LeftShift` cannot be part of a leader sequence",
+        ),
+        (
+            "[[layers]]\nkeys = [[\"A\", \"B\"], [\"C\", \"D\"], [\"E\", \"F\"]]\n\n\
+             [[leader.sequences]]\nkeys = []\nsteps = []\n",
+            "test.toml:5:8: a leader sequence needs 1 to 8 keys, has 0",
+        ),
+        (
+            "[[layers]]\nkeys = [[\"A\", \"B\"], [\"C\", \"D\"], [\"E\", \"F\"]]\n\n\
+             [[leader.sequences]]\nkeys = [\"G\", \"C\"]\nsteps = []\n\n\
+             [[leader.sequences]]\nkeys = [\"g\", \"c\"]\nsteps = []\n",
+            "test.toml:9:8: leader sequence is listed twice",
+        ),
+        (
+            "[[layers]]\nkeys = [[\"A\", \"B\"], [\"C\", \"D\"], [\"E\", \"F\"]]\n\n\
+             [[leader.sequences]]\nkeys = [\"G\"]\nsteps = [{ press = \"Foo\" }]\n",
+            "test.toml:6:20: unknown keycode `Foo`",
+        ),
+        ("layers = []\n", "test.toml: needs 1 to 32 layers, has 0"),
+    ];
+
+    #[test]
+    fn errors_point_into_the_keymap() {
+        for (text, expected) in ERRORS {
+            assert_eq!(compile_sample(text).unwrap_err(), *expected, "in\n{}", text);
+        }
+    }
+
+    #[test]
+    fn toml_errors_carry_the_position() {
+        let err = compile_sample("[[layers]]\nkeys = [\n").unwrap_err();
+        assert!(err.starts_with("test.toml:3:1: "), "{}", err);
+    }
+}
@@ -3,10 +3,26 @@ MEMORY
   /* NOTE 1 K = 1 KiBi = 1024 bytes */
   /* TODO Adjust these memory regions to match your device memory layout */
   /* These values correspond to the LM3S6965, one of the few devices QEMU can emulate */
-  FLASH : ORIGIN = 0x08002000, LENGTH = 64K
+  /* 0x08000000-0x08002000 is dapboot, which starts the boot stub in BOOT
+     (see src/boot.rs). The 58K after FLASH is the staging area for
+     in-application updates followed by a page for the install record
+     (see src/update.rs), and the last 2K of the 128K part are kept for
+     data (see src/storage.rs). */
+  BOOT : ORIGIN = 0x08002000, LENGTH = 1K
+  FLASH : ORIGIN = 0x08002400, LENGTH = 58K
   RAM : ORIGIN = 0x20000000, LENGTH = 20K
 }
 
+/* The boot stub, ahead of the application's vector table */
+SECTIONS
+{
+  .boot ORIGIN(BOOT) :
+  {
+    KEEP(*(.boot.vectors));
+    *(.boot.text .boot.text.*);
+  } > BOOT
+} INSERT BEFORE .vector_table;
+
 /* This is where the call stack will be allocated. */
 /* The stack is of the full descending type. */
 /* You may want to use this variable to locate the call stack and static
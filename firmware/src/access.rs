@@ -0,0 +1,345 @@
+// Accessibility filters. They run on the keyboard, so they work the same
+// on any host, BIOS setup included. Slow keys and bounce keys filter the
+// matrix state between the ghost filter and the event queue; sticky keys
+// works on the keycodes the keymap produces.
+
+use crate::keycode::{KeySet, Keycode};
+use crate::KeyState;
+
+#[derive(Debug, Clone, Copy)]
+pub struct StickyConfig {
+    pub enabled: bool,
+    // A second tap locks the modifier until it is tapped a third time
+    pub lock_on_double_tap: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SlowConfig {
+    pub enabled: bool,
+    // A key registers only once it has been held this long
+    pub hold_ms: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BounceConfig {
+    pub enabled: bool,
+    // A press this soon after the same key's release is ignored
+    pub ignore_ms: u16,
+}
+
+// One-shot modifiers. A modifier tapped on its own stays down until the
+// next other key is released, so chords can be typed one key at a time.
+// Modifiers used in an ordinary chord behave as usual.
+pub struct StickyKeys {
+    config: StickyConfig,
+    last: KeySet,
+    latched: KeySet,
+    locked: KeySet,
+    // Held modifiers that another key was pressed with
+    chorded: KeySet,
+    // The key the latched modifiers apply to
+    target: Option<Keycode>,
+}
+
+impl StickyKeys {
+    pub fn new(config: StickyConfig) -> Self {
+        StickyKeys {
+            config,
+            last: KeySet::new(),
+            latched: KeySet::new(),
+            locked: KeySet::new(),
+            chorded: KeySet::new(),
+            target: None,
+        }
+    }
+
+    pub fn update(&mut self, keys: KeySet) -> KeySet {
+        if !self.config.enabled {
+            return keys;
+        }
+        let last = self.last;
+        self.last = keys;
+        for key in keys.difference(&last).iter() {
+            if key.is_modifier() {
+                continue;
+            }
+            for held in keys.iter().filter(|held| held.is_modifier()) {
+                self.chorded.insert(held);
+            }
+            if self.target.is_none() && !self.latched.is_empty() {
+                self.target = Some(key);
+            }
+        }
+        for key in last.difference(&keys).iter() {
+            if !key.is_modifier() {
+                if self.target == Some(key) {
+                    self.target = None;
+                    self.latched.clear();
+                }
+            } else if self.chorded.contains(key) {
+                self.chorded.remove(key);
+            } else if self.locked.contains(key) {
+                self.locked.remove(key);
+            } else if self.latched.contains(key) {
+                self.latched.remove(key);
+                if self.config.lock_on_double_tap {
+                    self.locked.insert(key);
+                }
+            } else {
+                self.latched.insert(key);
+            }
+        }
+        keys.union(&self.latched).union(&self.locked)
+    }
+}
+
+pub struct SlowKeys<const ROWS: usize, const COLS: usize> {
+    config: SlowConfig,
+    raw: KeyState<ROWS, COLS>,
+    pressed_at: [[u32; COLS]; ROWS],
+    filtered: KeyState<ROWS, COLS>,
+}
+
+impl<const ROWS: usize, const COLS: usize> SlowKeys<ROWS, COLS> {
+    pub fn new(config: SlowConfig) -> Self {
+        SlowKeys {
+            config,
+            raw: [[false; COLS]; ROWS],
+            pressed_at: [[0; COLS]; ROWS],
+            filtered: [[false; COLS]; ROWS],
+        }
+    }
+
+    // Releases pass at once; a key let go too early never registers.
+    pub fn update(&mut self, state: &KeyState<ROWS, COLS>, now: u32) -> KeyState<ROWS, COLS> {
+        if !self.config.enabled {
+            return *state;
+        }
+        for (row, state_row) in state.iter().enumerate() {
+            for (col, &pressed) in state_row.iter().enumerate() {
+                if pressed && !self.raw[row][col] {
+                    self.pressed_at[row][col] = now;
+                }
+                let held_ms = now.wrapping_sub(self.pressed_at[row][col]);
+                self.filtered[row][col] =
+                    pressed && (self.filtered[row][col] || held_ms >= self.config.hold_ms as u32);
+            }
+        }
+        self.raw = *state;
+        self.filtered
+    }
+}
+
+pub struct BounceKeys<const ROWS: usize, const COLS: usize> {
+    config: BounceConfig,
+    released_at: [[Option<u32>; COLS]; ROWS],
+    // Presses being ignored, until their release
+    ignored: KeyState<ROWS, COLS>,
+    filtered: KeyState<ROWS, COLS>,
+}
+
+impl<const ROWS: usize, const COLS: usize> BounceKeys<ROWS, COLS> {
+    pub fn new(config: BounceConfig) -> Self {
+        BounceKeys {
+            config,
+            released_at: [[None; COLS]; ROWS],
+            ignored: [[false; COLS]; ROWS],
+            filtered: [[false; COLS]; ROWS],
+        }
+    }
+
+    pub fn update(&mut self, state: &KeyState<ROWS, COLS>, now: u32) -> KeyState<ROWS, COLS> {
+        if !self.config.enabled {
+            return *state;
+        }
+        for (row, state_row) in state.iter().enumerate() {
+            for (col, &pressed) in state_row.iter().enumerate() {
+                if !pressed {
+                    if self.filtered[row][col] {
+                        self.released_at[row][col] = Some(now);
+                    }
+                    self.filtered[row][col] = false;
+                    self.ignored[row][col] = false;
+                } else if !self.filtered[row][col] && !self.ignored[row][col] {
+                    let bounce = match self.released_at[row][col] {
+                        Some(at) => now.wrapping_sub(at) < self.config.ignore_ms as u32,
+                        None => false,
+                    };
+                    self.ignored[row][col] = bounce;
+                    self.filtered[row][col] = !bounce;
+                }
+            }
+        }
+        self.filtered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(keys: &[Keycode]) -> KeySet {
+        let mut set = KeySet::new();
+        for &key in keys {
+            set.insert(key);
+        }
+        set
+    }
+
+    fn sticky(lock_on_double_tap: bool) -> StickyKeys {
+        StickyKeys::new(StickyConfig {
+            enabled: true,
+            lock_on_double_tap,
+        })
+    }
+
+    // Feeds each held set in turn and returns what the filter reports.
+    fn feed(sticky: &mut StickyKeys, steps: &[&[Keycode]]) -> Vec<KeySet> {
+        steps.iter().map(|held| sticky.update(keys(held))).collect()
+    }
+
+    #[test]
+    fn sticky_modifier_applies_to_the_next_key() {
+        use Keycode::{LeftShift, A, B};
+        let reported = feed(&mut sticky(true), &[&[LeftShift], &[], &[A], &[], &[B]]);
+        assert_eq!(
+            reported,
+            [
+                keys(&[LeftShift]),
+                keys(&[LeftShift]),
+                keys(&[A, LeftShift]),
+                keys(&[]),
+                keys(&[B]),
+            ]
+        );
+    }
+
+    #[test]
+    fn sticky_modifier_stays_for_keys_pressed_before_target_release() {
+        use Keycode::{LeftCtrl, A, B};
+        let reported = feed(
+            &mut sticky(true),
+            &[&[LeftCtrl], &[], &[A], &[A, B], &[B], &[]],
+        );
+        assert_eq!(
+            reported,
+            [
+                keys(&[LeftCtrl]),
+                keys(&[LeftCtrl]),
+                keys(&[A, LeftCtrl]),
+                keys(&[A, B, LeftCtrl]),
+                keys(&[B]),
+                keys(&[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn chorded_modifier_is_not_latched() {
+        use Keycode::{LeftShift, A};
+        let reported = feed(
+            &mut sticky(true),
+            &[&[LeftShift], &[LeftShift, A], &[LeftShift], &[]],
+        );
+        assert_eq!(reported.last(), Some(&keys(&[])));
+    }
+
+    #[test]
+    fn double_tap_locks_until_tapped_again() {
+        use Keycode::{LeftShift, A};
+        let mut sticky = sticky(true);
+        let reported = feed(
+            &mut sticky,
+            &[&[LeftShift], &[], &[LeftShift], &[], &[A], &[]],
+        );
+        assert_eq!(reported[5], keys(&[LeftShift]));
+        let reported = feed(&mut sticky, &[&[LeftShift], &[]]);
+        assert_eq!(reported[1], keys(&[]));
+    }
+
+    #[test]
+    fn double_tap_cancels_without_lock() {
+        use Keycode::LeftShift;
+        let reported = feed(&mut sticky(false), &[&[LeftShift], &[], &[LeftShift], &[]]);
+        assert_eq!(reported[3], keys(&[]));
+    }
+
+    #[test]
+    fn disabled_filters_pass_everything() {
+        let mut sticky = StickyKeys::new(StickyConfig {
+            enabled: false,
+            lock_on_double_tap: true,
+        });
+        let reported = feed(&mut sticky, &[&[Keycode::LeftShift], &[]]);
+        assert_eq!(reported[1], keys(&[]));
+
+        let mut slow = SlowKeys::<1, 1>::new(SlowConfig {
+            enabled: false,
+            hold_ms: 300,
+        });
+        assert_eq!(slow.update(&[[true]], 0), [[true]]);
+        let mut bounce = BounceKeys::<1, 1>::new(BounceConfig {
+            enabled: false,
+            ignore_ms: 500,
+        });
+        bounce.update(&[[true]], 0);
+        bounce.update(&[[false]], 10);
+        assert_eq!(bounce.update(&[[true]], 20), [[true]]);
+    }
+
+    // Replays (ms, pressed) changes of a single key through `filter` once
+    // per ms and returns the times the filtered key changed.
+    fn replay(
+        trace: &[(u32, bool)],
+        mut filter: impl FnMut(&KeyState<1, 1>, u32) -> KeyState<1, 1>,
+    ) -> Vec<(u32, bool)> {
+        let mut raw = [[false]];
+        let mut last = false;
+        let mut changes = Vec::new();
+        for now in 0..1000 {
+            if let Some(&(_, pressed)) = trace.iter().find(|(at, _)| *at == now) {
+                raw[0][0] = pressed;
+            }
+            let pressed = filter(&raw, now)[0][0];
+            if pressed != last {
+                changes.push((now, pressed));
+                last = pressed;
+            }
+        }
+        changes
+    }
+
+    #[test]
+    fn slow_keys_registers_long_presses_only() {
+        let mut slow = SlowKeys::<1, 1>::new(SlowConfig {
+            enabled: true,
+            hold_ms: 100,
+        });
+        let trace = [(10, true), (60, false), (200, true), (400, false)];
+        let changes = replay(&trace, |state, now| slow.update(state, now));
+        assert_eq!(changes, [(300, true), (400, false)]);
+    }
+
+    #[test]
+    fn bounce_keys_ignores_quick_repeats() {
+        let mut bounce = BounceKeys::<1, 1>::new(BounceConfig {
+            enabled: true,
+            ignore_ms: 500,
+        });
+        let trace = [
+            (10, true),
+            (100, false),
+            (300, true),
+            (350, false),
+            (550, true),
+            (580, false),
+            (650, true),
+            (700, false),
+        ];
+        let changes = replay(&trace, |state, now| bounce.update(state, now));
+        assert_eq!(
+            changes,
+            [(10, true), (100, false), (650, true), (700, false)]
+        );
+    }
+}
@@ -0,0 +1,41 @@
+use crate::debounce::{self, Algorithm, Scope};
+use crate::gpio::{Port, PortPin};
+use crate::matrix::{self, DiodeDirection, Pull};
+
+pub use crate::matrix_size::{COLS, ROWS};
+
+// KB789: COL_0/COL_1 are driven high one at a time and ROW_0..ROW_2 are
+// pulled down, so a pressed switch reads high through its diode.
+//
+//         COL_0  COL_1
+// ROW_0   SW1    SW6
+// ROW_1   SW2    SW5
+// ROW_2   SW3    SW4
+pub const ROW_PINS: [PortPin; ROWS] = [
+    PortPin::new(Port::B, 5),
+    PortPin::new(Port::B, 6),
+    PortPin::new(Port::B, 7),
+];
+pub const COL_PINS: [PortPin; COLS] = [PortPin::new(Port::A, 8), PortPin::new(Port::B, 11)];
+
+pub const DIODE_DIRECTION: DiodeDirection = DiodeDirection::Col2Row;
+pub const PULL: Pull = Pull::Down;
+
+// D1-D6 make every key independent; set this for diodeless hand-wired builds
+pub const GHOST_DETECTION: bool = false;
+
+// ~5us at 72MHz, plenty for the row lines to follow the column
+pub const SETTLE_CYCLES: u32 = 360;
+
+pub const DEBOUNCE: debounce::Config = debounce::Config {
+    algorithm: Algorithm::EagerPressDeferRelease,
+    scope: Scope::PerKey,
+    time_ms: 5,
+};
+
+pub type KeyState = matrix::KeyState<ROWS, COLS>;
+pub type Matrix = matrix::Matrix<PortPin, ROWS, COLS>;
+
+pub fn matrix() -> Matrix {
+    Matrix::new(ROW_PINS, COL_PINS, DIODE_DIRECTION, PULL, SETTLE_CYCLES)
+}
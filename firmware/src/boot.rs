@@ -0,0 +1,131 @@
+// The boot stub, alone in the first page of the firmware (see memory.x).
+// dapboot starts it in place of the application. It finishes an update
+// recorded by kb789_firmware::update and then starts the application.
+//
+// Updates never erase this page, so a stub from an older build may be
+// the one running: the install record, the slot addresses and the jump
+// into the application must stay as they are.
+
+use core::arch::asm;
+
+use kb789_firmware::storage::PAGE_SIZE;
+use kb789_firmware::update::{APP_BASE, INSTALL_MAGIC, INSTALL_PAGE, SLOT_SIZE, STAGING_BASE};
+
+use crate::flash;
+
+// end of RAM in memory.x
+const STACK_TOP: u32 = 0x2000_5000;
+
+const FLASH_KEYR: u32 = 0x4002_2004;
+const FLASH_SR: u32 = 0x4002_200C;
+const FLASH_CR: u32 = 0x4002_2010;
+const FLASH_AR: u32 = 0x4002_2014;
+const SCB_VTOR: u32 = 0xE000_ED08;
+
+const SR_BSY: u32 = 1 << 0;
+const CR_PG: u32 = 1 << 0;
+const CR_PER: u32 = 1 << 1;
+const CR_STRT: u32 = 1 << 6;
+const CR_LOCK: u32 = 1 << 7;
+
+#[repr(C)]
+struct Vectors {
+    stack: u32,
+    reset: unsafe extern "C" fn() -> !,
+}
+
+#[used]
+#[link_section = ".boot.vectors"]
+static VECTORS: Vectors = Vectors {
+    stack: STACK_TOP,
+    reset: boot,
+};
+
+// The stub must not call into the application slot, which it rewrites and
+// which may hold half an image, not even in a debug build. Memory is accessed through these macros rather than
+// ptr::read_volatile and friends, which are not inlined at opt-level 0,
+// and the arithmetic only uses the always-inlined wrapping operations.
+
+macro_rules! write32 {
+    ($addr:expr, $value:expr) => {
+        asm!("str {}, [{}]", in(reg) $value, in(reg) $addr, options(nostack, preserves_flags))
+    };
+}
+
+macro_rules! read32 {
+    ($addr:expr) => {{
+        let value: u32;
+        asm!("ldr {}, [{}]", out(reg) value, in(reg) $addr, options(nostack, preserves_flags, readonly));
+        value
+    }};
+}
+
+macro_rules! wait {
+    () => {
+        while read32!(FLASH_SR) & SR_BSY != 0 {}
+    };
+}
+
+macro_rules! erase_page {
+    ($addr:expr) => {
+        write32!(FLASH_CR, CR_PER);
+        write32!(FLASH_AR, $addr);
+        write32!(FLASH_CR, CR_PER | CR_STRT);
+        wait!();
+        write32!(FLASH_CR, 0u32);
+    };
+}
+
+macro_rules! copy_page {
+    ($dst:expr, $src:expr, $len:expr) => {
+        let (dst, src, len): (u32, u32, u32) = ($dst, $src, $len);
+        write32!(FLASH_CR, CR_PG);
+        let mut pos = 0u32;
+        while pos < len {
+            let half: u32;
+            asm!("ldrh {}, [{}]", out(reg) half, in(reg) src.wrapping_add(pos), options(nostack, preserves_flags, readonly));
+            asm!("strh {}, [{}]", in(reg) half, in(reg) dst.wrapping_add(pos), options(nostack, preserves_flags));
+            wait!();
+            pos = pos.wrapping_add(2);
+        }
+        write32!(FLASH_CR, 0u32);
+    };
+}
+
+#[link_section = ".boot.text"]
+unsafe extern "C" fn boot() -> ! {
+    let len = read32!(INSTALL_PAGE.wrapping_add(4));
+    if read32!(INSTALL_PAGE) == INSTALL_MAGIC && len <= SLOT_SIZE {
+        install(len);
+    }
+
+    let stack = read32!(APP_BASE);
+    let reset = read32!(APP_BASE.wrapping_add(4));
+    write32!(SCB_VTOR, APP_BASE);
+    asm!("msr msp, {}", "bx {}", in(reg) stack, in(reg) reset, options(noreturn));
+}
+
+// Copies the staged image of `len` bytes over the application slot, then
+// erases the install record. Cut off at any point, it starts over on the
+// next boot, as the staged image and the record are still there.
+#[link_section = ".boot.text"]
+unsafe fn install(len: u32) {
+    write32!(FLASH_KEYR, flash::KEY1);
+    write32!(FLASH_KEYR, flash::KEY2);
+
+    let mut offset = 0u32;
+    while offset < len {
+        let left = len.wrapping_sub(offset);
+        let chunk = if left < PAGE_SIZE { left } else { PAGE_SIZE };
+        erase_page!(APP_BASE.wrapping_add(offset));
+        copy_page!(
+            APP_BASE.wrapping_add(offset),
+            STAGING_BASE.wrapping_add(offset),
+            chunk.wrapping_add(1) & !1
+        );
+        offset = offset.wrapping_add(PAGE_SIZE);
+    }
+    erase_page!(INSTALL_PAGE);
+
+    write32!(FLASH_CR, CR_LOCK);
+}
@@ -0,0 +1,130 @@
+use crate::keycode::{KeySet, Keycode};
+
+// Shifts letters until a key that ends the word is pressed, then turns
+// itself off. Digits, Backspace, Delete and Shift keep the word going, and
+// '-' is shifted to '_' so identifiers like MAX_LEN come out whole.
+pub struct CapsWord {
+    active: bool,
+}
+
+impl CapsWord {
+    pub fn new() -> Self {
+        CapsWord { active: false }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn toggle(&mut self) {
+        self.active = !self.active;
+    }
+
+    // Called for every key pressed while the keymap runs.
+    pub fn press(&mut self, key: Keycode) {
+        if self.active && !shifted(key) && !continues(key) {
+            self.active = false;
+        }
+    }
+
+    // Shift covers the whole report, so it is only added while every
+    // non-modifier key held is one to shift: a digit rolled over a letter
+    // stays a digit.
+    pub fn apply(&self, keys: &mut KeySet) {
+        if self.active
+            && keys.iter().any(shifted)
+            && keys.iter().all(|key| key.is_modifier() || shifted(key))
+        {
+            keys.insert(Keycode::LeftShift);
+        }
+    }
+}
+
+impl Default for CapsWord {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn shifted(key: Keycode) -> bool {
+    (Keycode::A.usage()..=Keycode::Z.usage()).contains(&key.usage()) || key == Keycode::Minus
+}
+
+fn continues(key: Keycode) -> bool {
+    (Keycode::N1.usage()..=Keycode::N0.usage()).contains(&key.usage())
+        || matches!(
+            key,
+            Keycode::Backspace | Keycode::Delete | Keycode::LeftShift | Keycode::RightShift
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(keys: &[Keycode]) -> KeySet {
+        let mut set = KeySet::new();
+        for &key in keys {
+            set.insert(key);
+        }
+        set
+    }
+
+    fn applied(caps_word: &CapsWord, held: &[Keycode]) -> KeySet {
+        let mut set = keys(held);
+        caps_word.apply(&mut set);
+        set
+    }
+
+    #[test]
+    fn shifts_letters_and_minus() {
+        let mut caps_word = CapsWord::new();
+        caps_word.toggle();
+        assert_eq!(
+            applied(&caps_word, &[Keycode::A]),
+            keys(&[Keycode::A, Keycode::LeftShift])
+        );
+        assert_eq!(
+            applied(&caps_word, &[Keycode::Minus, Keycode::LeftCtrl]),
+            keys(&[Keycode::Minus, Keycode::LeftCtrl, Keycode::LeftShift])
+        );
+        assert_eq!(applied(&caps_word, &[Keycode::N1]), keys(&[Keycode::N1]));
+        assert_eq!(applied(&caps_word, &[]), KeySet::new());
+    }
+
+    #[test]
+    fn does_not_shift_keys_held_along_with_letters() {
+        let mut caps_word = CapsWord::new();
+        caps_word.toggle();
+        let held = [Keycode::A, Keycode::N2];
+        assert_eq!(applied(&caps_word, &held), keys(&held));
+    }
+
+    #[test]
+    fn inactive_shifts_nothing() {
+        let caps_word = CapsWord::new();
+        assert_eq!(applied(&caps_word, &[Keycode::A]), keys(&[Keycode::A]));
+    }
+
+    #[test]
+    fn word_ends_on_other_keys() {
+        let mut caps_word = CapsWord::new();
+        caps_word.toggle();
+        for &key in &[
+            Keycode::A,
+            Keycode::Minus,
+            Keycode::N0,
+            Keycode::Backspace,
+            Keycode::Delete,
+            Keycode::RightShift,
+        ] {
+            caps_word.press(key);
+            assert!(caps_word.is_active(), "{} ended the word", key);
+        }
+        caps_word.press(Keycode::Space);
+        assert!(!caps_word.is_active());
+        caps_word.toggle();
+        caps_word.toggle();
+        assert!(!caps_word.is_active());
+    }
+}
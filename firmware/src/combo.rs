@@ -0,0 +1,113 @@
+use core::cmp;
+
+use crate::event::EventQueue;
+use crate::keymap::Action;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Release {
+    // The action ends as soon as one of the keys is released
+    AnyKey,
+    // The action lasts until every key is released
+    AllKeys,
+}
+
+// Matrix positions that, pressed together within `timeout_ms` of the first
+// one, trigger `action` instead of their own actions. At most 32 keys.
+#[derive(Debug, Clone, Copy)]
+pub struct Combo {
+    pub keys: &'static [(usize, usize)],
+    pub action: Action,
+    pub timeout_ms: u16,
+    pub release: Release,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Progress {
+    Complete,
+    // Not complete yet, but the window is still open
+    Possible,
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Match {
+    // Combo at this index fired; its presses are the first `keys.len()`
+    // events in the queue.
+    Fire(usize),
+    // A combo may still complete; leave the queue alone for now.
+    Wait,
+    None,
+}
+
+impl Combo {
+    // Walks the queue from its oldest event. The chord must be an unbroken
+    // run of presses of this combo's keys: any release or other key ends it.
+    fn progress<const ROWS: usize, const COLS: usize>(
+        &self,
+        events: &EventQueue<ROWS, COLS>,
+        now: u32,
+    ) -> Progress {
+        if self.keys.is_empty() || self.keys.len() > 32 {
+            return Progress::Failed;
+        }
+        let timeout = self.timeout_ms as u32;
+        let all = u32::MAX >> (32 - self.keys.len());
+        let mut start = None;
+        let mut seen = 0u32;
+        for event in events.iter() {
+            let start = *start.get_or_insert(event.time);
+            if event.time.wrapping_sub(start) >= timeout || !event.pressed {
+                return Progress::Failed;
+            }
+            let idx = match self
+                .keys
+                .iter()
+                .position(|&key| key == (event.row, event.col))
+            {
+                Some(idx) => idx,
+                None => return Progress::Failed,
+            };
+            seen |= 1 << idx;
+            if seen == all {
+                return Progress::Complete;
+            }
+        }
+        match start {
+            Some(start) if now.wrapping_sub(start) < timeout => Progress::Possible,
+            _ => Progress::Failed,
+        }
+    }
+}
+
+// Decides what the press at the front of `events` starts. When combos
+// overlap, the one with the most keys wins, so a completed combo waits
+// while a larger one sharing its keys can still complete.
+pub fn find<const ROWS: usize, const COLS: usize>(
+    combos: &[Combo],
+    events: &EventQueue<ROWS, COLS>,
+    now: u32,
+) -> Match {
+    let mut fire: Option<usize> = None;
+    let mut waiting = 0;
+    for (idx, combo) in combos.iter().enumerate() {
+        match combo.progress(events, now) {
+            Progress::Complete => {
+                let larger = match fire {
+                    Some(best) => combos[best].keys.len() < combo.keys.len(),
+                    None => true,
+                };
+                if larger {
+                    fire = Some(idx);
+                }
+            }
+            Progress::Possible => waiting = cmp::max(waiting, combo.keys.len()),
+            Progress::Failed => {}
+        }
+    }
+    match fire {
+        Some(idx) if combos[idx].keys.len() >= waiting => Match::Fire(idx),
+        _ if waiting > 0 => Match::Wait,
+        _ => Match::None,
+    }
+}
@@ -7,6 +7,7 @@ pub struct ReadCursor<'a> {
     pos: usize,
 }
 
+#[allow(clippy::len_without_is_empty)]
 impl<'a> ReadCursor<'a> {
     #[allow(dead_code)]
     pub fn new(buf: &'a mut [u8]) -> Self {
@@ -56,6 +57,7 @@ pub struct WriteCursor<'a> {
     len: usize,
 }
 
+#[allow(clippy::len_without_is_empty)]
 impl<'a> WriteCursor<'a> {
     pub fn new(buf: &'a mut [u8]) -> Self {
         WriteCursor { buf, len: 0 }
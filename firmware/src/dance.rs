@@ -0,0 +1,183 @@
+use crate::event::Event;
+use crate::keymap::Action;
+
+// What a dance does after a given number of taps. `hold` applies when the
+// last tap is still held at the end of the dance; Action::No there holds
+// `tap` down like a normal key instead.
+#[derive(Debug, Clone, Copy)]
+pub struct Step {
+    pub tap: Action,
+    pub hold: Action,
+}
+
+// steps[0] is the single tap, steps[1] the double tap and so on. Tapping
+// more often than there are steps repeats the last one. The dance ends
+// `timeout_ms` after the last press or release of the key, when another
+// key is pressed, or when the last step's tap is released.
+#[derive(Debug, Clone, Copy)]
+pub struct TapDance {
+    pub steps: &'static [Step],
+    pub timeout_ms: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    // Press and release at once
+    Tap(Action),
+    // Keep pressed until the key is released
+    Hold(Action),
+}
+
+// A dance in progress
+#[derive(Debug, Clone, Copy)]
+pub struct Dance {
+    pub row: usize,
+    pub col: usize,
+    dance: &'static TapDance,
+    count: usize,
+    pressed: bool,
+    last: u32,
+}
+
+impl Dance {
+    pub fn start(row: usize, col: usize, dance: &'static TapDance, time: u32) -> Self {
+        Dance {
+            row,
+            col,
+            dance,
+            count: 1,
+            pressed: true,
+            last: time,
+        }
+    }
+
+    pub fn is_key(&self, event: &Event) -> bool {
+        (event.row, event.col) == (self.row, self.col)
+    }
+
+    pub fn expired(&self, now: u32) -> bool {
+        now.wrapping_sub(self.last) >= self.dance.timeout_ms as u32
+    }
+
+    // Takes the next press or release of the dance key. Returns the outcome
+    // once no further tap can change it.
+    pub fn feed(&mut self, event: &Event) -> Option<Outcome> {
+        self.last = event.time;
+        self.pressed = event.pressed;
+        if event.pressed {
+            self.count += 1;
+            None
+        } else if self.count >= self.dance.steps.len() {
+            Some(self.finish())
+        } else {
+            None
+        }
+    }
+
+    pub fn finish(&self) -> Outcome {
+        let step = match self.dance.steps.len() {
+            0 => return Outcome::Tap(Action::No),
+            len => self.dance.steps[self.count.min(len) - 1],
+        };
+        match (self.pressed, step.hold) {
+            (false, _) => Outcome::Tap(step.tap),
+            (true, Action::No) => Outcome::Hold(step.tap),
+            (true, hold) => Outcome::Hold(hold),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keycode::Keycode;
+
+    static DANCE: TapDance = TapDance {
+        steps: &[
+            Step {
+                tap: Action::Key(Keycode::A),
+                hold: Action::Key(Keycode::LeftCtrl),
+            },
+            Step {
+                tap: Action::Key(Keycode::B),
+                hold: Action::No,
+            },
+            Step {
+                tap: Action::Key(Keycode::C),
+                hold: Action::Momentary(1),
+            },
+        ],
+        timeout_ms: 200,
+    };
+
+    fn event(time: u32, pressed: bool) -> Event {
+        Event {
+            row: 0,
+            col: 0,
+            pressed,
+            time,
+        }
+    }
+
+    // Starts a dance and feeds it alternating releases and presses, 10 ms
+    // apart, after the first press. Returns the dance and what the last
+    // event decided.
+    fn dance(events: usize) -> (Dance, Option<Outcome>) {
+        let mut dance = Dance::start(0, 0, &DANCE, 0);
+        let mut outcome = None;
+        for idx in 0..events {
+            let pressed = idx % 2 == 1;
+            outcome = dance.feed(&event(10 * (idx as u32 + 1), pressed));
+        }
+        (dance, outcome)
+    }
+
+    #[test]
+    fn taps_pick_their_step() {
+        let (dance1, outcome) = dance(1);
+        assert_eq!(outcome, None);
+        assert_eq!(dance1.finish(), Outcome::Tap(Action::Key(Keycode::A)));
+        let (dance2, outcome) = dance(3);
+        assert_eq!(outcome, None);
+        assert_eq!(dance2.finish(), Outcome::Tap(Action::Key(Keycode::B)));
+    }
+
+    #[test]
+    fn last_step_ends_the_dance_on_release() {
+        let (_, outcome) = dance(4);
+        assert_eq!(outcome, None);
+        let (_, outcome) = dance(5);
+        assert_eq!(outcome, Some(Outcome::Tap(Action::Key(Keycode::C))));
+    }
+
+    #[test]
+    fn held_last_tap_picks_hold() {
+        let (dance1, _) = dance(0);
+        assert_eq!(
+            dance1.finish(),
+            Outcome::Hold(Action::Key(Keycode::LeftCtrl))
+        );
+        // No hold action holds the tap down
+        let (dance2, _) = dance(2);
+        assert_eq!(dance2.finish(), Outcome::Hold(Action::Key(Keycode::B)));
+        let (dance3, _) = dance(4);
+        assert_eq!(dance3.finish(), Outcome::Hold(Action::Momentary(1)));
+    }
+
+    #[test]
+    fn timeout_counts_from_the_last_event() {
+        let (dance, _) = dance(3);
+        assert!(!dance.expired(229));
+        assert!(dance.expired(230));
+    }
+
+    #[test]
+    fn empty_dance_does_nothing() {
+        static EMPTY: TapDance = TapDance {
+            steps: &[],
+            timeout_ms: 200,
+        };
+        let dance = Dance::start(0, 0, &EMPTY, 0);
+        assert_eq!(dance.finish(), Outcome::Tap(Action::No));
+    }
+}
@@ -0,0 +1,182 @@
+use crate::KeyState;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    // Commit any change once the input has been stable for `time_ms`.
+    SymmetricDefer,
+    // Commit presses at once, releases once stable for `time_ms`.
+    EagerPressDeferRelease,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    // One timer for the whole matrix: any change restarts it.
+    Global,
+    // One timer per key.
+    PerKey,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    pub algorithm: Algorithm,
+    pub scope: Scope,
+    pub time_ms: u16,
+}
+
+pub struct Debouncer<const ROWS: usize, const COLS: usize> {
+    config: Config,
+    raw: KeyState<ROWS, COLS>,
+    debounced: KeyState<ROWS, COLS>,
+    changed_at: [[u32; COLS]; ROWS],
+    any_changed_at: u32,
+}
+
+impl<const ROWS: usize, const COLS: usize> Debouncer<ROWS, COLS> {
+    pub fn new(config: Config) -> Self {
+        Debouncer {
+            config,
+            raw: [[false; COLS]; ROWS],
+            debounced: [[false; COLS]; ROWS],
+            changed_at: [[0; COLS]; ROWS],
+            any_changed_at: 0,
+        }
+    }
+
+    pub fn update(&mut self, raw: &KeyState<ROWS, COLS>, now: u32) -> KeyState<ROWS, COLS> {
+        for (row, raw_row) in raw.iter().enumerate() {
+            for (col, &pressed) in raw_row.iter().enumerate() {
+                if pressed != self.raw[row][col] {
+                    self.changed_at[row][col] = now;
+                    self.any_changed_at = now;
+                }
+            }
+        }
+        self.raw = *raw;
+
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                let pressed = self.raw[row][col];
+                if pressed == self.debounced[row][col] {
+                    continue;
+                }
+                let since = match self.config.scope {
+                    Scope::Global => self.any_changed_at,
+                    Scope::PerKey => self.changed_at[row][col],
+                };
+                let stable = now.wrapping_sub(since) >= self.config.time_ms as u32;
+                let eager = pressed && self.config.algorithm == Algorithm::EagerPressDeferRelease;
+                if stable || eager {
+                    self.debounced[row][col] = pressed;
+                }
+            }
+        }
+        self.debounced
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Raw level of both keys of a 1x2 matrix from the given ms on. Key 0
+    // bounces for 4 ms on press and 2 ms on release, key 1 is pressed cleanly
+    // and released while key 0 is still settling, then picks up a 1 ms spike.
+    const TRACE: &[(u32, [bool; 2])] = &[
+        (10, [true, false]),
+        (11, [false, false]),
+        (12, [true, false]),
+        (13, [false, false]),
+        (14, [true, false]),
+        (16, [true, true]),
+        (40, [false, true]),
+        (41, [true, true]),
+        (42, [false, true]),
+        (44, [false, false]),
+        (60, [false, true]),
+        (61, [false, false]),
+    ];
+
+    // Scans the trace once per ms from `start` and returns the debounced
+    // changes as (ms since start, col, pressed).
+    fn replay(algorithm: Algorithm, scope: Scope, start: u32) -> Vec<(u32, usize, bool)> {
+        let mut debouncer = Debouncer::<1, 2>::new(Config {
+            algorithm,
+            scope,
+            time_ms: 5,
+        });
+        let mut raw = [[false; 2]];
+        let mut last = [[false; 2]];
+        let mut changes = Vec::new();
+        for t in 0..80 {
+            if let Some((_, level)) = TRACE.iter().find(|(at, _)| *at == t) {
+                raw[0] = *level;
+            }
+            let state = debouncer.update(&raw, start.wrapping_add(t));
+            for (col, (&now, &before)) in state[0].iter().zip(last[0].iter()).enumerate() {
+                if now != before {
+                    changes.push((t, col, now));
+                }
+            }
+            last = state;
+        }
+        changes
+    }
+
+    #[test]
+    fn symmetric_defer_per_key() {
+        assert_eq!(
+            replay(Algorithm::SymmetricDefer, Scope::PerKey, 0),
+            [(19, 0, true), (21, 1, true), (47, 0, false), (49, 1, false)]
+        );
+    }
+
+    #[test]
+    fn symmetric_defer_global() {
+        assert_eq!(
+            replay(Algorithm::SymmetricDefer, Scope::Global, 0),
+            [(21, 0, true), (21, 1, true), (49, 0, false), (49, 1, false)]
+        );
+    }
+
+    #[test]
+    fn eager_press_per_key() {
+        assert_eq!(
+            replay(Algorithm::EagerPressDeferRelease, Scope::PerKey, 0),
+            [
+                (10, 0, true),
+                (16, 1, true),
+                (47, 0, false),
+                (49, 1, false),
+                (60, 1, true),
+                (66, 1, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn eager_press_global() {
+        assert_eq!(
+            replay(Algorithm::EagerPressDeferRelease, Scope::Global, 0),
+            [
+                (10, 0, true),
+                (16, 1, true),
+                (49, 0, false),
+                (49, 1, false),
+                (60, 1, true),
+                (66, 1, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn timing_survives_clock_wrap() {
+        for &algorithm in &[Algorithm::SymmetricDefer, Algorithm::EagerPressDeferRelease] {
+            for &scope in &[Scope::Global, Scope::PerKey] {
+                assert_eq!(
+                    replay(algorithm, scope, u32::MAX - 30),
+                    replay(algorithm, scope, 0)
+                );
+            }
+        }
+    }
+}
@@ -1,4 +1,4 @@
-#[repr(packed)]
+#[repr(C, packed)]
 #[allow(non_snake_case)]
 pub struct DeviceDescriptor {
     pub bLength: u8,
@@ -17,7 +17,7 @@ pub struct DeviceDescriptor {
     pub bNumConfigurations: u8,
 }
 
-#[repr(packed)]
+#[repr(C, packed)]
 #[allow(non_snake_case)]
 pub struct ConfigDescriptor {
     pub bLength: u8,
@@ -30,7 +30,7 @@ pub struct ConfigDescriptor {
     pub bMaxPower: u8,
 }
 
-#[repr(packed)]
+#[repr(C, packed)]
 #[allow(non_snake_case)]
 pub struct InterfaceDescriptor {
     pub bLength: u8,
@@ -44,7 +44,7 @@ pub struct InterfaceDescriptor {
     pub iInterface: u8,
 }
 
-#[repr(packed)]
+#[repr(C, packed)]
 #[allow(non_snake_case)]
 pub struct EndpointDescriptor {
     pub bLength: u8,
@@ -55,7 +55,7 @@ pub struct EndpointDescriptor {
     pub bInterval: u8,
 }
 
-#[repr(packed)]
+#[repr(C, packed)]
 #[allow(non_snake_case)]
 pub struct HidDescriptor {
     pub bLength: u8,
@@ -65,20 +65,31 @@ pub struct HidDescriptor {
     pub bNumDescriptors: u8,
 }
 
-#[repr(packed)]
+#[repr(C, packed)]
 #[allow(non_snake_case)]
 pub struct HidReport {
     pub bReportDescriptorType: u8,
     pub wDescriptorLength: u16,
 }
 
-#[repr(packed)]
+#[repr(C, packed)]
 #[allow(non_snake_case)]
 pub struct HidFunction {
     pub hid_descriptor: HidDescriptor,
     pub hid_report: HidReport,
 }
 
+#[repr(C, packed)]
+#[allow(non_snake_case)]
+pub struct DfuFunctionalDescriptor {
+    pub bLength: u8,
+    pub bDescriptorType: u8,
+    pub bmAttributes: u8,
+    pub wDetachTimeOut: u16,
+    pub wTransferSize: u16,
+    pub bcdDFUVersion: u16,
+}
+
 pub const STRING_DESCR0: &[u8] = &[0x04, 0x03, 0x09, 0x04];
 
 pub fn build_string_descr(buf: &mut [u8], data: &str) -> Option<usize> {
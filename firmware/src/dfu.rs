@@ -0,0 +1,37 @@
+use stm32f1::stm32f103;
+
+pub const DFU_DETACH: u8 = 0x00;
+pub const DFU_GETSTATUS: u8 = 0x03;
+pub const DFU_GETSTATE: u8 = 0x05;
+
+// dapboot stays in the bootloader when it finds this value in BKP_DR1/BKP_DR2
+const CMD_BOOT: u32 = 0x544F_4F42;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    AppIdle,
+    AppDetach,
+}
+impl State {
+    pub fn bits(&self) -> u8 {
+        use State::*;
+        match self {
+            AppIdle => 0,
+            AppDetach => 1,
+        }
+    }
+}
+
+pub fn reboot_into_bootloader(
+    rcc: &stm32f103::RCC,
+    pwr: &stm32f103::PWR,
+    bkp: &stm32f103::BKP,
+) -> ! {
+    rcc.apb1enr
+        .modify(|_, w| w.pwren().set_bit().bkpen().set_bit());
+    pwr.cr.modify(|_, w| w.dbp().set_bit());
+    bkp.dr[0].write(|w| w.d().bits(CMD_BOOT as u16));
+    bkp.dr[1].write(|w| w.d().bits((CMD_BOOT >> 16) as u16));
+    pwr.cr.modify(|_, w| w.dbp().clear_bit());
+    cortex_m::peripheral::SCB::sys_reset()
+}
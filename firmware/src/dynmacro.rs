@@ -0,0 +1,280 @@
+// Macros recorded on the keyboard itself. While recording, every change
+// in the keys the keymap produces is stored as a press or release; playback
+// hands the recording to the macro player. Timing is not kept.
+//
+// Saved page layout (storage::MACRO_PAGE):
+//   0  magic "DMC1"
+//   4  CRC-32 of the body
+//   8  body: per slot, event count (u8) then SLOT_EVENTS x (usage, pressed)
+
+use crate::crc;
+use crate::keycode::KeySet;
+use crate::recording::{self, Recording, SLOTS};
+use crate::storage::{self, Storage, MACRO_PAGE};
+
+const SLOT_EVENTS: usize = recording::CAPACITY;
+
+const MAGIC: u32 = 0x3143_4D44;
+const HEADER_SIZE: usize = 8;
+const SLOT_SIZE: usize = 1 + SLOT_EVENTS * 2;
+const BODY_SIZE: usize = SLOTS * SLOT_SIZE;
+
+pub struct Recorder {
+    slots: [Recording; SLOTS],
+    active: Option<usize>,
+    last: KeySet,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Recorder {
+            slots: [Recording::new(); SLOTS],
+            active: None,
+            last: KeySet::new(),
+        }
+    }
+
+    // Starts recording into `slot`, replacing what it held, or stops the
+    // recording in progress. `keys` are the keys held right now; they are
+    // not recorded as presses. Returns true when a recording was finished.
+    pub fn toggle(&mut self, slot: u8, keys: &KeySet) -> bool {
+        if self.active.take().is_some() {
+            return true;
+        }
+        if let Some(recording) = self.slots.get_mut(slot as usize) {
+            *recording = Recording::new();
+            self.active = Some(slot as usize);
+            self.last = *keys;
+        }
+        false
+    }
+
+    // Records the difference to the previous call.
+    pub fn record(&mut self, keys: &KeySet) {
+        let slot = match self.active {
+            Some(slot) => slot,
+            None => return,
+        };
+        let last = self.last;
+        for key in last.iter().filter(|key| !keys.contains(*key)) {
+            self.slots[slot].push(key, false);
+        }
+        for key in keys.iter().filter(|key| !last.contains(*key)) {
+            self.slots[slot].push(key, true);
+        }
+        self.last = *keys;
+    }
+
+    // A slot cannot be played while it is being recorded.
+    pub fn recording(&self, slot: u8) -> Option<&Recording> {
+        if self.active == Some(slot as usize) {
+            return None;
+        }
+        self.slots.get(slot as usize)
+    }
+
+    // Restores the recordings saved by save(). A blank or damaged page
+    // leaves every slot empty.
+    pub fn load<S: Storage>(&mut self, flash: &S) {
+        let page = flash.read(MACRO_PAGE, HEADER_SIZE + BODY_SIZE);
+        let (header, body) = page.split_at(HEADER_SIZE);
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if magic != MAGIC || crc != crc::crc32(body) {
+            return;
+        }
+        for (recording, buf) in self.slots.iter_mut().zip(body.chunks_exact(SLOT_SIZE)) {
+            *recording = Recording::read_from(buf);
+        }
+    }
+
+    // Erases and rewrites the macro page. The CPU stalls for the ~20 ms
+    // page erase, so call this only when the user asked for it.
+    pub fn save<S: Storage>(&self, flash: &mut S) -> Result<(), storage::Error> {
+        let mut page = [0u8; HEADER_SIZE + BODY_SIZE];
+        let (header, body) = page.split_at_mut(HEADER_SIZE);
+        for (recording, buf) in self.slots.iter().zip(body.chunks_exact_mut(SLOT_SIZE)) {
+            recording.write_to(buf);
+        }
+        header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&crc::crc32(body).to_le_bytes());
+        flash.erase_page(MACRO_PAGE)?;
+        flash.program(MACRO_PAGE, &page)
+    }
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keycode::Keycode;
+    use crate::macros::Player;
+    use crate::storage::{RamStorage, PAGE_SIZE};
+
+    fn key_set(keys: &[Keycode]) -> KeySet {
+        let mut set = KeySet::new();
+        for &key in keys {
+            set.insert(key);
+        }
+        set
+    }
+
+    fn events(recording: &Recording) -> Vec<(Keycode, bool)> {
+        (0..).map_while(|idx| recording.get(idx)).collect()
+    }
+
+    // Records the key sets in turn into `slot`
+    fn record(recorder: &mut Recorder, slot: u8, sets: &[&[Keycode]]) {
+        assert!(!recorder.toggle(slot, &KeySet::new()));
+        for keys in sets {
+            recorder.record(&key_set(keys));
+        }
+        assert!(recorder.toggle(slot, &KeySet::new()));
+    }
+
+    #[test]
+    fn playback_repeats_the_recorded_keys() {
+        use Keycode::*;
+        let sets: &[&[Keycode]] = &[&[LeftShift], &[A, LeftShift], &[LeftShift], &[B], &[]];
+        let mut recorder = Recorder::new();
+        record(&mut recorder, 0, sets);
+
+        let mut player = Player::new(&[]);
+        player.start_recording(recorder.recording(0).unwrap());
+        let mut played = Vec::new();
+        while player.step(0) {
+            played.push(player.keys(KeySet::new()));
+        }
+        played.dedup();
+        // Releases are recorded before presses, one change at a time
+        let expected: Vec<KeySet> = [
+            &[LeftShift][..],
+            &[A, LeftShift],
+            &[LeftShift],
+            &[],
+            &[B],
+            &[],
+        ]
+        .iter()
+        .map(|keys| key_set(keys))
+        .collect();
+        assert_eq!(played, expected);
+    }
+
+    #[test]
+    fn keys_held_at_the_start_are_not_recorded() {
+        use Keycode::*;
+        let mut recorder = Recorder::new();
+        recorder.toggle(0, &key_set(&[LeftCtrl]));
+        recorder.record(&key_set(&[LeftCtrl]));
+        recorder.record(&key_set(&[LeftCtrl, A]));
+        recorder.record(&key_set(&[]));
+        recorder.toggle(0, &KeySet::new());
+        assert_eq!(
+            events(recorder.recording(0).unwrap()),
+            [(A, true), (A, false), (LeftCtrl, false)]
+        );
+    }
+
+    #[test]
+    fn recording_stops_at_capacity() {
+        let mut recorder = Recorder::new();
+        recorder.toggle(0, &KeySet::new());
+        for idx in 0..recording::CAPACITY {
+            let keys: &[Keycode] = if idx % 2 == 0 { &[Keycode::A] } else { &[] };
+            recorder.record(&key_set(keys));
+        }
+        recorder.record(&key_set(&[Keycode::B]));
+        recorder.toggle(0, &KeySet::new());
+        let recorded = events(recorder.recording(0).unwrap());
+        assert_eq!(recorded.len(), recording::CAPACITY);
+        assert_eq!(recorded.last(), Some(&(Keycode::A, false)));
+    }
+
+    #[test]
+    fn slots_are_independent() {
+        use Keycode::*;
+        let mut recorder = Recorder::new();
+        record(&mut recorder, 0, &[&[A], &[]]);
+        assert!(!recorder.toggle(1, &KeySet::new()));
+        // A slot being recorded cannot be played
+        assert!(recorder.recording(1).is_none());
+        recorder.record(&key_set(&[B]));
+        recorder.record(&key_set(&[]));
+        assert!(recorder.toggle(1, &KeySet::new()));
+        assert_eq!(
+            events(recorder.recording(0).unwrap()),
+            [(A, true), (A, false)]
+        );
+        assert_eq!(
+            events(recorder.recording(1).unwrap()),
+            [(B, true), (B, false)]
+        );
+        // Recording again replaces only that slot
+        record(&mut recorder, 0, &[&[C], &[]]);
+        assert_eq!(
+            events(recorder.recording(0).unwrap()),
+            [(C, true), (C, false)]
+        );
+        assert_eq!(
+            events(recorder.recording(1).unwrap()),
+            [(B, true), (B, false)]
+        );
+        // Slots past the end are ignored
+        assert!(!recorder.toggle(SLOTS as u8, &KeySet::new()));
+        assert!(recorder.recording(SLOTS as u8).is_none());
+    }
+
+    fn saved() -> (Recorder, RamStorage) {
+        use Keycode::*;
+        let mut recorder = Recorder::new();
+        record(&mut recorder, 0, &[&[LeftShift], &[A, LeftShift], &[]]);
+        record(&mut recorder, 1, &[&[Z], &[]]);
+        let mut flash = RamStorage::new(MACRO_PAGE, PAGE_SIZE as usize);
+        recorder.save(&mut flash).unwrap();
+        (recorder, flash)
+    }
+
+    #[test]
+    fn save_and_load() {
+        let (recorder, flash) = saved();
+        let page = flash.read(MACRO_PAGE, HEADER_SIZE + BODY_SIZE);
+        assert_eq!(&page[0..4], b"DMC1");
+        assert_eq!(page[4..8], crc::crc32(&page[HEADER_SIZE..]).to_le_bytes());
+
+        let mut loaded = Recorder::new();
+        loaded.load(&flash);
+        for slot in 0..SLOTS as u8 {
+            assert_eq!(
+                events(loaded.recording(slot).unwrap()),
+                events(recorder.recording(slot).unwrap())
+            );
+        }
+    }
+
+    #[test]
+    fn corrupt_page_loads_empty() {
+        let (_, mut flash) = saved();
+        flash.bytes_mut(MACRO_PAGE + HEADER_SIZE as u32 + 1, 1)[0] ^= 0x01;
+        let mut loaded = Recorder::new();
+        loaded.load(&flash);
+        for slot in 0..SLOTS as u8 {
+            assert_eq!(events(loaded.recording(slot).unwrap()), []);
+        }
+    }
+
+    #[test]
+    fn erased_page_loads_empty() {
+        let flash = RamStorage::new(MACRO_PAGE, PAGE_SIZE as usize);
+        let mut loaded = Recorder::new();
+        loaded.load(&flash);
+        for slot in 0..SLOTS as u8 {
+            assert_eq!(events(loaded.recording(slot).unwrap()), []);
+        }
+    }
+}
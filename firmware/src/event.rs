@@ -0,0 +1,94 @@
+use crate::KeyState;
+
+const CAPACITY: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Event {
+    pub row: usize,
+    pub col: usize,
+    pub pressed: bool,
+    // timer::now() of the scan that saw the change
+    pub time: u32,
+}
+
+// Key changes waiting for the keymap, oldest first. Stages that need to
+// look ahead (tap-hold) read the queued events without consuming them.
+pub struct EventQueue<const ROWS: usize, const COLS: usize> {
+    buf: [Event; CAPACITY],
+    head: usize,
+    len: usize,
+    last: KeyState<ROWS, COLS>,
+}
+
+impl<const ROWS: usize, const COLS: usize> EventQueue<ROWS, COLS> {
+    pub fn new() -> Self {
+        EventQueue {
+            buf: [Event {
+                row: 0,
+                col: 0,
+                pressed: false,
+                time: 0,
+            }; CAPACITY],
+            head: 0,
+            len: 0,
+            last: [[false; COLS]; ROWS],
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == CAPACITY
+    }
+
+    // Queues an event for every key that differs from the previous call,
+    // releases before presses so a key rolling onto another sees the layers
+    // the first one leaves behind. Changes that do not fit stay pending and
+    // are queued on a later call, so a full queue delays keys but never
+    // loses them.
+    pub fn push_changes(&mut self, state: &KeyState<ROWS, COLS>, now: u32) {
+        for pass_pressed in [false, true] {
+            self.push_pass(state, pass_pressed, now);
+        }
+    }
+
+    fn push_pass(&mut self, state: &KeyState<ROWS, COLS>, pass_pressed: bool, now: u32) {
+        for (row, state_row) in state.iter().enumerate() {
+            for (col, &pressed) in state_row.iter().enumerate() {
+                if pressed == self.last[row][col] || pressed != pass_pressed || self.is_full() {
+                    continue;
+                }
+                self.buf[(self.head + self.len) % CAPACITY] = Event {
+                    row,
+                    col,
+                    pressed,
+                    time: now,
+                };
+                self.len += 1;
+                self.last[row][col] = pressed;
+            }
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Event> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.buf[self.head];
+        self.head = (self.head + 1) % CAPACITY;
+        self.len -= 1;
+        Some(event)
+    }
+
+    pub fn peek(&self) -> Option<&Event> {
+        self.iter().next()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Event> + '_ {
+        (0..self.len).map(move |idx| &self.buf[(self.head + idx) % CAPACITY])
+    }
+}
+
+impl<const ROWS: usize, const COLS: usize> Default for EventQueue<ROWS, COLS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,93 @@
+use core::ptr;
+use core::slice;
+
+use kb789_firmware::storage::{Storage, PAGE_SIZE};
+use stm32f1::stm32f103;
+
+pub use kb789_firmware::storage::Error;
+
+pub const KEY1: u32 = 0x4567_0123;
+pub const KEY2: u32 = 0xCDEF_89AB;
+
+pub struct Flash {
+    regs: stm32f103::FLASH,
+}
+
+impl Flash {
+    pub fn new(regs: stm32f103::FLASH) -> Self {
+        Flash { regs }
+    }
+
+    fn unlock(&mut self) {
+        if self.regs.cr.read().lock().bit() {
+            self.regs.keyr.write(|w| unsafe { w.key().bits(KEY1) });
+            self.regs.keyr.write(|w| unsafe { w.key().bits(KEY2) });
+        }
+    }
+
+    fn lock(&mut self) {
+        self.regs.cr.modify(|_, w| w.lock().set_bit());
+    }
+
+    fn wait(&mut self) -> Result<(), Error> {
+        while self.regs.sr.read().bsy().bit() {}
+        let sr = self.regs.sr.read();
+        let res = if sr.pgerr().bit() {
+            Err(Error::Program)
+        } else if sr.wrprterr().bit() {
+            Err(Error::WriteProtected)
+        } else {
+            Ok(())
+        };
+        self.regs
+            .sr
+            .write(|w| w.eop().set_bit().pgerr().set_bit().wrprterr().set_bit());
+        res
+    }
+}
+
+impl Storage for Flash {
+    fn erase_page(&mut self, addr: u32) -> Result<(), Error> {
+        debug_assert!(addr % PAGE_SIZE == 0);
+        self.unlock();
+        self.regs.cr.modify(|_, w| w.per().set_bit());
+        self.regs.ar.write(|w| unsafe { w.far().bits(addr) });
+        self.regs
+            .cr
+            .modify(|_, w| w.per().set_bit().strt().set_bit());
+        let res = self.wait();
+        self.regs.cr.modify(|_, w| w.per().clear_bit());
+        self.lock();
+        res
+    }
+
+    fn program(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        debug_assert!(addr & 1 == 0);
+        self.unlock();
+        self.regs.cr.modify(|_, w| w.pg().set_bit());
+        let mut res = Ok(());
+        for (i, chunk) in data.chunks(2).enumerate() {
+            let half = match *chunk {
+                [lo, hi] => u16::from_le_bytes([lo, hi]),
+                [lo] => u16::from_le_bytes([lo, 0xff]),
+                _ => unreachable!(),
+            };
+            let dst = (addr + i as u32 * 2) as *mut u16;
+            unsafe { ptr::write_volatile(dst, half) };
+            res = self.wait();
+            if res.is_ok() && unsafe { ptr::read_volatile(dst) } != half {
+                res = Err(Error::Verify);
+            }
+            if res.is_err() {
+                break;
+            }
+        }
+        self.regs.cr.modify(|_, w| w.pg().clear_bit());
+        self.lock();
+        res
+    }
+
+    fn read(&self, addr: u32, len: usize) -> &[u8] {
+        unsafe { slice::from_raw_parts(addr as *const u8, len) }
+    }
+}
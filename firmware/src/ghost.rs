@@ -0,0 +1,111 @@
+use crate::KeyState;
+
+// Without diodes, three pressed corners of a rectangle make the fourth corner
+// read as pressed too. When two rows share two or more pressed columns, the
+// keys on those columns are ambiguous: they keep their last unambiguous state
+// and the scan is flagged so the report can carry ErrorRollOver instead.
+pub struct GhostFilter<const ROWS: usize, const COLS: usize> {
+    enabled: bool,
+    last: KeyState<ROWS, COLS>,
+}
+
+impl<const ROWS: usize, const COLS: usize> GhostFilter<ROWS, COLS> {
+    pub fn new(enabled: bool) -> Self {
+        GhostFilter {
+            enabled,
+            last: [[false; COLS]; ROWS],
+        }
+    }
+
+    pub fn filter(&mut self, state: &KeyState<ROWS, COLS>) -> (KeyState<ROWS, COLS>, bool) {
+        if !self.enabled {
+            return (*state, false);
+        }
+        let mut filtered = *state;
+        let mut ghosted = false;
+        for r1 in 0..ROWS {
+            for r2 in r1 + 1..ROWS {
+                let shared = (0..COLS)
+                    .filter(|&col| state[r1][col] && state[r2][col])
+                    .count();
+                if shared < 2 {
+                    continue;
+                }
+                ghosted = true;
+                for col in 0..COLS {
+                    if state[r1][col] && state[r2][col] {
+                        filtered[r1][col] = self.last[r1][col];
+                        filtered[r2][col] = self.last[r2][col];
+                    }
+                }
+            }
+        }
+        self.last = filtered;
+        (filtered, ghosted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pressed keys as (row, col)
+    fn state(keys: &[(usize, usize)]) -> KeyState<3, 3> {
+        let mut state = [[false; 3]; 3];
+        for &(row, col) in keys {
+            state[row][col] = true;
+        }
+        state
+    }
+
+    #[test]
+    fn fourth_corner_of_a_rectangle_is_suppressed() {
+        let mut filter = GhostFilter::new(true);
+        for keys in [&[(0, 0)][..], &[(0, 0), (0, 2)], &[(0, 0), (0, 2), (2, 0)]] {
+            assert_eq!(filter.filter(&state(keys)), (state(keys), false));
+        }
+        // The third corner makes the fourth read as pressed
+        let read = state(&[(0, 0), (0, 2), (2, 0), (2, 2)]);
+        assert_eq!(
+            filter.filter(&read),
+            (state(&[(0, 0), (0, 2), (2, 0)]), true)
+        );
+        assert_eq!(
+            filter.filter(&read),
+            (state(&[(0, 0), (0, 2), (2, 0)]), true)
+        );
+        // Releasing a corner resolves the rectangle again
+        let read = state(&[(0, 0), (2, 0)]);
+        assert_eq!(filter.filter(&read), (read, false));
+    }
+
+    #[test]
+    fn rectangle_seen_at_once_keeps_the_last_state() {
+        let mut filter = GhostFilter::new(true);
+        filter.filter(&state(&[(1, 1)]));
+        let read = state(&[(0, 0), (0, 2), (2, 0), (2, 2), (1, 1)]);
+        assert_eq!(filter.filter(&read), (state(&[(1, 1)]), true));
+    }
+
+    #[test]
+    fn non_rectangular_chords_pass() {
+        let chords: [&[(usize, usize)]; 4] = [
+            // A row, a column, a diagonal and an L
+            &[(1, 0), (1, 1), (1, 2)],
+            &[(0, 1), (1, 1), (2, 1)],
+            &[(0, 0), (1, 1), (2, 2)],
+            &[(0, 0), (1, 0), (2, 0), (2, 1), (2, 2)],
+        ];
+        for keys in chords {
+            let mut filter = GhostFilter::new(true);
+            assert_eq!(filter.filter(&state(keys)), (state(keys), false));
+        }
+    }
+
+    #[test]
+    fn disabled_filter_passes_rectangles() {
+        let mut filter = GhostFilter::new(false);
+        let read = state(&[(0, 0), (0, 2), (2, 0), (2, 2)]);
+        assert_eq!(filter.filter(&read), (read, false));
+    }
+}
@@ -1,3 +1,5 @@
+use stm32f1::stm32f103;
+
 #[allow(dead_code)]
 pub enum Mode {
     Input,
@@ -52,3 +54,80 @@ impl OutputCnf {
         }
     }
 }
+
+pub trait Pin {
+    fn configure_input(&self, cnf: InputCnf);
+    fn configure_output(&self, mode: Mode, cnf: OutputCnf);
+    fn set_high(&self);
+    fn set_low(&self);
+    fn is_high(&self) -> bool;
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Port {
+    A,
+    B,
+    C,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PortPin {
+    port: Port,
+    index: u8,
+}
+
+impl PortPin {
+    pub const fn new(port: Port, index: u8) -> Self {
+        PortPin { port, index }
+    }
+
+    fn regs(&self) -> &'static stm32f103::gpioa::RegisterBlock {
+        let ptr = match self.port {
+            Port::A => stm32f103::GPIOA::ptr(),
+            Port::B => stm32f103::GPIOB::ptr(),
+            Port::C => stm32f103::GPIOC::ptr(),
+        };
+        unsafe { &*ptr }
+    }
+
+    fn configure(&self, mode: u8, cnf: u8) {
+        let regs = self.regs();
+        let shift = (self.index % 8) * 4;
+        let mask = !(0b1111 << shift);
+        let bits = ((cnf << 2 | mode) as u32) << shift;
+        if self.index < 8 {
+            regs.crl
+                .modify(|r, w| unsafe { w.bits(r.bits() & mask | bits) });
+        } else {
+            regs.crh
+                .modify(|r, w| unsafe { w.bits(r.bits() & mask | bits) });
+        }
+    }
+}
+
+impl Pin for PortPin {
+    fn configure_input(&self, cnf: InputCnf) {
+        self.configure(Mode::Input.bits(), cnf.bits());
+    }
+
+    fn configure_output(&self, mode: Mode, cnf: OutputCnf) {
+        self.configure(mode.bits(), cnf.bits());
+    }
+
+    fn set_high(&self) {
+        self.regs()
+            .bsrr
+            .write(|w| unsafe { w.bits(1 << self.index) });
+    }
+
+    fn set_low(&self) {
+        self.regs()
+            .bsrr
+            .write(|w| unsafe { w.bits(1 << (self.index + 16)) });
+    }
+
+    fn is_high(&self) -> bool {
+        self.regs().idr.read().bits() & (1 << self.index) != 0
+    }
+}
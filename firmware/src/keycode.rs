@@ -0,0 +1,506 @@
+use core::fmt;
+use core::str::FromStr;
+
+macro_rules! keycodes {
+    ($($key:ident = $usage:literal, $name:literal;)*) => {
+        // Usages of the Keyboard/Keypad page (0x07)
+        #[allow(dead_code)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[repr(u8)]
+        pub enum Keycode {
+            $($key = $usage,)*
+        }
+
+        impl Keycode {
+            pub fn from_usage(usage: u8) -> Option<Self> {
+                match usage {
+                    $($usage => Some(Keycode::$key),)*
+                    _ => None,
+                }
+            }
+
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Keycode::$key => $name,)*
+                }
+            }
+
+            // Accepts the variant name ("LeftCtrl") or the display name
+            // ("Left Ctrl"), ignoring ASCII case.
+            fn parse(s: &str) -> Option<Self> {
+                $(
+                    if s.eq_ignore_ascii_case(stringify!($key)) || s.eq_ignore_ascii_case($name) {
+                        return Some(Keycode::$key);
+                    }
+                )*
+                None
+            }
+        }
+
+        // Every variant, in declaration order
+        #[cfg(test)]
+        const ALL: &[Keycode] = &[$(Keycode::$key,)*];
+    };
+}
+
+keycodes! {
+    No = 0x00, "No";
+    ErrorRollOver = 0x01, "ErrorRollOver";
+    PostFail = 0x02, "POSTFail";
+    ErrorUndefined = 0x03, "ErrorUndefined";
+    A = 0x04, "A";
+    B = 0x05, "B";
+    C = 0x06, "C";
+    D = 0x07, "D";
+    E = 0x08, "E";
+    F = 0x09, "F";
+    G = 0x0A, "G";
+    H = 0x0B, "H";
+    I = 0x0C, "I";
+    J = 0x0D, "J";
+    K = 0x0E, "K";
+    L = 0x0F, "L";
+    M = 0x10, "M";
+    N = 0x11, "N";
+    O = 0x12, "O";
+    P = 0x13, "P";
+    Q = 0x14, "Q";
+    R = 0x15, "R";
+    S = 0x16, "S";
+    T = 0x17, "T";
+    U = 0x18, "U";
+    V = 0x19, "V";
+    W = 0x1A, "W";
+    X = 0x1B, "X";
+    Y = 0x1C, "Y";
+    Z = 0x1D, "Z";
+    N1 = 0x1E, "1";
+    N2 = 0x1F, "2";
+    N3 = 0x20, "3";
+    N4 = 0x21, "4";
+    N5 = 0x22, "5";
+    N6 = 0x23, "6";
+    N7 = 0x24, "7";
+    N8 = 0x25, "8";
+    N9 = 0x26, "9";
+    N0 = 0x27, "0";
+    Enter = 0x28, "Enter";
+    Escape = 0x29, "Escape";
+    Backspace = 0x2A, "Backspace";
+    Tab = 0x2B, "Tab";
+    Space = 0x2C, "Space";
+    Minus = 0x2D, "-";
+    Equal = 0x2E, "=";
+    LeftBracket = 0x2F, "[";
+    RightBracket = 0x30, "]";
+    Backslash = 0x31, "\\";
+    NonUsHash = 0x32, "Non-US #";
+    Semicolon = 0x33, ";";
+    Quote = 0x34, "'";
+    Grave = 0x35, "`";
+    Comma = 0x36, ",";
+    Dot = 0x37, ".";
+    Slash = 0x38, "/";
+    CapsLock = 0x39, "Caps Lock";
+    F1 = 0x3A, "F1";
+    F2 = 0x3B, "F2";
+    F3 = 0x3C, "F3";
+    F4 = 0x3D, "F4";
+    F5 = 0x3E, "F5";
+    F6 = 0x3F, "F6";
+    F7 = 0x40, "F7";
+    F8 = 0x41, "F8";
+    F9 = 0x42, "F9";
+    F10 = 0x43, "F10";
+    F11 = 0x44, "F11";
+    F12 = 0x45, "F12";
+    PrintScreen = 0x46, "Print Screen";
+    ScrollLock = 0x47, "Scroll Lock";
+    Pause = 0x48, "Pause";
+    Insert = 0x49, "Insert";
+    Home = 0x4A, "Home";
+    PageUp = 0x4B, "Page Up";
+    Delete = 0x4C, "Delete";
+    End = 0x4D, "End";
+    PageDown = 0x4E, "Page Down";
+    Right = 0x4F, "Right";
+    Left = 0x50, "Left";
+    Down = 0x51, "Down";
+    Up = 0x52, "Up";
+    NumLock = 0x53, "Num Lock";
+    KpSlash = 0x54, "Keypad /";
+    KpAsterisk = 0x55, "Keypad *";
+    KpMinus = 0x56, "Keypad -";
+    KpPlus = 0x57, "Keypad +";
+    KpEnter = 0x58, "Keypad Enter";
+    Kp1 = 0x59, "Keypad 1";
+    Kp2 = 0x5A, "Keypad 2";
+    Kp3 = 0x5B, "Keypad 3";
+    Kp4 = 0x5C, "Keypad 4";
+    Kp5 = 0x5D, "Keypad 5";
+    Kp6 = 0x5E, "Keypad 6";
+    Kp7 = 0x5F, "Keypad 7";
+    Kp8 = 0x60, "Keypad 8";
+    Kp9 = 0x61, "Keypad 9";
+    Kp0 = 0x62, "Keypad 0";
+    KpDot = 0x63, "Keypad .";
+    NonUsBackslash = 0x64, "Non-US \\";
+    Application = 0x65, "Application";
+    Power = 0x66, "Power";
+    KpEqual = 0x67, "Keypad =";
+    F13 = 0x68, "F13";
+    F14 = 0x69, "F14";
+    F15 = 0x6A, "F15";
+    F16 = 0x6B, "F16";
+    F17 = 0x6C, "F17";
+    F18 = 0x6D, "F18";
+    F19 = 0x6E, "F19";
+    F20 = 0x6F, "F20";
+    F21 = 0x70, "F21";
+    F22 = 0x71, "F22";
+    F23 = 0x72, "F23";
+    F24 = 0x73, "F24";
+    Execute = 0x74, "Execute";
+    Help = 0x75, "Help";
+    Menu = 0x76, "Menu";
+    Select = 0x77, "Select";
+    Stop = 0x78, "Stop";
+    Again = 0x79, "Again";
+    Undo = 0x7A, "Undo";
+    Cut = 0x7B, "Cut";
+    Copy = 0x7C, "Copy";
+    Paste = 0x7D, "Paste";
+    Find = 0x7E, "Find";
+    Mute = 0x7F, "Mute";
+    VolumeUp = 0x80, "Volume Up";
+    VolumeDown = 0x81, "Volume Down";
+    LockingCapsLock = 0x82, "Locking Caps Lock";
+    LockingNumLock = 0x83, "Locking Num Lock";
+    LockingScrollLock = 0x84, "Locking Scroll Lock";
+    KpComma = 0x85, "Keypad ,";
+    KpEqualSign = 0x86, "Keypad Equal Sign";
+    International1 = 0x87, "International1 (Ro)";
+    International2 = 0x88, "International2 (Katakana/Hiragana)";
+    International3 = 0x89, "International3 (Yen)";
+    International4 = 0x8A, "International4 (Henkan)";
+    International5 = 0x8B, "International5 (Muhenkan)";
+    International6 = 0x8C, "International6";
+    International7 = 0x8D, "International7";
+    International8 = 0x8E, "International8";
+    International9 = 0x8F, "International9";
+    Lang1 = 0x90, "LANG1 (Kana)";
+    Lang2 = 0x91, "LANG2 (Eisu)";
+    Lang3 = 0x92, "LANG3";
+    Lang4 = 0x93, "LANG4";
+    Lang5 = 0x94, "LANG5";
+    Lang6 = 0x95, "LANG6";
+    Lang7 = 0x96, "LANG7";
+    Lang8 = 0x97, "LANG8";
+    Lang9 = 0x98, "LANG9";
+    AltErase = 0x99, "Alternate Erase";
+    SysReq = 0x9A, "SysReq";
+    Cancel = 0x9B, "Cancel";
+    Clear = 0x9C, "Clear";
+    Prior = 0x9D, "Prior";
+    Return = 0x9E, "Return";
+    Separator = 0x9F, "Separator";
+    Out = 0xA0, "Out";
+    Oper = 0xA1, "Oper";
+    ClearAgain = 0xA2, "Clear/Again";
+    CrSel = 0xA3, "CrSel";
+    ExSel = 0xA4, "ExSel";
+    Kp00 = 0xB0, "Keypad 00";
+    Kp000 = 0xB1, "Keypad 000";
+    ThousandsSeparator = 0xB2, "Thousands Separator";
+    DecimalSeparator = 0xB3, "Decimal Separator";
+    CurrencyUnit = 0xB4, "Currency Unit";
+    CurrencySubunit = 0xB5, "Currency Sub-unit";
+    KpLeftParen = 0xB6, "Keypad (";
+    KpRightParen = 0xB7, "Keypad )";
+    KpLeftBrace = 0xB8, "Keypad {";
+    KpRightBrace = 0xB9, "Keypad }";
+    KpTab = 0xBA, "Keypad Tab";
+    KpBackspace = 0xBB, "Keypad Backspace";
+    KpA = 0xBC, "Keypad A";
+    KpB = 0xBD, "Keypad B";
+    KpC = 0xBE, "Keypad C";
+    KpD = 0xBF, "Keypad D";
+    KpE = 0xC0, "Keypad E";
+    KpF = 0xC1, "Keypad F";
+    KpXor = 0xC2, "Keypad XOR";
+    KpCaret = 0xC3, "Keypad ^";
+    KpPercent = 0xC4, "Keypad %";
+    KpLess = 0xC5, "Keypad <";
+    KpGreater = 0xC6, "Keypad >";
+    KpAmpersand = 0xC7, "Keypad &";
+    KpDoubleAmpersand = 0xC8, "Keypad &&";
+    KpBar = 0xC9, "Keypad |";
+    KpDoubleBar = 0xCA, "Keypad ||";
+    KpColon = 0xCB, "Keypad :";
+    KpHash = 0xCC, "Keypad #";
+    KpSpace = 0xCD, "Keypad Space";
+    KpAt = 0xCE, "Keypad @";
+    KpExclamation = 0xCF, "Keypad !";
+    KpMemStore = 0xD0, "Keypad Memory Store";
+    KpMemRecall = 0xD1, "Keypad Memory Recall";
+    KpMemClear = 0xD2, "Keypad Memory Clear";
+    KpMemAdd = 0xD3, "Keypad Memory Add";
+    KpMemSubtract = 0xD4, "Keypad Memory Subtract";
+    KpMemMultiply = 0xD5, "Keypad Memory Multiply";
+    KpMemDivide = 0xD6, "Keypad Memory Divide";
+    KpPlusMinus = 0xD7, "Keypad +/-";
+    KpClear = 0xD8, "Keypad Clear";
+    KpClearEntry = 0xD9, "Keypad Clear Entry";
+    KpBinary = 0xDA, "Keypad Binary";
+    KpOctal = 0xDB, "Keypad Octal";
+    KpDecimal = 0xDC, "Keypad Decimal";
+    KpHexadecimal = 0xDD, "Keypad Hexadecimal";
+    LeftCtrl = 0xE0, "Left Ctrl";
+    LeftShift = 0xE1, "Left Shift";
+    LeftAlt = 0xE2, "Left Alt";
+    LeftGui = 0xE3, "Left GUI";
+    RightCtrl = 0xE4, "Right Ctrl";
+    RightShift = 0xE5, "Right Shift";
+    RightAlt = 0xE6, "Right Alt";
+    RightGui = 0xE7, "Right GUI";
+}
+
+impl Keycode {
+    pub fn usage(self) -> u8 {
+        self as u8
+    }
+
+    pub fn is_modifier(self) -> bool {
+        (Keycode::LeftCtrl.usage()..=Keycode::RightGui.usage()).contains(&self.usage())
+    }
+
+    // The key and whether Shift is needed to type `c` on a US layout
+    pub fn from_ascii(c: u8) -> Option<(Self, bool)> {
+        let (usage, shift) = match c {
+            b'a'..=b'z' => (Keycode::A.usage() + (c - b'a'), false),
+            b'A'..=b'Z' => (Keycode::A.usage() + (c - b'A'), true),
+            b'1'..=b'9' => (Keycode::N1.usage() + (c - b'1'), false),
+            b'0' => (Keycode::N0.usage(), false),
+            b'!' => (Keycode::N1.usage(), true),
+            b'@' => (Keycode::N2.usage(), true),
+            b'#' => (Keycode::N3.usage(), true),
+            b'$' => (Keycode::N4.usage(), true),
+            b'%' => (Keycode::N5.usage(), true),
+            b'^' => (Keycode::N6.usage(), true),
+            b'&' => (Keycode::N7.usage(), true),
+            b'*' => (Keycode::N8.usage(), true),
+            b'(' => (Keycode::N9.usage(), true),
+            b')' => (Keycode::N0.usage(), true),
+            b'\n' => (Keycode::Enter.usage(), false),
+            0x1b => (Keycode::Escape.usage(), false),
+            0x08 => (Keycode::Backspace.usage(), false),
+            b'\t' => (Keycode::Tab.usage(), false),
+            b' ' => (Keycode::Space.usage(), false),
+            b'-' => (Keycode::Minus.usage(), false),
+            b'_' => (Keycode::Minus.usage(), true),
+            b'=' => (Keycode::Equal.usage(), false),
+            b'+' => (Keycode::Equal.usage(), true),
+            b'[' => (Keycode::LeftBracket.usage(), false),
+            b'{' => (Keycode::LeftBracket.usage(), true),
+            b']' => (Keycode::RightBracket.usage(), false),
+            b'}' => (Keycode::RightBracket.usage(), true),
+            b'\\' => (Keycode::Backslash.usage(), false),
+            b'|' => (Keycode::Backslash.usage(), true),
+            b';' => (Keycode::Semicolon.usage(), false),
+            b':' => (Keycode::Semicolon.usage(), true),
+            b'\'' => (Keycode::Quote.usage(), false),
+            b'"' => (Keycode::Quote.usage(), true),
+            b'`' => (Keycode::Grave.usage(), false),
+            b'~' => (Keycode::Grave.usage(), true),
+            b',' => (Keycode::Comma.usage(), false),
+            b'<' => (Keycode::Comma.usage(), true),
+            b'.' => (Keycode::Dot.usage(), false),
+            b'>' => (Keycode::Dot.usage(), true),
+            b'/' => (Keycode::Slash.usage(), false),
+            b'?' => (Keycode::Slash.usage(), true),
+            _ => return None,
+        };
+        Keycode::from_usage(usage).map(|key| (key, shift))
+    }
+
+    // Bit in the modifier byte of the boot keyboard report
+    pub fn modifier_bit(self) -> Option<u8> {
+        if self.is_modifier() {
+            Some(1 << (self.usage() - Keycode::LeftCtrl.usage()))
+        } else {
+            None
+        }
+    }
+}
+
+// A set of keycodes, one bit per usage ID
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct KeySet([u32; 8]);
+
+impl KeySet {
+    pub fn new() -> Self {
+        KeySet([0; 8])
+    }
+
+    pub fn insert(&mut self, key: Keycode) {
+        let usage = key.usage() as usize;
+        self.0[usage / 32] |= 1 << (usage % 32);
+    }
+
+    pub fn remove(&mut self, key: Keycode) {
+        let usage = key.usage() as usize;
+        self.0[usage / 32] &= !(1 << (usage % 32));
+    }
+
+    pub fn contains(&self, key: Keycode) -> bool {
+        let usage = key.usage() as usize;
+        self.0[usage / 32] & (1 << (usage % 32)) != 0
+    }
+
+    pub fn clear(&mut self) {
+        self.0 = [0; 8];
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|word| *word == 0)
+    }
+
+    pub fn union(&self, other: &KeySet) -> KeySet {
+        let mut set = *self;
+        for (word, other) in set.0.iter_mut().zip(&other.0) {
+            *word |= *other;
+        }
+        set
+    }
+
+    pub fn intersection(&self, other: &KeySet) -> KeySet {
+        let mut set = *self;
+        for (word, other) in set.0.iter_mut().zip(&other.0) {
+            *word &= *other;
+        }
+        set
+    }
+
+    pub fn difference(&self, other: &KeySet) -> KeySet {
+        let mut set = *self;
+        for (word, other) in set.0.iter_mut().zip(&other.0) {
+            *word &= !*other;
+        }
+        set
+    }
+
+    // In ascending usage order
+    pub fn iter(&self) -> impl Iterator<Item = Keycode> + '_ {
+        (0..=255u8)
+            .filter_map(Keycode::from_usage)
+            .filter(move |key| self.contains(*key))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParseKeycodeError;
+
+impl FromStr for Keycode {
+    type Err = ParseKeycodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Keycode::parse(s).ok_or(ParseKeycodeError)
+    }
+}
+
+impl fmt::Display for Keycode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usages_round_trip() {
+        for &key in ALL {
+            assert_eq!(Keycode::from_usage(key as u8), Some(key), "{:?}", key);
+        }
+        let known = (0..=255u8).filter_map(Keycode::from_usage).count();
+        assert_eq!(known, ALL.len());
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for &key in ALL {
+            assert_eq!(key.name().parse(), Ok(key), "{:?}", key);
+            assert_eq!(format!("{:?}", key).parse(), Ok(key), "{:?}", key);
+            assert_eq!(key.to_string(), key.name());
+        }
+    }
+
+    #[test]
+    fn aliases_parse() {
+        for name in ["Left Shift", "LeftShift", "left shift", "LEFTSHIFT"] {
+            assert_eq!(name.parse(), Ok(Keycode::LeftShift), "{}", name);
+        }
+        assert_eq!("Keypad +/-".parse(), Ok(Keycode::KpPlusMinus));
+        assert_eq!("KpPlusMinus".parse(), Ok(Keycode::KpPlusMinus));
+        assert_eq!("LeftShiftt".parse::<Keycode>(), Err(ParseKeycodeError));
+        assert_eq!("".parse::<Keycode>(), Err(ParseKeycodeError));
+    }
+
+    #[test]
+    fn reserved_usages_are_gaps() {
+        for usage in [0xDE, 0xDF, 0xE8, 0xFF] {
+            assert_eq!(Keycode::from_usage(usage), None, "{:#04x}", usage);
+        }
+        assert_eq!(Keycode::from_usage(0xDD), Some(Keycode::KpHexadecimal));
+        assert_eq!(Keycode::from_usage(0xE0), Some(Keycode::LeftCtrl));
+    }
+
+    #[test]
+    fn ascii_shifted_pairs() {
+        let pairs = [
+            (b'a', b'A'),
+            (b'z', b'Z'),
+            (b'1', b'!'),
+            (b'2', b'@'),
+            (b'9', b'('),
+            (b'0', b')'),
+            (b'-', b'_'),
+            (b'=', b'+'),
+            (b'[', b'{'),
+            (b']', b'}'),
+            (b'\\', b'|'),
+            (b';', b':'),
+            (b'\'', b'"'),
+            (b'`', b'~'),
+            (b',', b'<'),
+            (b'.', b'>'),
+            (b'/', b'?'),
+        ];
+        for (plain, shifted) in pairs {
+            let (key, shift) = Keycode::from_ascii(plain).unwrap();
+            assert!(!shift, "{}", plain as char);
+            assert_eq!(
+                Keycode::from_ascii(shifted),
+                Some((key, true)),
+                "{}",
+                shifted as char
+            );
+        }
+        assert_eq!(Keycode::from_ascii(b'a'), Some((Keycode::A, false)));
+        assert_eq!(Keycode::from_ascii(b'0'), Some((Keycode::N0, false)));
+        assert_eq!(Keycode::from_ascii(b' '), Some((Keycode::Space, false)));
+        assert_eq!(Keycode::from_ascii(b'\n'), Some((Keycode::Enter, false)));
+        assert_eq!(Keycode::from_ascii(0x7F), None);
+        assert_eq!(Keycode::from_ascii(0xE9), None);
+    }
+
+    #[test]
+    fn modifier_bits() {
+        assert_eq!(Keycode::LeftCtrl.modifier_bit(), Some(0x01));
+        assert_eq!(Keycode::RightGui.modifier_bit(), Some(0x80));
+        assert_eq!(Keycode::A.modifier_bit(), None);
+        let modifiers = ALL.iter().filter(|key| key.is_modifier()).count();
+        assert_eq!(modifiers, 8);
+    }
+}
@@ -0,0 +1,104 @@
+use crate::keycode::{KeySet, Keycode};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    Off,
+    // Waiting for the key to lock
+    Armed,
+    // Held whether or not its key is down
+    Locked(Keycode),
+}
+
+// Keeps the first key pressed after KeyLock held until that key is pressed
+// again. Arming again while a key is locked releases it and stays armed, so
+// the next key pressed is locked in its place; arming again before any key
+// was locked cancels.
+pub struct KeyLock {
+    state: State,
+}
+
+impl KeyLock {
+    pub fn new() -> Self {
+        KeyLock { state: State::Off }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn arm(&mut self) {
+        self.state = match self.state {
+            State::Armed => State::Off,
+            _ => State::Armed,
+        };
+    }
+
+    // Called for every key pressed while the keymap runs. The press that
+    // unlocks a key holds it as usual until its release.
+    pub fn press(&mut self, key: Keycode) {
+        match self.state {
+            State::Armed => self.state = State::Locked(key),
+            State::Locked(locked) if locked == key => self.state = State::Off,
+            _ => {}
+        }
+    }
+
+    pub fn apply(&self, keys: &mut KeySet) {
+        if let State::Locked(key) = self.state {
+            keys.insert(key);
+        }
+    }
+}
+
+impl Default for KeyLock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn applied(key_lock: &KeyLock, held: &[Keycode]) -> Vec<Keycode> {
+        let mut set = KeySet::new();
+        for &key in held {
+            set.insert(key);
+        }
+        key_lock.apply(&mut set);
+        set.iter().collect()
+    }
+
+    #[test]
+    fn locks_the_next_key_until_pressed_again() {
+        let mut key_lock = KeyLock::new();
+        key_lock.arm();
+        assert_eq!(key_lock.state(), State::Armed);
+        assert_eq!(applied(&key_lock, &[]), []);
+        key_lock.press(Keycode::W);
+        assert_eq!(key_lock.state(), State::Locked(Keycode::W));
+        assert_eq!(applied(&key_lock, &[]), [Keycode::W]);
+        key_lock.press(Keycode::A);
+        assert_eq!(applied(&key_lock, &[Keycode::A]), [Keycode::A, Keycode::W]);
+        key_lock.press(Keycode::W);
+        assert_eq!(key_lock.state(), State::Off);
+        assert_eq!(applied(&key_lock, &[Keycode::W]), [Keycode::W]);
+        assert_eq!(applied(&key_lock, &[]), []);
+    }
+
+    #[test]
+    fn arming_again_releases_or_cancels() {
+        let mut key_lock = KeyLock::new();
+        key_lock.arm();
+        key_lock.arm();
+        assert_eq!(key_lock.state(), State::Off);
+        key_lock.press(Keycode::W);
+        assert_eq!(key_lock.state(), State::Off);
+
+        key_lock.arm();
+        key_lock.press(Keycode::W);
+        key_lock.arm();
+        assert_eq!(key_lock.state(), State::Armed);
+        assert_eq!(applied(&key_lock, &[]), []);
+    }
+}
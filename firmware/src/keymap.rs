@@ -0,0 +1,1344 @@
+use crate::capsword::CapsWord;
+use crate::combo::{self, Combo, Match, Release};
+use crate::dance::{Dance, Outcome, TapDance};
+use crate::event::EventQueue;
+use crate::keycode::{KeySet, Keycode};
+use crate::keylock::{self, KeyLock};
+use crate::unicode;
+
+// Layer numbers are bits in a u32
+pub const MAX_LAYERS: usize = 32;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    // Does nothing and hides the layers below
+    No,
+    // Falls through to the next active layer below
+    Trans,
+    Key(Keycode),
+    // Layer active while held (MO)
+    Momentary(u8),
+    // Layer flips on each press (TG)
+    Toggle(u8),
+    // Layer becomes the only active one above the default layer (TO)
+    To(u8),
+    // Replaces the default layer (DF)
+    Default(u8),
+    // Layer active for the next key press only (OSL)
+    OneShot(u8),
+    // `tap` on tap, the modifier `hold` on hold (MT)
+    ModTap { hold: Keycode, tap: Keycode },
+    // `tap` on tap, momentary `layer` on hold (LT)
+    LayerTap { layer: u8, tap: Keycode },
+    // Index into the keymap's tap dances (TD)
+    TapDance(u8),
+    // Index into the macro player's macros, played on press
+    Macro(u8),
+    // Starts recording into a dynamic macro slot, or stops recording
+    RecordMacro(u8),
+    // Plays a dynamic macro slot
+    PlayRecorded(u8),
+    // Starts a leader key sequence
+    Leader,
+    // `key` on tap, Shift+`key` once held for `timeout_ms`. Meant for
+    // alphas and numbers; pressing another key in the meantime taps `key`.
+    // The Shift is left out while another key is held along.
+    AutoShift { key: Keycode, timeout_ms: u16 },
+    // `key`, repeated by the firmware at `rate` rather than by the host.
+    // Only the last Repeat key pressed repeats.
+    Repeat { key: Keycode, rate: RepeatRate },
+    // Types a code point through the host's input method
+    Unicode(char),
+    // Selects the host input method Unicode actions type through
+    UnicodeMode(unicode::Mode),
+    // Turns Caps Word on, or off again
+    CapsWord,
+    // Locks the next key pressed (see keylock::KeyLock)
+    KeyLock,
+}
+
+pub type Layer<const ROWS: usize, const COLS: usize> = [[Action; COLS]; ROWS];
+
+// How a ModTap/LayerTap key is decided. It is a hold once it has been down
+// for `tapping_term_ms`, and a tap if released before that. Keys pressed
+// in the meantime wait in the event queue until the decision is made.
+#[derive(Debug, Clone, Copy)]
+pub struct TapHoldConfig {
+    pub tapping_term_ms: u16,
+    // Hold as soon as another key is pressed and released inside the term.
+    pub permissive_hold: bool,
+    // Hold as soon as another key is pressed inside the term.
+    pub hold_on_other_key_press: bool,
+    // Send the tap on release after a hold in which no other key was
+    // pressed.
+    pub retro_tapping: bool,
+}
+
+// A Repeat key is tapped again every `interval_ms` once held for
+// `delay_ms`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RepeatRate {
+    pub delay_ms: u16,
+    pub interval_ms: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Decision {
+    Tap,
+    Hold,
+}
+
+// A ModTap/LayerTap/AutoShift press that is not decided yet
+#[derive(Debug, Clone, Copy)]
+struct Pending {
+    row: usize,
+    col: usize,
+    time: u32,
+    action: Action,
+}
+
+// The held Repeat key. Between repeats the key is lifted for one report,
+// which the host sees as a release.
+#[derive(Debug, Clone, Copy)]
+struct Repeating {
+    row: usize,
+    col: usize,
+    // Set by the first step after the press
+    since: Option<u32>,
+    wait: u16,
+    lifted: bool,
+}
+
+// A key whose press went into a combo
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ComboMember {
+    combo: usize,
+    // Where the combo's action is held: the combo's first pressed key
+    anchor: (usize, usize),
+}
+
+// Resolves matrix positions to actions through a stack of layers. The
+// highest active layer wins; Trans entries fall through to the ones below,
+// ending at the default layer. The action is looked up once at press time
+// and remembered, so a release always undoes what its press did even if
+// the layers changed in between.
+pub struct Keymap<const LAYERS: usize, const ROWS: usize, const COLS: usize> {
+    layers: &'static [Layer<ROWS, COLS>; LAYERS],
+    combos: &'static [Combo],
+    dances: &'static [TapDance],
+    tap_hold: TapHoldConfig,
+    default_layer: u8,
+    active: u32,
+    oneshot: Option<u8>,
+    held: [[Option<Action>; COLS]; ROWS],
+    combo_of: [[Option<ComboMember>; COLS]; ROWS],
+    pending: Option<Pending>,
+    dance: Option<Dance>,
+    // The last key pressed, if it is a held tap-hold key
+    retro: Option<(usize, usize, Keycode)>,
+    // A tap pressed by the previous step, released by the next one
+    tapped: Option<(usize, usize)>,
+    repeating: Option<Repeating>,
+    caps_word: CapsWord,
+    key_lock: KeyLock,
+    // The last action pressed that main carries out: macros, the leader
+    // and Unicode input
+    request: Option<Action>,
+}
+
+impl<const LAYERS: usize, const ROWS: usize, const COLS: usize> Keymap<LAYERS, ROWS, COLS> {
+    pub fn new(
+        layers: &'static [Layer<ROWS, COLS>; LAYERS],
+        combos: &'static [Combo],
+        dances: &'static [TapDance],
+        tap_hold: TapHoldConfig,
+    ) -> Self {
+        assert!(LAYERS > 0 && LAYERS <= MAX_LAYERS);
+        Keymap {
+            layers,
+            combos,
+            dances,
+            tap_hold,
+            default_layer: 0,
+            active: 0,
+            oneshot: None,
+            held: [[None; COLS]; ROWS],
+            combo_of: [[None; COLS]; ROWS],
+            pending: None,
+            dance: None,
+            retro: None,
+            tapped: None,
+            repeating: None,
+            caps_word: CapsWord::new(),
+            key_lock: KeyLock::new(),
+            request: None,
+        }
+    }
+
+    fn is_active(&self, layer: usize) -> bool {
+        layer == self.default_layer as usize
+            || self.active & (1 << layer) != 0
+            || self.oneshot == Some(layer as u8)
+    }
+
+    fn lookup(&self, row: usize, col: usize) -> Action {
+        for layer in (0..LAYERS).rev() {
+            if !self.is_active(layer) {
+                continue;
+            }
+            match self.layers[layer][row][col] {
+                Action::Trans => continue,
+                action => return action,
+            }
+        }
+        Action::No
+    }
+
+    // Out-of-range layer numbers are ignored rather than trusted as shifts.
+    fn layer_bit(layer: u8) -> u32 {
+        if (layer as usize) < LAYERS {
+            1 << layer
+        } else {
+            0
+        }
+    }
+
+    // Handles at most one event, so every change gets a report of its own.
+    // Returns false when there is nothing to do until the next scan.
+    pub fn step(&mut self, events: &mut EventQueue<ROWS, COLS>, now: u32) -> bool {
+        if let Some((row, col)) = self.tapped.take() {
+            self.release_action(row, col);
+            return true;
+        }
+        if self.step_repeat(now) {
+            return true;
+        }
+        if let Some(pending) = self.pending {
+            return match self.decide(&pending, events, now) {
+                Some(decision) => {
+                    self.pending = None;
+                    self.resolve(&pending, decision);
+                    true
+                }
+                None => false,
+            };
+        }
+        if let Some(dance) = self.dance {
+            return self.step_dance(dance, events, now);
+        }
+        if matches!(events.peek(), Some(event) if event.pressed) {
+            match combo::find(self.combos, events, now) {
+                Match::Fire(idx) => {
+                    self.fire_combo(idx, events);
+                    return true;
+                }
+                Match::Wait => return false,
+                Match::None => {}
+            }
+        }
+        let event = match events.pop() {
+            Some(event) => event,
+            None => return false,
+        };
+        if event.pressed {
+            self.retro = None;
+            let action = self.lookup(event.row, event.col);
+            match action {
+                Action::ModTap { .. } | Action::LayerTap { .. } | Action::AutoShift { .. } => {
+                    self.pending = Some(Pending {
+                        row: event.row,
+                        col: event.col,
+                        time: event.time,
+                        action,
+                    });
+                }
+                Action::TapDance(idx) => match self.dances.get(idx as usize) {
+                    Some(dance) => {
+                        self.dance = Some(Dance::start(event.row, event.col, dance, event.time));
+                    }
+                    None => self.press(event.row, event.col, Action::No),
+                },
+                _ => self.press(event.row, event.col, action),
+            }
+        } else {
+            self.release(event.row, event.col);
+        }
+        true
+    }
+
+    // Looks through the events queued behind a tap-hold press for anything
+    // that settles it. Events are judged by their own timestamps, so a
+    // backlog does not turn taps into holds.
+    fn decide(
+        &self,
+        pending: &Pending,
+        events: &EventQueue<ROWS, COLS>,
+        now: u32,
+    ) -> Option<Decision> {
+        let (term, auto_shift) = match pending.action {
+            Action::AutoShift { timeout_ms, .. } => (timeout_ms as u32, true),
+            _ => (self.tap_hold.tapping_term_ms as u32, false),
+        };
+        for (idx, event) in events.iter().enumerate() {
+            if event.time.wrapping_sub(pending.time) >= term {
+                return Some(Decision::Hold);
+            }
+            let is_pending = event.row == pending.row && event.col == pending.col;
+            if is_pending && !event.pressed {
+                return Some(Decision::Tap);
+            }
+            if is_pending {
+                continue;
+            }
+            if auto_shift {
+                if event.pressed {
+                    return Some(Decision::Tap);
+                }
+                continue;
+            }
+            if event.pressed && self.tap_hold.hold_on_other_key_press {
+                return Some(Decision::Hold);
+            }
+            if !event.pressed
+                && self.tap_hold.permissive_hold
+                && events
+                    .iter()
+                    .take(idx)
+                    .any(|prev| prev.pressed && prev.row == event.row && prev.col == event.col)
+            {
+                return Some(Decision::Hold);
+            }
+        }
+        if now.wrapping_sub(pending.time) >= term {
+            Some(Decision::Hold)
+        } else {
+            None
+        }
+    }
+
+    fn resolve(&mut self, pending: &Pending, decision: Decision) {
+        let (hold, tap) = match pending.action {
+            Action::ModTap { hold, tap } => (Action::Key(hold), tap),
+            Action::LayerTap { layer, tap } => (Action::Momentary(layer), tap),
+            // Held, the action itself stands for Shift+key
+            Action::AutoShift { key, .. } => (pending.action, key),
+            _ => return,
+        };
+        match decision {
+            Decision::Tap => self.press(pending.row, pending.col, Action::Key(tap)),
+            Decision::Hold => {
+                self.press(pending.row, pending.col, hold);
+                if self.tap_hold.retro_tapping
+                    && !matches!(pending.action, Action::AutoShift { .. })
+                {
+                    self.retro = Some((pending.row, pending.col, tap));
+                }
+            }
+        }
+    }
+
+    // Feeds the dance key's own events to the dance. Other keys' releases go
+    // through as usual; a press of another key, or the timeout passing, ends
+    // it first.
+    fn step_dance(
+        &mut self,
+        mut dance: Dance,
+        events: &mut EventQueue<ROWS, COLS>,
+        now: u32,
+    ) -> bool {
+        let outcome = match events.peek().copied() {
+            Some(event) if dance.expired(event.time) => dance.finish(),
+            Some(event) if dance.is_key(&event) => {
+                events.pop();
+                match dance.feed(&event) {
+                    Some(outcome) => outcome,
+                    None => {
+                        self.dance = Some(dance);
+                        return true;
+                    }
+                }
+            }
+            Some(event) if !event.pressed => {
+                events.pop();
+                self.release(event.row, event.col);
+                return true;
+            }
+            Some(_) => dance.finish(),
+            None if dance.expired(now) => dance.finish(),
+            None => return false,
+        };
+        self.dance = None;
+        match outcome {
+            Outcome::Tap(action) => {
+                self.press(dance.row, dance.col, action);
+                self.tapped = Some((dance.row, dance.col));
+            }
+            Outcome::Hold(action) => self.press(dance.row, dance.col, action),
+        }
+        true
+    }
+
+    // Lifts the repeating key once its wait is over and presses it again in
+    // the next step.
+    fn step_repeat(&mut self, now: u32) -> bool {
+        let repeating = match &mut self.repeating {
+            Some(repeating) => repeating,
+            None => return false,
+        };
+        if repeating.lifted {
+            repeating.lifted = false;
+            return true;
+        }
+        let interval_ms = match self.held[repeating.row][repeating.col] {
+            Some(Action::Repeat { rate, .. }) => rate.interval_ms,
+            _ => return false,
+        };
+        let since = *repeating.since.get_or_insert(now);
+        if now.wrapping_sub(since) < repeating.wait as u32 {
+            return false;
+        }
+        repeating.since = Some(now);
+        repeating.wait = interval_ms;
+        repeating.lifted = true;
+        true
+    }
+
+    fn fire_combo(&mut self, idx: usize, events: &mut EventQueue<ROWS, COLS>) {
+        let combo = &self.combos[idx];
+        let anchor = match events.peek() {
+            Some(event) => (event.row, event.col),
+            None => return,
+        };
+        let member = ComboMember { combo: idx, anchor };
+        for _ in combo.keys {
+            if let Some(event) = events.pop() {
+                self.combo_of[event.row][event.col] = Some(member);
+            }
+        }
+        self.retro = None;
+        self.press(anchor.0, anchor.1, combo.action);
+    }
+
+    fn press(&mut self, row: usize, col: usize, action: Action) {
+        self.held[row][col] = Some(action);
+        match action {
+            Action::Key(key) | Action::AutoShift { key, .. } | Action::Repeat { key, .. } => {
+                self.caps_word.press(key);
+                self.key_lock.press(key);
+            }
+            _ => {}
+        }
+        match action {
+            Action::No
+            | Action::Trans
+            | Action::Key(_)
+            | Action::ModTap { .. }
+            | Action::LayerTap { .. }
+            | Action::TapDance(_)
+            | Action::AutoShift { .. } => {}
+            Action::CapsWord => self.caps_word.toggle(),
+            Action::KeyLock => self.key_lock.arm(),
+            Action::Repeat { rate, .. } => {
+                self.repeating = Some(Repeating {
+                    row,
+                    col,
+                    since: None,
+                    wait: rate.delay_ms,
+                    lifted: false,
+                });
+            }
+            Action::Momentary(layer) => self.active |= Self::layer_bit(layer),
+            Action::Toggle(layer) => self.active ^= Self::layer_bit(layer),
+            Action::To(layer) => {
+                if (layer as usize) < LAYERS {
+                    self.active = 1 << layer;
+                }
+            }
+            Action::Default(layer) => {
+                if (layer as usize) < LAYERS {
+                    self.default_layer = layer;
+                }
+            }
+            Action::OneShot(layer) => {
+                if (layer as usize) < LAYERS {
+                    self.oneshot = Some(layer);
+                }
+                return;
+            }
+            Action::Macro(_)
+            | Action::RecordMacro(_)
+            | Action::PlayRecorded(_)
+            | Action::Leader
+            | Action::Unicode(_)
+            | Action::UnicodeMode(_) => self.request = Some(action),
+        }
+        self.oneshot = None;
+    }
+
+    // A combo's action is released once, by its last key or, with
+    // Release::AnyKey, by its first. Then the keys still held drop out of
+    // the combo, so their releases cannot touch a new press of the anchor.
+    fn release(&mut self, row: usize, col: usize) {
+        if let Some(member) = self.combo_of[row][col].take() {
+            let last = !self
+                .combo_of
+                .iter()
+                .flatten()
+                .any(|other| *other == Some(member));
+            if last || self.combos[member.combo].release == Release::AnyKey {
+                for other in self.combo_of.iter_mut().flatten() {
+                    if *other == Some(member) {
+                        *other = None;
+                    }
+                }
+                self.release_action(member.anchor.0, member.anchor.1);
+            }
+        } else {
+            self.release_action(row, col);
+        }
+    }
+
+    fn release_action(&mut self, row: usize, col: usize) {
+        if let Some(repeating) = self.repeating {
+            if (repeating.row, repeating.col) == (row, col) {
+                self.repeating = None;
+            }
+        }
+        if let Some(Action::Momentary(layer)) = self.held[row][col].take() {
+            let still_held = self
+                .held
+                .iter()
+                .flatten()
+                .any(|action| *action == Some(Action::Momentary(layer)));
+            if !still_held {
+                self.active &= !Self::layer_bit(layer);
+            }
+        }
+        if let Some((retro_row, retro_col, tap)) = self.retro {
+            if (retro_row, retro_col) == (row, col) {
+                self.retro = None;
+                self.press(row, col, Action::Key(tap));
+                self.tapped = Some((row, col));
+            }
+        }
+    }
+
+    // The action for main pressed since the last call, if any
+    pub fn take_request(&mut self) -> Option<Action> {
+        self.request.take()
+    }
+
+    pub fn caps_word(&self) -> bool {
+        self.caps_word.is_active()
+    }
+
+    pub fn key_lock(&self) -> keylock::State {
+        self.key_lock.state()
+    }
+
+    // Keycodes of every held Key, AutoShift and Repeat action, plus the
+    // locked key and Caps Word's Shift. Shift is one bit for the whole
+    // report, so held AutoShift keys only get theirs while every other key
+    // is a modifier.
+    pub fn keys(&self) -> KeySet {
+        let lifted = match self.repeating {
+            Some(repeating) if repeating.lifted => Some((repeating.row, repeating.col)),
+            _ => None,
+        };
+        let mut keys = KeySet::new();
+        let mut shifted = KeySet::new();
+        for (row, actions) in self.held.iter().enumerate() {
+            for (col, action) in actions.iter().enumerate() {
+                match action {
+                    Some(Action::Key(key)) => keys.insert(*key),
+                    Some(Action::AutoShift { key, .. }) => {
+                        shifted.insert(*key);
+                        keys.insert(*key);
+                    }
+                    Some(Action::Repeat { key, .. }) if lifted != Some((row, col)) => {
+                        keys.insert(*key)
+                    }
+                    _ => {}
+                }
+            }
+        }
+        self.key_lock.apply(&mut keys);
+        if !shifted.is_empty()
+            && keys
+                .iter()
+                .all(|key| key.is_modifier() || shifted.contains(key))
+        {
+            keys.insert(Keycode::LeftShift);
+        }
+        self.caps_word.apply(&mut keys);
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dance::Step;
+
+    const MT: usize = 0;
+    const LT: usize = 1;
+    const AS: usize = 0;
+    const REP: usize = 1;
+    const C: usize = 2;
+
+    static LAYERS: [Layer<1, 3>; 2] = [
+        [[
+            Action::ModTap {
+                hold: Keycode::LeftCtrl,
+                tap: Keycode::A,
+            },
+            Action::LayerTap {
+                layer: 1,
+                tap: Keycode::B,
+            },
+            Action::Key(Keycode::C),
+        ]],
+        [[Action::Trans, Action::Trans, Action::Key(Keycode::X)]],
+    ];
+
+    static AUTO_LAYERS: [Layer<1, 3>; 2] = [
+        [[
+            Action::AutoShift {
+                key: Keycode::A,
+                timeout_ms: 150,
+            },
+            Action::Repeat {
+                key: Keycode::B,
+                rate: RepeatRate {
+                    delay_ms: 300,
+                    interval_ms: 50,
+                },
+            },
+            Action::Key(Keycode::C),
+        ]],
+        [[Action::Trans, Action::Trans, Action::Trans]],
+    ];
+
+    const fn tap_hold(
+        permissive_hold: bool,
+        hold_on_other_key_press: bool,
+        retro_tapping: bool,
+    ) -> TapHoldConfig {
+        TapHoldConfig {
+            tapping_term_ms: 200,
+            permissive_hold,
+            hold_on_other_key_press,
+            retro_tapping,
+        }
+    }
+
+    const PLAIN: TapHoldConfig = tap_hold(false, false, false);
+    const PERMISSIVE: TapHoldConfig = tap_hold(true, false, false);
+    const HOLD_ON_PRESS: TapHoldConfig = tap_hold(false, true, false);
+    const RETRO: TapHoldConfig = tap_hold(false, false, true);
+
+    struct Case {
+        name: &'static str,
+        tap_hold: TapHoldConfig,
+        // (ms, col, pressed) on the only row
+        events: &'static [(u32, usize, bool)],
+        // Every distinct key set the keymap produces, in order
+        keys: &'static [&'static [Keycode]],
+    }
+
+    const CASES: &[Case] = &[
+        Case {
+            name: "tap",
+            tap_hold: PLAIN,
+            events: &[(0, MT, true), (100, MT, false)],
+            keys: &[&[Keycode::A], &[]],
+        },
+        Case {
+            name: "hold past the term",
+            tap_hold: PLAIN,
+            events: &[(0, MT, true), (300, MT, false)],
+            keys: &[&[Keycode::LeftCtrl], &[]],
+        },
+        Case {
+            name: "nested key is a tap without permissive hold",
+            tap_hold: PLAIN,
+            events: &[
+                (0, MT, true),
+                (50, C, true),
+                (100, C, false),
+                (150, MT, false),
+            ],
+            keys: &[&[Keycode::A], &[Keycode::A, Keycode::C], &[Keycode::A], &[]],
+        },
+        Case {
+            name: "nested key is a hold with permissive hold",
+            tap_hold: PERMISSIVE,
+            events: &[
+                (0, MT, true),
+                (50, C, true),
+                (100, C, false),
+                (150, MT, false),
+            ],
+            keys: &[
+                &[Keycode::LeftCtrl],
+                &[Keycode::C, Keycode::LeftCtrl],
+                &[Keycode::LeftCtrl],
+                &[],
+            ],
+        },
+        Case {
+            name: "rolled key is a tap with permissive hold",
+            tap_hold: PERMISSIVE,
+            events: &[
+                (0, MT, true),
+                (50, C, true),
+                (100, MT, false),
+                (150, C, false),
+            ],
+            keys: &[&[Keycode::A], &[Keycode::A, Keycode::C], &[Keycode::C], &[]],
+        },
+        Case {
+            name: "rolled key is a hold with hold on other key press",
+            tap_hold: HOLD_ON_PRESS,
+            events: &[
+                (0, MT, true),
+                (50, C, true),
+                (100, MT, false),
+                (150, C, false),
+            ],
+            keys: &[
+                &[Keycode::LeftCtrl],
+                &[Keycode::C, Keycode::LeftCtrl],
+                &[Keycode::C],
+                &[],
+            ],
+        },
+        Case {
+            name: "lone hold taps on release with retro tapping",
+            tap_hold: RETRO,
+            events: &[(0, MT, true), (300, MT, false)],
+            keys: &[&[Keycode::LeftCtrl], &[Keycode::A], &[]],
+        },
+        Case {
+            name: "other key cancels retro tapping",
+            tap_hold: RETRO,
+            events: &[
+                (0, MT, true),
+                (250, C, true),
+                (260, C, false),
+                (300, MT, false),
+            ],
+            keys: &[
+                &[Keycode::LeftCtrl],
+                &[Keycode::C, Keycode::LeftCtrl],
+                &[Keycode::LeftCtrl],
+                &[],
+            ],
+        },
+        Case {
+            name: "layer tap",
+            tap_hold: PLAIN,
+            events: &[(0, LT, true), (100, LT, false)],
+            keys: &[&[Keycode::B], &[]],
+        },
+        Case {
+            name: "layer hold",
+            tap_hold: PLAIN,
+            events: &[
+                (0, LT, true),
+                (250, C, true),
+                (260, C, false),
+                (300, LT, false),
+            ],
+            keys: &[&[Keycode::X], &[]],
+        },
+    ];
+
+    // Scans once per ms and steps the keymap until it has nothing to do.
+    // Returns every distinct key set the keymap produces, in order.
+    fn run(
+        layers: &'static [Layer<1, 3>; 2],
+        tap_hold: TapHoldConfig,
+        trace: &[(u32, usize, bool)],
+    ) -> Vec<Vec<Keycode>> {
+        let keymap = Keymap::new(layers, &[], &[], tap_hold);
+        run_timed(keymap, trace)
+            .into_iter()
+            .map(|(_, keys)| keys)
+            .collect()
+    }
+
+    // Like run, with the ms at which each key set appeared
+    fn run_timed<const LAYERS: usize, const COLS: usize>(
+        mut keymap: Keymap<LAYERS, 1, COLS>,
+        trace: &[(u32, usize, bool)],
+    ) -> Vec<(u32, Vec<Keycode>)> {
+        let mut events = EventQueue::<1, COLS>::new();
+        let mut state = [[false; COLS]];
+        let mut last = KeySet::new();
+        let mut seen = Vec::new();
+        for now in 0..1000 {
+            for &(_, col, pressed) in trace.iter().filter(|(at, ..)| *at == now) {
+                state[0][col] = pressed;
+            }
+            events.push_changes(&state, now);
+            while keymap.step(&mut events, now) {
+                let keys = keymap.keys();
+                if keys != last {
+                    seen.push((now, keys.iter().collect()));
+                    last = keys;
+                }
+            }
+        }
+        seen
+    }
+
+    #[test]
+    fn tap_hold_decisions() {
+        for case in CASES {
+            let keys = run(&LAYERS, case.tap_hold, case.events);
+            assert_eq!(keys, case.keys, "{}", case.name);
+        }
+    }
+
+    #[test]
+    fn auto_shift_tap() {
+        let keys = run(&AUTO_LAYERS, PLAIN, &[(0, AS, true), (100, AS, false)]);
+        assert_eq!(keys, [&[Keycode::A][..], &[]]);
+    }
+
+    #[test]
+    fn auto_shift_hold() {
+        let keys = run(&AUTO_LAYERS, PLAIN, &[(0, AS, true), (300, AS, false)]);
+        assert_eq!(keys, [&[Keycode::A, Keycode::LeftShift][..], &[]]);
+    }
+
+    #[test]
+    fn auto_shift_taps_when_another_key_is_pressed() {
+        let trace = [
+            (0, AS, true),
+            (50, C, true),
+            (100, AS, false),
+            (150, C, false),
+        ];
+        let keys = run(&AUTO_LAYERS, PLAIN, &trace);
+        assert_eq!(
+            keys,
+            [
+                &[Keycode::A][..],
+                &[Keycode::A, Keycode::C],
+                &[Keycode::C],
+                &[],
+            ]
+        );
+    }
+
+    #[test]
+    fn auto_shift_does_not_shift_other_keys() {
+        let trace = [
+            (0, AS, true),
+            (200, C, true),
+            (250, C, false),
+            (300, AS, false),
+        ];
+        let keys = run(&AUTO_LAYERS, PLAIN, &trace);
+        assert_eq!(
+            keys,
+            [
+                &[Keycode::A, Keycode::LeftShift][..],
+                &[Keycode::A, Keycode::C],
+                &[Keycode::A, Keycode::LeftShift],
+                &[],
+            ]
+        );
+        let trace = [
+            (0, C, true),
+            (50, AS, true),
+            (300, AS, false),
+            (350, C, false),
+        ];
+        let keys = run(&AUTO_LAYERS, PLAIN, &trace);
+        assert_eq!(
+            keys,
+            [
+                &[Keycode::C][..],
+                &[Keycode::A, Keycode::C],
+                &[Keycode::C],
+                &[],
+            ]
+        );
+    }
+
+    #[test]
+    fn repeat_lifts_the_key_between_repeats() {
+        let keys = run(&AUTO_LAYERS, PLAIN, &[(0, REP, true), (420, REP, false)]);
+        let b: &[Keycode] = &[Keycode::B];
+        assert_eq!(keys, [b, &[], b, &[], b, &[], b, &[]]);
+    }
+
+    #[test]
+    fn repeat_leaves_other_keys_held() {
+        let trace = [
+            (0, C, true),
+            (10, REP, true),
+            (330, REP, false),
+            (400, C, false),
+        ];
+        let keys = run(&AUTO_LAYERS, PLAIN, &trace);
+        assert_eq!(
+            keys,
+            [
+                &[Keycode::C][..],
+                &[Keycode::B, Keycode::C],
+                &[Keycode::C],
+                &[Keycode::B, Keycode::C],
+                &[Keycode::C],
+                &[],
+            ]
+        );
+    }
+
+    const MO: usize = 0;
+    const MO2: usize = 1;
+    const TG: usize = 2;
+    const TO: usize = 3;
+    const OSL: usize = 4;
+    const BAD_TO: usize = 5;
+    const K: usize = 6;
+    const K2: usize = 7;
+
+    static LAYER_LAYERS: [Layer<1, 8>; 4] = [
+        [[
+            Action::Momentary(1),
+            Action::Momentary(1),
+            Action::Toggle(2),
+            Action::To(3),
+            Action::OneShot(2),
+            Action::To(9),
+            Action::Key(Keycode::A),
+            Action::Key(Keycode::C),
+        ]],
+        [[
+            Action::Trans,
+            Action::Trans,
+            Action::Trans,
+            Action::Trans,
+            Action::Trans,
+            Action::Trans,
+            Action::Key(Keycode::B),
+            Action::Trans,
+        ]],
+        [[
+            Action::Trans,
+            Action::Trans,
+            Action::Trans,
+            Action::Trans,
+            Action::Trans,
+            Action::Trans,
+            Action::Key(Keycode::X),
+            Action::No,
+        ]],
+        [[
+            Action::Trans,
+            Action::Trans,
+            Action::Trans,
+            Action::To(0),
+            Action::Trans,
+            Action::Default(1),
+            Action::Key(Keycode::D),
+            Action::Trans,
+        ]],
+    ];
+
+    struct LayerCase {
+        name: &'static str,
+        // (ms, col, pressed) on the only row
+        events: &'static [(u32, usize, bool)],
+        // Every distinct key set the keymap produces, in order
+        keys: &'static [&'static [Keycode]],
+    }
+
+    const LAYER_CASES: &[LayerCase] = &[
+        LayerCase {
+            name: "momentary layer, transparent keys fall through",
+            events: &[
+                (0, MO, true),
+                (10, K2, true),
+                (20, K2, false),
+                (30, K, true),
+                (40, K, false),
+                (50, MO, false),
+                (60, K, true),
+                (70, K, false),
+            ],
+            keys: &[&[Keycode::C], &[], &[Keycode::B], &[], &[Keycode::A], &[]],
+        },
+        LayerCase {
+            name: "layer stays while another MO key for it is held",
+            events: &[
+                (0, MO, true),
+                (10, MO2, true),
+                (20, MO, false),
+                (30, K, true),
+                (40, K, false),
+                (50, MO2, false),
+                (60, K, true),
+                (70, K, false),
+            ],
+            keys: &[&[Keycode::B], &[], &[Keycode::A], &[]],
+        },
+        LayerCase {
+            name: "release undoes the press even after the layer changed",
+            events: &[
+                (0, MO, true),
+                (10, K, true),
+                (20, MO, false),
+                (30, K, false),
+            ],
+            keys: &[&[Keycode::B], &[]],
+        },
+        LayerCase {
+            name: "toggle, and No hides the layers below",
+            events: &[
+                (0, TG, true),
+                (10, TG, false),
+                (20, K2, true),
+                (30, K2, false),
+                (40, K, true),
+                (50, K, false),
+                (60, TG, true),
+                (70, TG, false),
+                (80, K, true),
+                (90, K, false),
+                (100, K2, true),
+                (110, K2, false),
+            ],
+            keys: &[&[Keycode::X], &[], &[Keycode::A], &[], &[Keycode::C], &[]],
+        },
+        LayerCase {
+            name: "to replaces every other layer",
+            events: &[
+                (0, TG, true),
+                (10, TG, false),
+                (20, TO, true),
+                (30, TO, false),
+                (40, K2, true),
+                (50, K2, false),
+                (60, K, true),
+                (70, K, false),
+                (80, TO, true),
+                (90, TO, false),
+                (100, K, true),
+                (110, K, false),
+            ],
+            keys: &[&[Keycode::C], &[], &[Keycode::D], &[], &[Keycode::A], &[]],
+        },
+        LayerCase {
+            name: "to an out-of-range layer is ignored",
+            events: &[
+                (0, TG, true),
+                (10, TG, false),
+                (20, BAD_TO, true),
+                (30, BAD_TO, false),
+                (40, K, true),
+                (50, K, false),
+            ],
+            keys: &[&[Keycode::X], &[]],
+        },
+        LayerCase {
+            name: "default layer outlasts to",
+            events: &[
+                (0, TO, true),
+                (10, TO, false),
+                // DF(1) on layer 3
+                (20, BAD_TO, true),
+                (30, BAD_TO, false),
+                // TO(0) on layer 3
+                (40, TO, true),
+                (50, TO, false),
+                (60, K, true),
+                (70, K, false),
+            ],
+            keys: &[&[Keycode::B], &[]],
+        },
+        LayerCase {
+            name: "one-shot layer clears after the next key",
+            events: &[
+                (0, OSL, true),
+                (10, OSL, false),
+                (20, K, true),
+                (30, K, false),
+                (40, K, true),
+                (50, K, false),
+            ],
+            keys: &[&[Keycode::X], &[], &[Keycode::A], &[]],
+        },
+    ];
+
+    #[test]
+    fn layer_actions() {
+        for case in LAYER_CASES {
+            let keymap = Keymap::new(&LAYER_LAYERS, &[], &[], PLAIN);
+            let keys: Vec<Vec<Keycode>> = run_timed(keymap, case.events)
+                .into_iter()
+                .map(|(_, keys)| keys)
+                .collect();
+            assert_eq!(keys, case.keys, "{}", case.name);
+        }
+    }
+
+    const CA: usize = 0;
+    const CB: usize = 1;
+    const CC: usize = 2;
+    const CMO: usize = 3;
+
+    static COMBO_LAYERS: [Layer<1, 4>; 2] = [
+        [[
+            Action::Key(Keycode::A),
+            Action::Key(Keycode::B),
+            Action::Key(Keycode::C),
+            Action::Momentary(1),
+        ]],
+        [[
+            Action::Trans,
+            Action::Trans,
+            Action::Key(Keycode::X),
+            Action::Trans,
+        ]],
+    ];
+
+    static COMBOS: [Combo; 3] = [
+        Combo {
+            keys: &[(0, CA), (0, CB)],
+            action: Action::Key(Keycode::D),
+            timeout_ms: 50,
+            release: Release::AllKeys,
+        },
+        Combo {
+            keys: &[(0, CA), (0, CB), (0, CC)],
+            action: Action::Key(Keycode::E),
+            timeout_ms: 50,
+            release: Release::AllKeys,
+        },
+        Combo {
+            keys: &[(0, CMO), (0, CB)],
+            action: Action::Key(Keycode::F),
+            timeout_ms: 50,
+            release: Release::AnyKey,
+        },
+    ];
+
+    fn run_combos(trace: &[(u32, usize, bool)]) -> Vec<(u32, Vec<Keycode>)> {
+        run_timed(Keymap::new(&COMBO_LAYERS, &COMBOS, &[], PLAIN), trace)
+    }
+
+    #[test]
+    fn combo_fires_within_its_timeout() {
+        let trace = [
+            (0, CMO, true),
+            (20, CB, true),
+            (100, CB, false),
+            (110, CMO, false),
+        ];
+        assert_eq!(run_combos(&trace), [(20, vec![Keycode::F]), (100, vec![])]);
+    }
+
+    #[test]
+    fn slow_chord_falls_through_to_the_keys() {
+        let trace = [
+            (0, CA, true),
+            (100, CB, true),
+            (200, CA, false),
+            (250, CB, false),
+        ];
+        assert_eq!(
+            run_combos(&trace),
+            [
+                (50, vec![Keycode::A]),
+                (150, vec![Keycode::A, Keycode::B]),
+                (200, vec![Keycode::B]),
+                (250, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn larger_combo_wins() {
+        let trace = [
+            (0, CA, true),
+            (10, CB, true),
+            (20, CC, true),
+            (100, CA, false),
+            (110, CB, false),
+            (120, CC, false),
+        ];
+        assert_eq!(run_combos(&trace), [(20, vec![Keycode::E]), (120, vec![])]);
+        // The smaller one fires once the larger one can no longer complete
+        let trace = [
+            (0, CA, true),
+            (10, CB, true),
+            (100, CA, false),
+            (110, CB, false),
+        ];
+        assert_eq!(run_combos(&trace), [(50, vec![Keycode::D]), (110, vec![])]);
+    }
+
+    #[test]
+    fn all_keys_combo_lasts_until_every_key_is_released() {
+        let trace = [
+            (0, CB, true),
+            (10, CA, true),
+            (100, CA, false),
+            (200, CB, false),
+        ];
+        assert_eq!(run_combos(&trace), [(50, vec![Keycode::D]), (200, vec![])]);
+    }
+
+    #[test]
+    fn any_key_combo_ends_with_the_first_release() {
+        let trace = [
+            (0, CB, true),
+            (10, CMO, true),
+            (100, CMO, false),
+            (200, CB, false),
+        ];
+        assert_eq!(run_combos(&trace), [(10, vec![Keycode::F]), (100, vec![])]);
+    }
+
+    #[test]
+    fn any_key_combo_leaves_a_new_press_of_its_anchor_alone() {
+        // The combo is held at MO's position, which is pressed again as a
+        // plain MO(1) while B is still down from the combo.
+        let trace = [
+            (0, CMO, true),
+            (10, CB, true),
+            (100, CMO, false),
+            (150, CMO, true),
+            (300, CB, false),
+            (350, CC, true),
+            (450, CC, false),
+            (500, CMO, false),
+        ];
+        assert_eq!(
+            run_combos(&trace),
+            [
+                (10, vec![Keycode::F]),
+                (100, vec![]),
+                (400, vec![Keycode::X]),
+                (450, vec![]),
+            ]
+        );
+    }
+
+    const TD: usize = 0;
+    const TD_OTHER: usize = 1;
+
+    static DANCE_LAYERS: [Layer<1, 2>; 1] = [[[Action::TapDance(0), Action::Key(Keycode::X)]]];
+
+    static DANCES: [TapDance; 1] = [TapDance {
+        steps: &[
+            Step {
+                tap: Action::Key(Keycode::A),
+                hold: Action::Key(Keycode::LeftCtrl),
+            },
+            Step {
+                tap: Action::Key(Keycode::B),
+                hold: Action::No,
+            },
+        ],
+        timeout_ms: 200,
+    }];
+
+    fn run_dances(trace: &[(u32, usize, bool)]) -> Vec<(u32, Vec<Keycode>)> {
+        run_timed(Keymap::new(&DANCE_LAYERS, &[], &DANCES, PLAIN), trace)
+    }
+
+    #[test]
+    fn tap_dance_taps() {
+        // One tap resolves once the timeout passes
+        let trace = [(0, TD, true), (10, TD, false)];
+        assert_eq!(run_dances(&trace), [(210, vec![Keycode::A]), (210, vec![])]);
+        // The last step resolves on its release
+        let trace = [
+            (0, TD, true),
+            (10, TD, false),
+            (20, TD, true),
+            (30, TD, false),
+        ];
+        assert_eq!(run_dances(&trace), [(30, vec![Keycode::B]), (30, vec![])]);
+    }
+
+    #[test]
+    fn tap_dance_holds() {
+        let trace = [(0, TD, true), (300, TD, false)];
+        assert_eq!(
+            run_dances(&trace),
+            [(200, vec![Keycode::LeftCtrl]), (300, vec![])]
+        );
+        let trace = [
+            (0, TD, true),
+            (10, TD, false),
+            (20, TD, true),
+            (300, TD, false),
+        ];
+        assert_eq!(run_dances(&trace), [(220, vec![Keycode::B]), (300, vec![])]);
+    }
+
+    #[test]
+    fn other_key_finishes_the_dance_first() {
+        let trace = [
+            (0, TD, true),
+            (10, TD, false),
+            (20, TD_OTHER, true),
+            (30, TD_OTHER, false),
+        ];
+        assert_eq!(
+            run_dances(&trace),
+            [
+                (20, vec![Keycode::A]),
+                (20, vec![]),
+                (20, vec![Keycode::X]),
+                (30, vec![]),
+            ]
+        );
+        // Interrupted while held, the dance holds under the other key
+        let trace = [
+            (0, TD, true),
+            (20, TD_OTHER, true),
+            (30, TD_OTHER, false),
+            (40, TD, false),
+        ];
+        assert_eq!(
+            run_dances(&trace),
+            [
+                (20, vec![Keycode::LeftCtrl]),
+                (20, vec![Keycode::X, Keycode::LeftCtrl]),
+                (30, vec![Keycode::LeftCtrl]),
+                (40, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn other_key_release_keeps_the_dance() {
+        // A double tap started while rolling off another key
+        let trace = [
+            (0, TD_OTHER, true),
+            (10, TD, true),
+            (20, TD_OTHER, false),
+            (30, TD, false),
+            (40, TD, true),
+            (50, TD, false),
+        ];
+        assert_eq!(
+            run_dances(&trace),
+            [
+                (0, vec![Keycode::X]),
+                (20, vec![]),
+                (50, vec![Keycode::B]),
+                (50, vec![]),
+            ]
+        );
+    }
+}
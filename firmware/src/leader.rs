@@ -0,0 +1,372 @@
+use crate::keycode::{KeySet, Keycode};
+use crate::macros::Macro;
+use crate::recording::Recording;
+
+// Trie nodes for all sequences together, root included
+const MAX_NODES: usize = 64;
+pub const MAX_SEQUENCE: usize = 8;
+
+// Leader, then `keys` one after another, plays `steps`.
+#[derive(Debug, Clone, Copy)]
+pub struct Sequence {
+    pub keys: &'static [Keycode],
+    pub steps: Macro,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fallback {
+    // Unmatched keys are dropped
+    Drop,
+    // Unmatched keys are typed as if there had been no leader
+    Replay,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    // Ends the sequence when no key follows for this long
+    pub key_timeout_ms: u16,
+    // Ends the sequence this long after the leader, however fast the keys
+    pub sequence_timeout_ms: u16,
+    pub fallback: Fallback,
+}
+
+// Returned at most once per sequence and handed straight to the player
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, Copy)]
+pub enum Outcome {
+    Matched(Macro),
+    // Taps of the keys typed after the leader, per `Fallback::Replay`
+    Unmatched(Recording),
+}
+
+// Children are a linked list through `sibling`.
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    key: Keycode,
+    child: Option<u8>,
+    sibling: Option<u8>,
+    steps: Option<Macro>,
+}
+
+// All sequences, built at compile time: put Trie::new() in a static, and
+// a sequence over MAX_SEQUENCE keys or MAX_NODES nodes in total fails the
+// build.
+pub struct Trie {
+    nodes: [Node; MAX_NODES],
+    len: usize,
+}
+
+impl Trie {
+    pub const fn new(sequences: &[Sequence]) -> Self {
+        let root = Node {
+            key: Keycode::No,
+            child: None,
+            sibling: None,
+            steps: None,
+        };
+        let mut trie = Trie {
+            nodes: [root; MAX_NODES],
+            len: 1,
+        };
+        // No for loops or &mut self in a const fn
+        let mut seq = 0;
+        while seq < sequences.len() {
+            let sequence = &sequences[seq];
+            assert!(
+                sequence.keys.len() <= MAX_SEQUENCE,
+                "leader sequence too long"
+            );
+            let mut node = 0;
+            let mut pos = 0;
+            while pos < sequence.keys.len() {
+                let key = sequence.keys[pos];
+                node = match trie.child(node, key) {
+                    Some(child) => child,
+                    None => {
+                        assert!(trie.len < MAX_NODES, "too many leader sequence keys");
+                        let child = trie.len;
+                        trie.nodes[child] = Node {
+                            key,
+                            child: None,
+                            sibling: trie.nodes[node].child,
+                            steps: None,
+                        };
+                        trie.nodes[node].child = Some(child as u8);
+                        trie.len += 1;
+                        child
+                    }
+                };
+                pos += 1;
+            }
+            trie.nodes[node].steps = Some(sequence.steps);
+            seq += 1;
+        }
+        trie
+    }
+
+    const fn child(&self, node: usize, key: Keycode) -> Option<usize> {
+        let mut next = self.nodes[node].child;
+        while let Some(idx) = next {
+            let child = &self.nodes[idx as usize];
+            if child.key as u8 == key as u8 {
+                return Some(idx as usize);
+            }
+            next = child.sibling;
+        }
+        None
+    }
+}
+
+// Once the leader is pressed, the keys the keymap produces are taken out
+// of the report and walked down the trie. A sequence ends as soon as it
+// can only match one way, cannot match at all, or on timeout; keys still
+// held at that point stay hidden until released.
+pub struct Leader {
+    trie: &'static Trie,
+    config: Config,
+    active: bool,
+    node: usize,
+    started: u32,
+    last_key: u32,
+    typed: [Keycode; MAX_SEQUENCE],
+    len: usize,
+    last: KeySet,
+    swallowed: KeySet,
+}
+
+impl Leader {
+    pub fn new(trie: &'static Trie, config: Config) -> Self {
+        Leader {
+            trie,
+            config,
+            active: false,
+            node: 0,
+            started: 0,
+            last_key: 0,
+            typed: [Keycode::No; MAX_SEQUENCE],
+            len: 0,
+            last: KeySet::new(),
+            swallowed: KeySet::new(),
+        }
+    }
+
+    // `keys` are held already and pass through untouched.
+    pub fn start(&mut self, keys: &KeySet, now: u32) {
+        self.active = true;
+        self.node = 0;
+        self.started = now;
+        self.last_key = now;
+        self.len = 0;
+        self.last = *keys;
+    }
+
+    // Feeds the keymap's keys; returns what to play once a sequence ends.
+    pub fn update(&mut self, keys: &KeySet, now: u32) -> Option<Outcome> {
+        self.swallowed = self.swallowed.intersection(keys);
+        if !self.active {
+            return None;
+        }
+        let last = self.last;
+        self.last = *keys;
+        let pressed = keys
+            .iter()
+            .filter(|key| !last.contains(*key) && !key.is_modifier());
+        for key in pressed {
+            self.swallowed.insert(key);
+            self.last_key = now;
+            if self.len < MAX_SEQUENCE {
+                self.typed[self.len] = key;
+                self.len += 1;
+            }
+            match self.trie.child(self.node, key) {
+                Some(child) => self.node = child,
+                None => return Some(self.finish(false)),
+            }
+            let node = &self.trie.nodes[self.node];
+            if node.child.is_none() && node.steps.is_some() {
+                return Some(self.finish(true));
+            }
+        }
+        let key_timeout = now.wrapping_sub(self.last_key) >= self.config.key_timeout_ms as u32;
+        let sequence_timeout =
+            now.wrapping_sub(self.started) >= self.config.sequence_timeout_ms as u32;
+        if key_timeout || sequence_timeout {
+            let matched = self.trie.nodes[self.node].steps.is_some();
+            return Some(self.finish(matched));
+        }
+        None
+    }
+
+    fn finish(&mut self, matched: bool) -> Outcome {
+        self.active = false;
+        if matched {
+            if let Some(steps) = self.trie.nodes[self.node].steps {
+                return Outcome::Matched(steps);
+            }
+        }
+        let mut taps = Recording::new();
+        if self.config.fallback == Fallback::Replay {
+            for &key in &self.typed[..self.len] {
+                taps.push(key, true);
+                taps.push(key, false);
+            }
+        }
+        Outcome::Unmatched(taps)
+    }
+
+    // The keys to report: everything but what the leader took.
+    pub fn mask(&self, keys: KeySet) -> KeySet {
+        keys.difference(&self.swallowed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::macros::Step;
+
+    const GIT: Macro = &[Step::Text("git")];
+    const COMMIT: Macro = &[Step::Text("git commit")];
+    const BRANCH: Macro = &[Step::Text("git branch")];
+
+    static TRIE: Trie = Trie::new(&[
+        Sequence {
+            keys: &[Keycode::G],
+            steps: GIT,
+        },
+        Sequence {
+            keys: &[Keycode::G, Keycode::C],
+            steps: COMMIT,
+        },
+        Sequence {
+            keys: &[Keycode::B, Keycode::B],
+            steps: BRANCH,
+        },
+    ]);
+
+    const CONFIG: Config = Config {
+        key_timeout_ms: 300,
+        sequence_timeout_ms: 1000,
+        fallback: Fallback::Replay,
+    };
+
+    fn keys(keys: &[Keycode]) -> KeySet {
+        let mut set = KeySet::new();
+        for &key in keys {
+            set.insert(key);
+        }
+        set
+    }
+
+    // Presses and releases each key 10 ms apart from `from`, stopping at
+    // the first outcome.
+    fn type_keys(leader: &mut Leader, typed: &[Keycode], from: u32) -> Option<Outcome> {
+        let mut now = from;
+        for &key in typed {
+            for held in &[keys(&[key]), KeySet::new()] {
+                if let Some(outcome) = leader.update(held, now) {
+                    return Some(outcome);
+                }
+                now += 10;
+            }
+        }
+        None
+    }
+
+    fn matched(outcome: Option<Outcome>) -> Macro {
+        match outcome {
+            Some(Outcome::Matched(steps)) => steps,
+            other => panic!("expected a match, got {:?}", other),
+        }
+    }
+
+    fn unmatched(outcome: Option<Outcome>) -> Vec<(Keycode, bool)> {
+        match outcome {
+            Some(Outcome::Unmatched(taps)) => (0..).map_while(|idx| taps.get(idx)).collect(),
+            other => panic!("expected no match, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sequence_ends_on_its_last_key() {
+        let mut leader = Leader::new(&TRIE, CONFIG);
+        leader.start(&KeySet::new(), 0);
+        let outcome = type_keys(&mut leader, &[Keycode::B, Keycode::B], 0);
+        assert_eq!(matched(outcome), BRANCH);
+        leader.start(&KeySet::new(), 100);
+        let outcome = type_keys(&mut leader, &[Keycode::G, Keycode::C], 100);
+        assert_eq!(matched(outcome), COMMIT);
+    }
+
+    #[test]
+    fn prefix_matches_on_key_timeout() {
+        let mut leader = Leader::new(&TRIE, CONFIG);
+        leader.start(&KeySet::new(), 0);
+        assert!(type_keys(&mut leader, &[Keycode::G], 10).is_none());
+        assert!(leader.update(&KeySet::new(), 309).is_none());
+        assert_eq!(matched(leader.update(&KeySet::new(), 310)), GIT);
+        assert!(leader.update(&KeySet::new(), 1000).is_none());
+    }
+
+    #[test]
+    fn prefix_matches_on_sequence_timeout() {
+        let config = Config {
+            sequence_timeout_ms: 200,
+            ..CONFIG
+        };
+        let mut leader = Leader::new(&TRIE, config);
+        leader.start(&KeySet::new(), 0);
+        assert!(type_keys(&mut leader, &[Keycode::G], 150).is_none());
+        assert!(leader.update(&KeySet::new(), 199).is_none());
+        assert_eq!(matched(leader.update(&KeySet::new(), 200)), GIT);
+    }
+
+    #[test]
+    fn unmatched_keys_are_replayed() {
+        let mut leader = Leader::new(&TRIE, CONFIG);
+        leader.start(&KeySet::new(), 0);
+        assert_eq!(
+            unmatched(type_keys(&mut leader, &[Keycode::B, Keycode::X], 0)),
+            [
+                (Keycode::B, true),
+                (Keycode::B, false),
+                (Keycode::X, true),
+                (Keycode::X, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn unmatched_keys_are_dropped() {
+        let config = Config {
+            fallback: Fallback::Drop,
+            ..CONFIG
+        };
+        let mut leader = Leader::new(&TRIE, config);
+        leader.start(&KeySet::new(), 0);
+        assert_eq!(
+            unmatched(type_keys(&mut leader, &[Keycode::B, Keycode::X], 0)),
+            []
+        );
+        leader.start(&KeySet::new(), 100);
+        assert_eq!(unmatched(leader.update(&KeySet::new(), 400)), []);
+    }
+
+    #[test]
+    fn sequence_keys_stay_hidden_until_released() {
+        let mut leader = Leader::new(&TRIE, CONFIG);
+        let held = keys(&[Keycode::A]);
+        leader.start(&held, 0);
+        let typing = keys(&[Keycode::A, Keycode::B, Keycode::LeftShift]);
+        assert!(leader.update(&typing, 10).is_none());
+        assert_eq!(leader.mask(typing), keys(&[Keycode::A, Keycode::LeftShift]));
+        assert!(leader.update(&held, 20).is_none());
+        let typing = keys(&[Keycode::A, Keycode::B]);
+        assert_eq!(matched(leader.update(&typing, 30)), BRANCH);
+        assert_eq!(leader.mask(typing), held);
+        assert!(leader.update(&held, 40).is_none());
+        assert!(leader.update(&typing, 50).is_none());
+        assert_eq!(leader.mask(typing), typing);
+    }
+}
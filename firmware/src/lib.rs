@@ -0,0 +1,37 @@
+// The parts of the firmware that do not touch the MCU. They build for the
+// host as well, so their tests run there (see Cargo.toml).
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod access;
+pub mod capsword;
+pub mod combo;
+pub mod crc;
+pub mod cursor;
+pub mod dance;
+pub mod debounce;
+pub mod descr;
+pub mod dynmacro;
+pub mod event;
+pub mod ghost;
+pub mod keycode;
+pub mod keylock;
+pub mod keymap;
+pub mod leader;
+pub mod macros;
+pub mod matrix_size;
+pub mod recording;
+pub mod report;
+pub mod settings;
+pub mod storage;
+pub mod unicode;
+pub mod update;
+pub mod usb;
+
+// build.rs's keymap compiler, tested here since build scripts have no tests
+#[cfg(test)]
+#[path = "../build/keymap_compiler.rs"]
+mod keymap_compiler;
+
+// Pressed keys of a scan, indexed [row][col]
+pub type KeyState<const ROWS: usize, const COLS: usize> = [[bool; COLS]; ROWS];
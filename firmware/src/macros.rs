@@ -0,0 +1,336 @@
+use crate::keycode::{KeySet, Keycode};
+use crate::recording::Recording;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+    Press(Keycode),
+    Release(Keycode),
+    // Press, then release in the next report
+    Tap(Keycode),
+    // Typed as on a US layout; characters without a key are skipped
+    Text(&'static str),
+    Delay { ms: u16 },
+}
+
+pub type Macro = &'static [Step];
+
+// There is a single Player, so the copied recording costs no more RAM than
+// a pointer into the recorder would save.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, Copy)]
+enum Source {
+    Steps(Macro),
+    Recorded(Recording),
+}
+
+// Plays back one macro at a time. Each call to step() makes at most one
+// change to the macro's keys, so with one report per change the host sees
+// every press and release separately. While a macro plays, the keys the
+// user is holding (modifiers included) are lifted from the report and put
+// back once it ends, so a held Shift does not leak into the macro.
+pub struct Player {
+    macros: &'static [Macro],
+    source: Source,
+    pos: usize,
+    // Progress within the current step: Tap and Text alternate between a
+    // press (even) and a release (odd); Text moves one character per pair.
+    sub: usize,
+    text_shift: bool,
+    delay_from: Option<u32>,
+    keys: KeySet,
+    playing: bool,
+}
+
+impl Player {
+    pub fn new(macros: &'static [Macro]) -> Self {
+        Player {
+            macros,
+            source: Source::Steps(&[]),
+            pos: 0,
+            sub: 0,
+            text_shift: false,
+            delay_from: None,
+            keys: KeySet::new(),
+            playing: false,
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    // Unknown indices are ignored.
+    pub fn start(&mut self, idx: u8) {
+        if let Some(steps) = self.macros.get(idx as usize) {
+            self.start_steps(steps);
+        }
+    }
+
+    pub fn start_steps(&mut self, steps: Macro) {
+        self.play(Source::Steps(steps));
+    }
+
+    pub fn start_recording(&mut self, recording: &Recording) {
+        self.play(Source::Recorded(*recording));
+    }
+
+    fn play(&mut self, source: Source) {
+        self.source = source;
+        self.pos = 0;
+        self.sub = 0;
+        self.text_shift = false;
+        self.delay_from = None;
+        self.keys.clear();
+        self.playing = true;
+    }
+
+    fn next(&mut self) {
+        self.pos += 1;
+        self.sub = 0;
+    }
+
+    // Returns false while waiting out a delay or when idle.
+    pub fn step(&mut self, now: u32) -> bool {
+        if !self.playing {
+            return false;
+        }
+        let step = match &self.source {
+            Source::Steps(steps) => steps.get(self.pos).copied(),
+            Source::Recorded(recording) => recording.get(self.pos).map(|(key, pressed)| {
+                if pressed {
+                    Step::Press(key)
+                } else {
+                    Step::Release(key)
+                }
+            }),
+        };
+        let step = match step {
+            Some(step) => step,
+            None => {
+                self.keys.clear();
+                self.playing = false;
+                return true;
+            }
+        };
+        match step {
+            Step::Press(key) => {
+                self.keys.insert(key);
+                self.next();
+            }
+            Step::Release(key) => {
+                self.keys.remove(key);
+                self.next();
+            }
+            Step::Tap(key) => {
+                if self.sub == 0 {
+                    self.keys.insert(key);
+                    self.sub = 1;
+                } else {
+                    self.keys.remove(key);
+                    self.next();
+                }
+            }
+            Step::Text(text) => self.step_text(text.as_bytes()),
+            Step::Delay { ms } => {
+                let from = *self.delay_from.get_or_insert(now);
+                if now.wrapping_sub(from) < ms as u32 {
+                    return false;
+                }
+                self.delay_from = None;
+                self.next();
+            }
+        }
+        true
+    }
+
+    fn step_text(&mut self, text: &[u8]) {
+        let (key, shift) = match text.get(self.sub / 2) {
+            Some(&c) => match Keycode::from_ascii(c) {
+                Some(key) => key,
+                None => {
+                    self.sub += 2;
+                    return;
+                }
+            },
+            None => {
+                self.next();
+                return;
+            }
+        };
+        if self.sub % 2 == 1 {
+            self.keys.remove(key);
+            if self.text_shift {
+                self.keys.remove(Keycode::LeftShift);
+            }
+        } else {
+            // Leave a Shift pressed by an earlier step alone
+            self.text_shift = shift && !self.keys.contains(Keycode::LeftShift);
+            if self.text_shift {
+                self.keys.insert(Keycode::LeftShift);
+            }
+            self.keys.insert(key);
+        }
+        self.sub += 1;
+    }
+
+    // The keys to report: the macro's own while it plays, the user's
+    // otherwise.
+    pub fn keys(&self, user: KeySet) -> KeySet {
+        if self.playing {
+            self.keys
+        } else {
+            user
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_set(keys: &[Keycode]) -> KeySet {
+        let mut set = KeySet::new();
+        for &key in keys {
+            set.insert(key);
+        }
+        set
+    }
+
+    // Steps the player once per ms, as the main loop does, while the user
+    // holds `user`. Returns every distinct key set reported after the
+    // start, with the ms it appeared in.
+    fn play(player: &mut Player, user: &[Keycode]) -> Vec<(u32, Vec<Keycode>)> {
+        let user = key_set(user);
+        let mut last = user;
+        let mut seen = Vec::new();
+        for now in 0..1000 {
+            while player.step(now) {
+                let keys = player.keys(user);
+                if keys != last {
+                    seen.push((now, keys.iter().collect()));
+                    last = keys;
+                }
+            }
+        }
+        seen
+    }
+
+    fn play_steps(steps: Macro, user: &[Keycode]) -> Vec<(u32, Vec<Keycode>)> {
+        let mut player = Player::new(&[]);
+        player.start_steps(steps);
+        play(&mut player, user)
+    }
+
+    #[test]
+    fn press_release_and_tap() {
+        use Keycode::*;
+        let steps = &[Step::Press(LeftCtrl), Step::Tap(C), Step::Release(LeftCtrl)];
+        assert_eq!(
+            play_steps(steps, &[]),
+            [
+                (0, vec![LeftCtrl]),
+                (0, vec![C, LeftCtrl]),
+                (0, vec![LeftCtrl]),
+                (0, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn delay_holds_the_next_step_back() {
+        use Keycode::*;
+        let steps = &[Step::Tap(A), Step::Delay { ms: 50 }, Step::Tap(B)];
+        assert_eq!(
+            play_steps(steps, &[]),
+            [(0, vec![A]), (0, vec![]), (50, vec![B]), (50, vec![])]
+        );
+    }
+
+    #[test]
+    fn text_shifts_where_needed() {
+        use Keycode::*;
+        assert_eq!(
+            play_steps(&[Step::Text("aB!")], &[]),
+            [
+                (0, vec![A]),
+                (0, vec![]),
+                (0, vec![B, LeftShift]),
+                (0, vec![]),
+                (0, vec![N1, LeftShift]),
+                (0, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn text_leaves_an_earlier_shift_alone() {
+        use Keycode::*;
+        let steps = &[
+            Step::Press(LeftShift),
+            Step::Text("A1"),
+            Step::Release(LeftShift),
+        ];
+        assert_eq!(
+            play_steps(steps, &[]),
+            [
+                (0, vec![LeftShift]),
+                (0, vec![A, LeftShift]),
+                (0, vec![LeftShift]),
+                (0, vec![N1, LeftShift]),
+                (0, vec![LeftShift]),
+                (0, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn text_skips_characters_without_a_key() {
+        use Keycode::*;
+        assert_eq!(
+            play_steps(&[Step::Text("a\u{e9}\r\u{3042}b")], &[]),
+            [(0, vec![A]), (0, vec![]), (0, vec![B]), (0, vec![])]
+        );
+    }
+
+    #[test]
+    fn held_keys_are_lifted_while_playing() {
+        use Keycode::*;
+        let steps = &[Step::Tap(A), Step::Delay { ms: 20 }];
+        assert_eq!(
+            play_steps(steps, &[LeftShift, X]),
+            [(0, vec![A]), (0, vec![]), (20, vec![X, LeftShift])]
+        );
+    }
+
+    #[test]
+    fn recordings_play_their_events() {
+        use Keycode::*;
+        let mut recording = Recording::new();
+        recording.push(LeftShift, true);
+        recording.push(A, true);
+        recording.push(LeftShift, false);
+        recording.push(A, false);
+        let mut player = Player::new(&[]);
+        player.start_recording(&recording);
+        assert_eq!(
+            play(&mut player, &[]),
+            [
+                (0, vec![LeftShift]),
+                (0, vec![A, LeftShift]),
+                (0, vec![A]),
+                (0, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn start_picks_a_macro_by_index() {
+        static MACROS: [Macro; 2] = [&[Step::Tap(Keycode::A)], &[Step::Tap(Keycode::B)]];
+        let mut player = Player::new(&MACROS);
+        player.start(2);
+        assert!(!player.is_playing());
+        player.start(1);
+        assert_eq!(play(&mut player, &[]), [(0, vec![Keycode::B]), (0, vec![])]);
+    }
+}
@@ -8,17 +8,40 @@ use core::cmp;
 use cortex_m_rt::entry;
 use stm32f1::stm32f103;
 use stm32f103::USB as USBRegs;
-use vcell::VolatileCell;
 
 #[allow(unused_imports)]
 use cortex_m_semihosting::hprintln;
 
-mod cursor;
-mod descr;
+mod board;
+mod boot;
+mod dfu;
+mod flash;
 mod gpio;
+mod matrix;
 mod pma;
+mod timer;
 
-use cursor::{ReadCursor, WriteCursor};
+mod config {
+    include!(concat!(env!("OUT_DIR"), "/config.rs"));
+}
+
+// Layers, tap dances, combos, macros and leader sequences from keymap.toml
+// (see build.rs)
+mod layout {
+    include!(concat!(env!("OUT_DIR"), "/layout.rs"));
+}
+
+use kb789_firmware::cursor::WriteCursor;
+use kb789_firmware::keycode;
+use kb789_firmware::keymap::{self, Action};
+use kb789_firmware::usb::{
+    self, DeviceRequest, Direction, EPAddr, EPStat, EPType, Endpoints, Recipient, RequestStatus,
+    Type,
+};
+use kb789_firmware::{
+    access, combo, dance, debounce, descr, dynmacro, event, ghost, keylock, leader, macros,
+    matrix_size, report, settings, unicode, update,
+};
 
 static DEVICE_DESCR: descr::DeviceDescriptor = descr::DeviceDescriptor {
     bLength: core::mem::size_of::<descr::DeviceDescriptor>() as u8,
@@ -37,12 +60,17 @@ static DEVICE_DESCR: descr::DeviceDescriptor = descr::DeviceDescriptor {
     bNumConfigurations: 1,
 };
 
-#[repr(packed)]
+const KBD_INTERFACE: u8 = 0;
+const DFU_INTERFACE: u8 = 1;
+
+#[repr(C, packed)]
 pub struct CompositeConfigDescriptor {
     pub config: descr::ConfigDescriptor,
     pub kbd_interf: descr::InterfaceDescriptor,
     pub hid_func: descr::HidFunction,
     pub hid_endpoint: descr::EndpointDescriptor,
+    pub dfu_interf: descr::InterfaceDescriptor,
+    pub dfu_func: descr::DfuFunctionalDescriptor,
 }
 
 static CONFIG_DESCR: CompositeConfigDescriptor = CompositeConfigDescriptor {
@@ -50,7 +78,7 @@ static CONFIG_DESCR: CompositeConfigDescriptor = CompositeConfigDescriptor {
         bLength: core::mem::size_of::<descr::ConfigDescriptor>() as u8,
         bDescriptorType: 2,
         wTotalLength: core::mem::size_of::<CompositeConfigDescriptor>() as u16,
-        bNumInterfaces: 1,
+        bNumInterfaces: 2,
         bConfigurationValue: 1,
         iConfiguration: 0,
         bmAttributes: 0xC0,
@@ -59,7 +87,7 @@ static CONFIG_DESCR: CompositeConfigDescriptor = CompositeConfigDescriptor {
     kbd_interf: descr::InterfaceDescriptor {
         bLength: core::mem::size_of::<descr::InterfaceDescriptor>() as u8,
         bDescriptorType: 4,
-        bInterfaceNumber: 0,
+        bInterfaceNumber: KBD_INTERFACE,
         bAlternateSetting: 0,
         bNumEndpoints: 1,
         bInterfaceClass: 3, // = USB_CLASS_HID
@@ -77,7 +105,7 @@ static CONFIG_DESCR: CompositeConfigDescriptor = CompositeConfigDescriptor {
         },
         hid_report: descr::HidReport {
             bReportDescriptorType: 0x22,
-            wDescriptorLength: 63,
+            wDescriptorLength: HID_REPORT_DESCR.len() as u16,
         },
     },
     hid_endpoint: descr::EndpointDescriptor {
@@ -86,17 +114,66 @@ static CONFIG_DESCR: CompositeConfigDescriptor = CompositeConfigDescriptor {
         bEndpointAddress: 0x81,
         bmAttributes: 0x03,
         wMaxPacketSize: 8,
-        bInterval: 0x0a,
+        bInterval: config::POLL_INTERVAL_MS,
+    },
+    dfu_interf: descr::InterfaceDescriptor {
+        bLength: core::mem::size_of::<descr::InterfaceDescriptor>() as u8,
+        bDescriptorType: 4,
+        bInterfaceNumber: DFU_INTERFACE,
+        bAlternateSetting: 0,
+        bNumEndpoints: 0,
+        bInterfaceClass: 0xFE, // = Application Specific
+        bInterfaceSubClass: 1, // = DFU
+        bInterfaceProtocol: 1, // = Runtime
+        iInterface: 0,
+    },
+    dfu_func: descr::DfuFunctionalDescriptor {
+        bLength: core::mem::size_of::<descr::DfuFunctionalDescriptor>() as u8,
+        bDescriptorType: 0x21,
+        bmAttributes: 0x09, // bitWillDetach | bitCanDnload
+        wDetachTimeOut: 255,
+        wTransferSize: 1024,
+        bcdDFUVersion: 0x0110, // DFU 1.1, not the DfuSe extension (0x011A)
     },
 };
 
-static HID_REPORT_DESCR: &[u8] = &[
+// The key array covers usages 0x00-0xDD, which includes International1-9,
+// LANG1-9 and the extended keypad. Logical Maximum needs the 2-byte form
+// there, since 0xDD would read as negative in a 1-byte item.
+const HID_REPORT_DESCR: &[u8] = &[
     0x05, 0x01, 0x09, 0x06, 0xA1, 0x01, 0x05, 0x07, 0x19, 0xE0, 0x29, 0xE7, 0x15, 0x00, 0x25, 0x01,
-    0x75, 0x01, 0x95, 0x08, 0x81, 0x02, 0x95, 0x01, 0x75, 0x08, 0x81, 0x01, 0x95, 0x06, 0x75, 0x01,
+    0x75, 0x01, 0x95, 0x08, 0x81, 0x02, 0x95, 0x01, 0x75, 0x08, 0x81, 0x01, 0x95, 0x05, 0x75, 0x01,
     0x05, 0x08, 0x19, 0x01, 0x29, 0x05, 0x91, 0x02, 0x95, 0x01, 0x75, 0x03, 0x91, 0x01, 0x95, 0x06,
-    0x75, 0x08, 0x15, 0x00, 0x25, 0x65, 0x05, 0x07, 0x19, 0x00, 0x29, 0x65, 0x81, 0x00, 0xC0,
+    0x75, 0x08, 0x15, 0x00, 0x26, 0xDD, 0x00, 0x05, 0x07, 0x19, 0x00, 0x29, 0xDD, 0x81, 0x00, 0xC0,
 ];
 
+// Keep recorded macros across power cycles
+const PERSIST_MACROS: bool = true;
+
+const TAP_HOLD: keymap::TapHoldConfig = keymap::TapHoldConfig {
+    tapping_term_ms: 200,
+    permissive_hold: true,
+    hold_on_other_key_press: false,
+    retro_tapping: false,
+};
+
+// Accessibility filters, off unless the user needs them
+const STICKY_KEYS: access::StickyConfig = access::StickyConfig {
+    enabled: false,
+    lock_on_double_tap: true,
+};
+const SLOW_KEYS: access::SlowConfig = access::SlowConfig {
+    enabled: false,
+    hold_ms: 300,
+};
+const BOUNCE_KEYS: access::BounceConfig = access::BounceConfig {
+    enabled: false,
+    ignore_ms: 500,
+};
+
+// 500 ms, the HID 1.11 recommendation for keyboards
+const HID_DEFAULT_IDLE: u8 = 125;
+
 static STRINGS: &[&str] = &["KOBA789", "KB789 MK-C", "789"];
 
 fn setup_clock(rcc: &stm32f103::RCC, flash: &stm32f103::FLASH) {
@@ -135,157 +212,6 @@ fn setup_clock(rcc: &stm32f103::RCC, flash: &stm32f103::FLASH) {
     rcc.cfgr.write(|w| w.sw().pll());
 }
 
-#[derive(Debug, PartialEq)]
-enum Direction {
-    HostToDevice,
-    DeviceToHost,
-}
-
-#[derive(Debug, PartialEq)]
-#[allow(dead_code)]
-enum Type {
-    Standard,
-    Class,
-    Vendor,
-    Reserved,
-}
-
-#[derive(Debug, PartialEq)]
-#[allow(dead_code)]
-enum Recipient {
-    Device,
-    Interface,
-    Endpoint,
-    Other,
-    Reserved,
-}
-
-#[repr(packed)]
-#[derive(Debug)]
-pub struct BmRequestType(u8);
-impl BmRequestType {
-    #[inline]
-    fn bits(&self) -> u8 {
-        self.0
-    }
-
-    #[inline]
-    fn direction(&self) -> Direction {
-        if self.bits() & 0x80 == 0 {
-            Direction::HostToDevice
-        } else {
-            Direction::DeviceToHost
-        }
-    }
-
-    #[inline]
-    #[allow(dead_code)]
-    fn request_type(&self) -> Type {
-        match (self.bits() >> 5) & 0b11 {
-            0 => Type::Standard,
-            1 => Type::Class,
-            2 => Type::Vendor,
-            3 => Type::Reserved,
-            _ => unreachable!(),
-        }
-    }
-
-    #[inline]
-    #[allow(dead_code)]
-    fn recipient(&self) -> Recipient {
-        match self.bits() & 0b11111 {
-            0 => Recipient::Device,
-            1 => Recipient::Interface,
-            2 => Recipient::Endpoint,
-            3 => Recipient::Other,
-            _ => Recipient::Reserved,
-        }
-    }
-}
-
-#[repr(packed)]
-#[allow(non_snake_case)]
-pub struct DeviceRequest {
-    pub bmRequestType: BmRequestType,
-    pub bRequest: u8,
-    pub wValue: u16,
-    pub wIndex: u16,
-    pub wLength: u16,
-}
-
-#[repr(packed)]
-#[derive(Clone, Copy)]
-struct EPAddr(u8);
-impl EPAddr {
-    fn new(bits: u8) -> Self {
-        EPAddr(bits)
-    }
-
-    #[allow(dead_code)]
-    fn from(dir: Direction, ep_id: u8) -> Self {
-        match dir {
-            Direction::DeviceToHost => Self::new(ep_id | 0x80),
-            Direction::HostToDevice => Self::new(ep_id),
-        }
-    }
-
-    fn bits(&self) -> u8 {
-        self.0
-    }
-
-    fn dir(&self) -> Direction {
-        if self.bits() & 0x80 == 0 {
-            Direction::HostToDevice
-        } else {
-            Direction::DeviceToHost
-        }
-    }
-
-    fn ep_id(&self) -> u8 {
-        self.bits() & 0x7f
-    }
-}
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-#[allow(dead_code)]
-enum EPType {
-    Bulk,
-    Control,
-    Isochronous,
-    Interrupt,
-}
-impl EPType {
-    fn bits(&self) -> u8 {
-        use EPType::*;
-        match self {
-            Bulk => 0b00,
-            Control => 0b01,
-            Isochronous => 0b10,
-            Interrupt => 0b11,
-        }
-    }
-}
-
-#[allow(dead_code)]
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum EPStat {
-    Disabled,
-    Stall,
-    Nak,
-    Valid,
-}
-impl EPStat {
-    fn bits(&self) -> u8 {
-        use EPStat::*;
-        match self {
-            Disabled => 0b00,
-            Stall => 0b01,
-            Nak => 0b10,
-            Valid => 0b11,
-        }
-    }
-}
-
 mod ep {
     use stm32f1::stm32f103::usb::epr::{R as EPR_R, W as EPR_W};
 
@@ -319,159 +245,59 @@ mod ep {
     }
 }
 
-#[allow(dead_code)]
-enum ControlState<'a> {
-    Idle {
-        buf: &'a mut [u8],
-    },
-    Stalled {
-        buf: &'a mut [u8],
-    },
-    DataIn {
-        cur: ReadCursor<'a>,
-        req: DeviceRequest,
-    },
-    LastDataIn {
-        cur: ReadCursor<'a>,
-        req: DeviceRequest,
-    },
-    StatusIn {
-        buf: &'a mut [u8],
-    },
-    DataOut {
-        cur: WriteCursor<'a>,
-        req: DeviceRequest,
-    },
-    LastDataOut {
-        cur: WriteCursor<'a>,
-        req: DeviceRequest,
-    },
-    StatusOut {
-        buf: &'a mut [u8],
-    },
-}
-impl<'a> ControlState<'a> {
-    fn into_buf(self) -> &'a mut [u8] {
-        use ControlState::*;
-        match self {
-            Idle { buf } => buf,
-            Stalled { buf } => buf,
-            DataIn { cur, .. } => cur.into(),
-            LastDataIn { cur, .. } => cur.into(),
-            StatusIn { buf } => buf,
-            DataOut { cur, .. } => cur.into(),
-            LastDataOut { cur, .. } => cur.into(),
-            StatusOut { buf } => buf,
-        }
-    }
-}
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum RequestStatus {
-    NotSupported,
-    Handled,
-}
-
-struct USBKbd<'a> {
+// The USB peripheral's registers and packet memory
+struct Peripheral {
     regs: USBRegs,
-    device_descr: &'a descr::DeviceDescriptor,
-    config_descr: &'a [u8],
-    ctrl_state: ControlState<'a>,
-    pending_addr: Option<u8>,
     pm_top: u16,
 }
 
-unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
-    core::slice::from_raw_parts((p as *const T) as *const u8, core::mem::size_of::<T>())
-}
-
-impl<'a> USBKbd<'a> {
-    fn new(
-        regs: USBRegs,
-        device_descr: &'static descr::DeviceDescriptor,
-        config_descr: &'static [u8],
-        ctrl_buf: &'a mut [u8],
-    ) -> Self {
-        USBKbd {
+impl Peripheral {
+    fn new(regs: USBRegs) -> Self {
+        Peripheral {
             regs,
-            device_descr,
-            config_descr,
-            ctrl_state: ControlState::Idle { buf: ctrl_buf },
-            pending_addr: None,
             pm_top: pma::BTABLE_SIZE,
         }
     }
 
-    fn setup(&mut self) {
-        self.reset();
+    fn enable(&self) {
         self.regs
             .cntr
             .write(|w| w.pdwn().clear_bit().resetm().set_bit().ctrm().set_bit());
         self.regs.cntr.modify(|_, w| w.fres().clear_bit());
     }
 
-    fn set_addr(&self, addr: u8) {
-        self.regs
-            .daddr
-            .modify(|_, w| w.add().bits(addr).ef().set_bit());
-    }
-
+    // Clears the interrupts and frees the packet memory of every endpoint
     fn reset(&mut self) {
         self.regs.istr.reset();
         self.pm_top = pma::BTABLE_SIZE;
-        self.ep_setup(
-            EPAddr::new(0),
-            EPType::Control,
-            self.device_descr.bMaxPacketSize0 as u16,
-        );
-        self.set_addr(0);
-    }
-
-    fn epr(&self, ep_id: u8) -> &stm32f103::usb::EPR {
-        &self.regs.epr[ep_id as usize]
-    }
-
-    fn ep_bt_entry(&mut self, addr: EPAddr) -> &mut pma::BTableEntry {
-        let ptr = pma::btable_entry_ptr(addr.ep_id());
-        unsafe { &mut *ptr }
-    }
-
-    fn ep_pm_tx(&mut self, addr: EPAddr, len: usize) -> &mut [VolatileCell<u16>] {
-        let entry = self.ep_bt_entry(addr);
-        entry.tx.set_count(len as u16);
-        unsafe { entry.tx.as_slice_mut(len) }
     }
 
-    fn ep_pm_rx(&mut self, addr: EPAddr) -> &[u8] {
-        unsafe { self.ep_bt_entry(addr).rx.as_slice() }
+    fn frame(&self) -> u16 {
+        self.regs.fnr.read().fn_().bits()
     }
 
-    fn ep_clear_ctr_tx(&mut self, ep_id: u8) {
-        self.epr(ep_id)
-            .modify(|_, w| ep::invariant(w).ctr_tx().clear_bit());
+    fn take_sof(&mut self) -> bool {
+        if !self.regs.istr.read().sof().bit() {
+            return false;
+        }
+        self.regs
+            .istr
+            .write(|w| unsafe { w.bits(0xffff).sof().clear_bit() });
+        true
     }
 
-    fn ep_clear_ctr_rx(&mut self, ep_id: u8) {
-        self.epr(ep_id)
-            .modify(|_, w| ep::invariant(w).ctr_rx().clear_bit());
+    fn epr(&self, ep_id: u8) -> &stm32f103::usb::EPR {
+        &self.regs.epr[ep_id as usize]
     }
 
-    fn ep_stall(&mut self, addr: EPAddr) {
-        self.epr(addr.ep_id()).modify(|r, w| {
-            let w = ep::invariant(w);
-            let w = if addr.ep_id() == 0 {
-                ep::set_tx_stat(r, w, EPStat::Stall.bits())
-            } else {
-                w
-            };
-            match addr.dir() {
-                Direction::HostToDevice => ep::set_rx_stat(r, w, EPStat::Stall.bits()),
-                Direction::DeviceToHost => ep::set_tx_stat(r, w, EPStat::Stall.bits()),
-            }
-        });
+    fn bt_entry(&mut self, ep_id: u8) -> &mut pma::BTableEntry {
+        let ptr = pma::btable_entry_ptr(ep_id);
+        unsafe { &mut *ptr }
     }
+}
 
-    fn ep_setup(&mut self, addr: EPAddr, ep_type: EPType, size: u16) {
+impl usb::Endpoints for Peripheral {
+    fn configure(&mut self, addr: EPAddr, ep_type: EPType, size: u16) {
         {
             let epr = self.epr(addr.ep_id());
             epr.modify(|_, w| {
@@ -483,7 +309,7 @@ impl<'a> USBKbd<'a> {
         // IN or control ep
         if addr.dir() == Direction::DeviceToHost || ep_type == EPType::Control {
             let tx_addr = self.pm_top;
-            let entry = self.ep_bt_entry(addr);
+            let entry = self.bt_entry(addr.ep_id());
             entry.tx.set_addr(tx_addr);
             entry.tx.set_count(0);
             let epr = self.epr(addr.ep_id());
@@ -497,7 +323,7 @@ impl<'a> USBKbd<'a> {
         // OUT
         if addr.dir() == Direction::HostToDevice {
             let rx_addr = self.pm_top;
-            let entry = self.ep_bt_entry(addr);
+            let entry = self.bt_entry(addr.ep_id());
             entry.rx.set_addr(rx_addr);
             let realsize = entry.rx.set_buf_size(size);
             let epr = self.epr(addr.ep_id());
@@ -510,218 +336,345 @@ impl<'a> USBKbd<'a> {
         }
     }
 
-    fn ep_write_packet(&mut self, addr: EPAddr, buf: &[u8]) -> Option<()> {
-        if self.epr(addr.ep_id()).read().stat_tx().bits() == EPStat::Valid.bits() {
-            return None;
-        }
-        let pm = self.ep_pm_tx(addr, buf.len());
-        pma::copy_to_pm(pm, buf);
-        self.epr(addr.ep_id()).modify(|r, w| {
+    fn set_address(&mut self, addr: u8) {
+        self.regs
+            .daddr
+            .modify(|_, w| w.add().bits(addr).ef().set_bit());
+    }
+
+    fn is_setup(&self, ep_id: u8) -> bool {
+        self.epr(ep_id).read().setup().bit()
+    }
+
+    fn stat_tx(&self, ep_id: u8) -> EPStat {
+        EPStat::from_bits(self.epr(ep_id).read().stat_tx().bits())
+    }
+
+    fn stat_rx(&self, ep_id: u8) -> EPStat {
+        EPStat::from_bits(self.epr(ep_id).read().stat_rx().bits())
+    }
+
+    fn set_stat_tx(&mut self, ep_id: u8, stat: EPStat) {
+        self.epr(ep_id).modify(|r, w| {
             let w = ep::invariant(w);
-            ep::set_tx_stat(r, w, EPStat::Valid.bits())
+            ep::set_tx_stat(r, w, stat.bits())
         });
-        Some(())
     }
 
-    fn ep_read_packet(&mut self, addr: EPAddr, buf: &mut [u8]) -> Option<()> {
-        if self.epr(addr.ep_id()).read().stat_rx().bits() == EPStat::Valid.bits() {
-            return None;
-        }
-
-        let pm = self.ep_pm_rx(addr);
-        pma::copy_from_pm(buf, pm);
-        self.ep_clear_ctr_rx(addr.ep_id());
-        self.epr(addr.ep_id()).modify(|r, w| {
+    fn set_stat_rx(&mut self, ep_id: u8, stat: EPStat) {
+        self.epr(ep_id).modify(|r, w| {
             let w = ep::invariant(w);
-            ep::set_rx_stat(r, w, EPStat::Valid.bits())
+            ep::set_rx_stat(r, w, stat.bits())
         });
-        Some(())
     }
 
-    fn ctrl_transition<F>(&mut self, cb: F)
-    where
-        F: FnOnce(&mut Self, ControlState<'a>) -> ControlState<'a>,
-    {
-        let state = core::mem::replace(&mut self.ctrl_state, ControlState::Idle { buf: &mut [] });
-        self.ctrl_state = cb(self, state);
+    fn clear_ctr_tx(&mut self, ep_id: u8) {
+        self.epr(ep_id)
+            .modify(|_, w| ep::invariant(w).ctr_tx().clear_bit());
     }
 
-    fn ctrl_handle_out(&mut self) {
-        if self.regs.epr[0].read().setup().bit() {
-            self.ctrl_handle_setup();
-            return;
+    fn clear_ctr_rx(&mut self, ep_id: u8) {
+        self.epr(ep_id)
+            .modify(|_, w| ep::invariant(w).ctr_rx().clear_bit());
+    }
+
+    fn write_pm(&mut self, ep_id: u8, buf: &[u8]) {
+        let entry = self.bt_entry(ep_id);
+        entry.tx.set_count(buf.len() as u16);
+        let pm = unsafe { entry.tx.as_slice_mut(buf.len()) };
+        pma::copy_to_pm(pm, buf);
+    }
+
+    fn read_pm(&mut self, ep_id: u8, buf: &mut [u8]) -> usize {
+        let entry = self.bt_entry(ep_id);
+        let pm = unsafe { entry.rx.as_slice() };
+        pma::copy_from_pm(buf, pm);
+        entry.rx.received() as usize
+    }
+}
+
+// The HID and DFU interfaces and the vendor requests
+struct Interfaces {
+    configured: bool,
+    hid_idle: u8,
+    hid_boot_protocol: bool,
+    hid_in_flight: Option<u16>,
+    hid_latency: report::Latency,
+    dfu_state: dfu::State,
+    detach_ready: bool,
+    updater: update::Updater<flash::Flash>,
+    install_ready: bool,
+}
+
+impl Interfaces {
+    fn new(updater: update::Updater<flash::Flash>) -> Self {
+        Interfaces {
+            configured: false,
+            hid_idle: HID_DEFAULT_IDLE,
+            hid_boot_protocol: false,
+            hid_in_flight: None,
+            hid_latency: report::Latency::default(),
+            dfu_state: dfu::State::AppIdle,
+            detach_ready: false,
+            updater,
+            install_ready: false,
         }
+    }
 
-        use ControlState::*;
-        self.ctrl_transition(|this, state| {
-            match state {
-                /*
-                DataOut { cur, req } => {
-                    this.ctrl_send_chunk(cur, req, needs_zlp)
-                },
-                */
-                StatusOut { buf, .. } => {
-                    this.ep_read_packet(EPAddr::new(0), &mut []);
-                    ControlState::Idle { buf }
-                }
-                _ => {
-                    this.ep_stall(EPAddr::new(0));
-                    ControlState::Stalled {
-                        buf: state.into_buf(),
-                    }
+    fn reset(&mut self) {
+        self.dfu_state = dfu::State::AppIdle;
+        self.detach_ready = false;
+        self.configured = false;
+        self.hid_idle = HID_DEFAULT_IDLE;
+        self.hid_boot_protocol = false;
+        self.hid_in_flight = None;
+    }
+
+    fn std_write_request(&mut self, ep: &mut Peripheral, req: &DeviceRequest) -> RequestStatus {
+        match req.bRequest {
+            0x09 => {
+                // SET_CONFIGURATION
+                if !self.configured {
+                    ep.configure(EPAddr::new(0x81), EPType::Interrupt, 8);
+                    self.configured = true;
                 }
+                RequestStatus::Handled
             }
-        });
+            _ => RequestStatus::NotSupported,
+        }
     }
 
-    fn ctrl_read_req(&mut self) -> DeviceRequest {
-        let mut buf = [0u8; core::mem::size_of::<DeviceRequest>()];
-        self.ep_read_packet(EPAddr::new(0), &mut buf).unwrap();
-        unsafe { core::mem::transmute(buf) }
+    fn hid_read_request(&mut self, req: &DeviceRequest, wcur: &mut WriteCursor) -> RequestStatus {
+        match req.bRequest {
+            0x02 => {
+                // GET_IDLE
+                let idle = [self.hid_idle];
+                let len = cmp::min(req.wLength as usize, idle.len());
+                wcur.write(&idle[0..len]);
+                RequestStatus::Handled
+            }
+            0x03 => {
+                // GET_PROTOCOL
+                let protocol = [if self.hid_boot_protocol { 0 } else { 1 }];
+                let len = cmp::min(req.wLength as usize, protocol.len());
+                wcur.write(&protocol[0..len]);
+                RequestStatus::Handled
+            }
+            _ => RequestStatus::NotSupported,
+        }
     }
 
-    fn ctrl_handle_setup(&mut self) {
-        let req = self.ctrl_read_req();
-        if req.wLength == 0 {
-            self.ctrl_setup_read(req);
-        } else {
-            match req.bmRequestType.direction() {
-                Direction::HostToDevice => self.ctrl_setup_write(req),
-                Direction::DeviceToHost => self.ctrl_setup_read(req),
+    fn hid_write_request(&mut self, req: &DeviceRequest) -> RequestStatus {
+        match req.bRequest {
+            0x0A => {
+                // SET_IDLE
+                self.hid_idle = (req.wValue >> 8) as u8;
+                RequestStatus::Handled
+            }
+            0x0B => {
+                // SET_PROTOCOL
+                self.hid_boot_protocol = req.wValue == 0;
+                RequestStatus::Handled
             }
+            _ => RequestStatus::NotSupported,
         }
     }
 
-    fn ctrl_setup_read(&mut self, req: DeviceRequest) {
-        self.ctrl_transition(|this, state| {
-            let buf = state.into_buf();
-            let mut wcur = WriteCursor::new(buf);
-            match this.ctrl_handle_read_request(&req, &mut wcur) {
-                RequestStatus::NotSupported => {
-                    this.ep_stall(EPAddr::new(0));
-                    ControlState::Stalled {
-                        buf: wcur.into_buf(),
-                    }
-                }
-                RequestStatus::Handled => {
-                    if req.wLength == 0 {
-                        this.ep_write_packet(EPAddr::new(0), &[]).unwrap();
-                        ControlState::StatusIn {
-                            buf: wcur.into_buf(),
-                        }
-                    } else {
-                        let cur = wcur.into_read();
-                        this.ctrl_send_chunk(cur, req)
-                    }
-                }
+    fn dfu_read_request(&mut self, req: &DeviceRequest, wcur: &mut WriteCursor) -> RequestStatus {
+        match req.bRequest {
+            dfu::DFU_GETSTATUS => {
+                // bStatus = OK, bwPollTimeout = 0, bState, iString = 0
+                let status = [0x00, 0x00, 0x00, 0x00, self.dfu_state.bits(), 0x00];
+                let len = cmp::min(req.wLength as usize, status.len());
+                wcur.write(&status[0..len]);
+                RequestStatus::Handled
             }
-        });
+            dfu::DFU_GETSTATE => {
+                let state = [self.dfu_state.bits()];
+                let len = cmp::min(req.wLength as usize, state.len());
+                wcur.write(&state[0..len]);
+                RequestStatus::Handled
+            }
+            _ => RequestStatus::NotSupported,
+        }
     }
 
-    fn ctrl_setup_write(&mut self, req: DeviceRequest) {
-        self.ctrl_handle_write_request(req, &[]);
+    fn dfu_write_request(&mut self, req: &DeviceRequest) -> RequestStatus {
+        match req.bRequest {
+            dfu::DFU_DETACH => {
+                self.dfu_state = dfu::State::AppDetach;
+                RequestStatus::Handled
+            }
+            _ => RequestStatus::NotSupported,
+        }
     }
 
-    fn ctrl_handle_read_request(
+    fn vendor_read_request(
         &mut self,
         req: &DeviceRequest,
-        wcur: &mut WriteCursor<'a>,
+        wcur: &mut WriteCursor,
     ) -> RequestStatus {
-        let mut str_buf = [0u8; 64];
         match req.bRequest {
-            0x05 => {
-                // SET_ADDRESS
-                self.pending_addr = Some(req.wValue as u8);
-                //self.set_addr(req.wValue as u8);
-                //hprintln!("{:x?}", req.wValue);
-                RequestStatus::Handled
-            }
-            0x06 => {
-                // GET_DESCRIPTOR
-                let descr_index = req.wValue & 0xff;
-                let bytes = match req.wValue & 0xff00 {
-                    0x0100 => unsafe { any_as_u8_slice(&*self.device_descr) },
-                    0x0200 => self.config_descr,
-                    0x0300 => {
-                        if descr_index == 0 {
-                            descr::STRING_DESCR0
-                        } else {
-                            let str_data = STRINGS[descr_index as usize - 1];
-                            let len = descr::build_string_descr(&mut str_buf, str_data).unwrap();
-                            &str_buf[0..len]
-                        }
-                    }
-                    0x2200 => HID_REPORT_DESCR,
-                    _ => {
-                        return RequestStatus::NotSupported;
-                    }
-                };
-                let len = cmp::min(req.wLength as usize, bytes.len());
-                wcur.write(&bytes[0..len]);
+            update::UPDATE_STATUS => {
+                let status = self.updater.status();
+                let len = cmp::min(req.wLength as usize, status.len());
+                wcur.write(&status[0..len]);
                 RequestStatus::Handled
             }
-            0x09 => {
-                // SET_CONFIGURATION
-                self.ep_setup(EPAddr::new(0x81), EPType::Interrupt, 8);
+            report::GET_LATENCY => {
+                let latency = self.hid_latency.to_bytes();
+                let len = cmp::min(req.wLength as usize, latency.len());
+                wcur.write(&latency[0..len]);
                 RequestStatus::Handled
             }
             _ => RequestStatus::NotSupported,
         }
     }
 
-    fn ctrl_handle_write_request(&mut self, _req: DeviceRequest, _buf: &[u8]) {}
-
-    fn ctrl_handle_in(&mut self) {
-        use ControlState::*;
-        self.ctrl_transition(|this, state| match state {
-            DataIn { cur, req } => this.ctrl_send_chunk(cur, req),
-            LastDataIn { cur, .. } => {
-                this.epr(0).modify(|r, w| {
-                    let w = ep::invariant(w);
-                    ep::set_rx_stat(r, w, EPStat::Valid.bits())
-                });
-                this.ep_read_packet(EPAddr::new(0), &mut []);
-                let buf = cur.into_buf();
-                ControlState::StatusOut { buf }
+    fn vendor_write_request(&mut self, req: &DeviceRequest, buf: &[u8]) -> RequestStatus {
+        let res = match req.bRequest {
+            update::UPDATE_BEGIN => self.updater.begin(buf),
+            update::UPDATE_WRITE => {
+                let offset = (req.wIndex as u32) << 16 | req.wValue as u32;
+                self.updater.write(offset, buf)
             }
-            StatusIn { buf, .. } => {
-                if let Some(addr) = this.pending_addr {
-                    this.set_addr(addr);
-                    this.pending_addr = None;
-                }
-                ControlState::Idle { buf }
-            }
-            _ => {
-                this.ep_stall(EPAddr::new(0));
-                ControlState::Stalled {
-                    buf: state.into_buf(),
-                }
-            }
-        });
+            update::UPDATE_COMMIT => self.updater.commit(),
+            _ => return RequestStatus::NotSupported,
+        };
+        match res {
+            Ok(()) => RequestStatus::Handled,
+            Err(_) => RequestStatus::NotSupported,
+        }
+    }
+}
+
+impl usb::Handler<Peripheral> for Interfaces {
+    fn read_request(&mut self, req: &DeviceRequest, wcur: &mut WriteCursor) -> RequestStatus {
+        match req.bmRequestType.request_type() {
+            Type::Class => match (req.bmRequestType.recipient(), req.wIndex as u8) {
+                (Recipient::Interface, KBD_INTERFACE) => self.hid_read_request(req, wcur),
+                (Recipient::Interface, DFU_INTERFACE) => self.dfu_read_request(req, wcur),
+                _ => RequestStatus::NotSupported,
+            },
+            Type::Vendor => self.vendor_read_request(req, wcur),
+            _ => RequestStatus::NotSupported,
+        }
+    }
+
+    fn write_request(
+        &mut self,
+        ep: &mut Peripheral,
+        req: &DeviceRequest,
+        data: &[u8],
+    ) -> RequestStatus {
+        match req.bmRequestType.request_type() {
+            Type::Standard => self.std_write_request(ep, req),
+            Type::Class => match (req.bmRequestType.recipient(), req.wIndex as u8) {
+                (Recipient::Interface, KBD_INTERFACE) => self.hid_write_request(req),
+                (Recipient::Interface, DFU_INTERFACE) => self.dfu_write_request(req),
+                _ => RequestStatus::NotSupported,
+            },
+            Type::Vendor => self.vendor_write_request(req, data),
+            _ => RequestStatus::NotSupported,
+        }
+    }
+
+    fn status_complete(&mut self) {
+        if self.dfu_state == dfu::State::AppDetach {
+            self.detach_ready = true;
+        }
+        if self.updater.state() == update::State::Verified {
+            self.install_ready = true;
+        }
+    }
+}
+
+struct USBKbd<'a> {
+    periph: Peripheral,
+    ctrl: usb::Control<'a>,
+    ifaces: Interfaces,
+}
+
+impl<'a> USBKbd<'a> {
+    fn new(
+        regs: USBRegs,
+        descriptors: usb::Descriptors<'static>,
+        ctrl_buf: &'a mut [u8],
+        updater: update::Updater<flash::Flash>,
+    ) -> Self {
+        USBKbd {
+            periph: Peripheral::new(regs),
+            ctrl: usb::Control::new(descriptors, ctrl_buf),
+            ifaces: Interfaces::new(updater),
+        }
+    }
+
+    fn setup(&mut self) {
+        self.reset();
+        self.periph.enable();
+    }
+
+    fn frame(&self) -> u16 {
+        self.periph.frame()
+    }
+
+    fn take_sof(&mut self) -> bool {
+        self.periph.take_sof()
+    }
+
+    fn reset(&mut self) {
+        self.periph.reset();
+        self.ctrl.reset(&mut self.periph);
+        self.ifaces.reset();
+    }
+
+    fn hid_idle_ms(&self) -> u32 {
+        self.ifaces.hid_idle as u32 * 4
+    }
+
+    fn hid_boot_protocol(&self) -> bool {
+        self.ifaces.hid_boot_protocol
     }
 
-    fn ctrl_send_chunk(&mut self, mut cur: ReadCursor<'a>, req: DeviceRequest) -> ControlState<'a> {
-        #[allow(non_snake_case)]
-        let bMaxPacketSize0 = self.device_descr.bMaxPacketSize0 as usize;
+    fn detach_ready(&self) -> bool {
+        self.ifaces.detach_ready
+    }
 
-        let chunk = cur.read(bMaxPacketSize0);
-        self.ep_write_packet(EPAddr::new(0), chunk).unwrap();
+    fn install_ready(&self) -> bool {
+        self.ifaces.install_ready
+    }
 
-        if bMaxPacketSize0 > chunk.len() {
-            ControlState::LastDataIn { cur, req }
-        } else {
-            ControlState::DataIn { cur, req }
+    fn flash(&mut self) -> &mut flash::Flash {
+        self.ifaces.updater.flash()
+    }
+
+    // Leaves the verified image to the boot stub and resets. If it cannot
+    // be recorded, the error is reported in UPDATE_STATUS instead.
+    fn install_update(&mut self) {
+        self.ifaces.install_ready = false;
+        if self.ifaces.updater.install().is_ok() {
+            cortex_m::peripheral::SCB::sys_reset();
         }
     }
 
-    fn hid_handle_in(&mut self) {}
+    fn hid_handle_in(&mut self) {
+        if let Some(scanned_at) = self.ifaces.hid_in_flight.take() {
+            let frame = self.frame();
+            self.ifaces.hid_latency.record(scanned_at, frame);
+        }
+    }
 
-    fn hid_send_keys(&mut self, keys: &[u8]) -> Option<()> {
-        self.ep_write_packet(EPAddr::new(0x81), keys)
+    fn hid_send_keys(&mut self, keys: &[u8], scanned_at: Option<u16>) -> Option<()> {
+        if !self.ifaces.configured {
+            return None;
+        }
+        self.periph.write_packet(EPAddr::new(0x81), keys)?;
+        self.ifaces.hid_in_flight = scanned_at;
+        Some(())
     }
 
     fn usb_poll(&mut self) {
-        let istr_r = self.regs.istr.read();
+        let istr_r = self.periph.regs.istr.read();
         if istr_r.reset().bit() {
             self.reset();
             return;
@@ -732,14 +685,14 @@ impl<'a> USBKbd<'a> {
             if istr_r.dir().bit() {
                 // OUT
                 match ep_id {
-                    0 => self.ctrl_handle_out(),
-                    _ => self.ep_clear_ctr_rx(ep_id),
+                    0 => self.ctrl.handle_out(&mut self.periph, &mut self.ifaces),
+                    _ => self.periph.clear_ctr_rx(ep_id),
                 }
             } else {
                 // IN
-                self.ep_clear_ctr_tx(ep_id);
+                self.periph.clear_ctr_tx(ep_id);
                 match ep_id {
-                    0 => self.ctrl_handle_in(),
+                    0 => self.ctrl.handle_in(&mut self.periph, &mut self.ifaces),
                     1 => self.hid_handle_in(),
                     _ => {}
                 }
@@ -751,42 +704,20 @@ impl<'a> USBKbd<'a> {
 #[entry]
 fn main() -> ! {
     let p = stm32f103::Peripherals::take().unwrap();
+    let cp = cortex_m::Peripherals::take().unwrap();
     setup_clock(&p.RCC, &p.FLASH);
+    timer::init(cp.SYST);
 
     p.RCC
         .apb2enr
         .write(|w| w.iopaen().set_bit().iopben().set_bit().iopcen().set_bit());
     p.RCC.apb1enr.write(|w| w.usben().set_bit());
     p.GPIOA.crh.write(|w| {
-        w.mode8()
-            .bits(gpio::Mode::Output2MHz.bits())
-            .cnf8()
-            .bits(gpio::OutputCnf::Pushpull.bits())
-            .mode12()
+        w.mode12()
             .bits(gpio::Mode::Output50MHz.bits())
             .cnf12()
             .bits(gpio::OutputCnf::Pushpull.bits())
     });
-    p.GPIOB.crl.write(|w| {
-        w.mode5()
-            .bits(gpio::Mode::Input.bits())
-            .cnf5()
-            .bits(gpio::InputCnf::PullUpdown.bits())
-            .mode6()
-            .bits(gpio::Mode::Input.bits())
-            .cnf6()
-            .bits(gpio::InputCnf::PullUpdown.bits())
-            .mode7()
-            .bits(gpio::Mode::Input.bits())
-            .cnf7()
-            .bits(gpio::InputCnf::PullUpdown.bits())
-    });
-    p.GPIOB.crh.write(|w| {
-        w.mode11()
-            .bits(gpio::Mode::Output2MHz.bits())
-            .cnf11()
-            .bits(gpio::OutputCnf::Pushpull.bits())
-    });
     p.GPIOC.crh.write(|w| {
         w.mode13()
             .bits(gpio::Mode::Output2MHz.bits())
@@ -809,17 +740,120 @@ fn main() -> ! {
             core::mem::size_of::<CompositeConfigDescriptor>(),
         )
     };
-    let mut kbd = USBKbd::new(p.USB, &DEVICE_DESCR, config_descr_buf, &mut ctrl_buf);
+    let updater = update::Updater::new(flash::Flash::new(p.FLASH));
+    let descriptors = usb::Descriptors {
+        device: &DEVICE_DESCR,
+        config: config_descr_buf,
+        hid_report: HID_REPORT_DESCR,
+        strings: STRINGS,
+    };
+    let mut kbd = USBKbd::new(p.USB, descriptors, &mut ctrl_buf, updater);
     kbd.setup();
-    p.GPIOA.odr.write(|w| w.odr8().bit(true));
+    let matrix = board::matrix();
+    let mut debouncer = debounce::Debouncer::new(board::DEBOUNCE);
+    let mut ghost_filter = ghost::GhostFilter::new(board::GHOST_DETECTION);
+    let mut bounce_keys = access::BounceKeys::new(BOUNCE_KEYS);
+    let mut slow_keys = access::SlowKeys::new(SLOW_KEYS);
+    let mut sticky_keys = access::StickyKeys::new(STICKY_KEYS);
+    let mut events = event::EventQueue::new();
+    let mut keymap = keymap::Keymap::new(
+        &layout::LAYERS,
+        &layout::COMBOS,
+        &layout::DANCES,
+        TAP_HOLD,
+    );
+    let mut player = macros::Player::new(&layout::MACROS);
+    let mut leader = leader::Leader::new(&layout::LEADER_TRIE, layout::LEADER);
+    let mut recorder = dynmacro::Recorder::new();
+    if PERSIST_MACROS {
+        recorder.load(kbd.flash());
+    }
+    let mut settings = settings::Settings::load(kbd.flash());
+    let mut report_builder = report::ReportBuilder::new();
+    let mut queue = report::ReportQueue::new();
     loop {
         kbd.usb_poll();
+        if kbd.detach_ready() {
+            dfu::reboot_into_bootloader(&p.RCC, &p.PWR, &p.BKP);
+        }
+        if kbd.install_ready() {
+            kbd.install_update();
+        }
 
-        let mut buf = [0u8; 8];
-        let bit = p.GPIOB.idr.read().idr5().bit();
-        if bit {
-            buf[2] = 0x04;
+        // Scan right after SOF so a new state is armed on EP1 before the
+        // host's next poll.
+        let now = timer::now();
+        if kbd.take_sof() {
+            let state = debouncer.update(&matrix.scan(), now);
+            let (state, ghosted) = ghost_filter.filter(&state);
+            let state = slow_keys.update(&bounce_keys.update(&state, now), now);
+            events.push_changes(&state, now);
+            report_builder.set_boot_protocol(kbd.hid_boot_protocol());
+            // Key events wait in the queue while a macro plays.
+            while !queue.is_full() {
+                let stepped = if player.is_playing() {
+                    player.step(now)
+                } else {
+                    keymap.step(&mut events, now)
+                };
+                let keys = sticky_keys.update(keymap.keys());
+                match keymap.take_request() {
+                    Some(Action::Macro(idx)) => player.start(idx),
+                    Some(Action::RecordMacro(slot)) => {
+                        if recorder.toggle(slot, &keys) && PERSIST_MACROS {
+                            recorder.save(kbd.flash()).ok();
+                        }
+                    }
+                    Some(Action::PlayRecorded(slot)) => {
+                        if let Some(recording) = recorder.recording(slot) {
+                            player.start_recording(recording);
+                        }
+                    }
+                    Some(Action::Leader) => leader.start(&keys, now),
+                    Some(Action::Unicode(c)) => {
+                        player.start_recording(&unicode::sequence(c, settings.unicode_mode));
+                    }
+                    Some(Action::UnicodeMode(mode)) => {
+                        if settings.unicode_mode != mode {
+                            settings.unicode_mode = mode;
+                            settings.save(kbd.flash()).ok();
+                        }
+                    }
+                    _ => {}
+                }
+                recorder.record(&keys);
+                match leader.update(&keys, now) {
+                    Some(leader::Outcome::Matched(steps)) => player.start_steps(steps),
+                    Some(leader::Outcome::Unmatched(taps)) => player.start_recording(&taps),
+                    None => {}
+                }
+                let keys = player.keys(leader.mask(keys));
+                let report = report_builder.build(&keys, ghosted);
+                queue.push(report, kbd.frame()).ok();
+                if !stepped {
+                    break;
+                }
+            }
+            // The PC13 LED is lit while the pin is low: during Caps Word or
+            // while a key is locked, blinking while Key Lock waits for a key
+            let led = keymap.caps_word()
+                || match keymap.key_lock() {
+                    keylock::State::Off => false,
+                    keylock::State::Armed => now / 250 % 2 == 0,
+                    keylock::State::Locked(_) => true,
+                };
+            p.GPIOC.bsrr.write(|w| {
+                if led {
+                    w.br13().set_bit()
+                } else {
+                    w.bs13().set_bit()
+                }
+            });
+        }
+        if let Some((report, scanned_at)) = queue.pending(now, kbd.hid_idle_ms()) {
+            if kbd.hid_send_keys(report, scanned_at).is_some() {
+                queue.mark_sent(now);
+            }
         }
-        kbd.hid_send_keys(&buf);
     }
 }
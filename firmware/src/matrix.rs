@@ -0,0 +1,106 @@
+use crate::gpio::{InputCnf, Mode, OutputCnf, Pin};
+
+pub use kb789_firmware::KeyState;
+
+// Which way current flows through a switch's diode
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiodeDirection {
+    Col2Row,
+    Row2Col,
+}
+
+// Pull on the input lines. With pull-down the anode side is driven high and
+// the cathode side is read; with pull-up the cathode side is driven low and
+// the anode side is read.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pull {
+    Up,
+    Down,
+}
+
+pub struct Matrix<P: Pin, const ROWS: usize, const COLS: usize> {
+    rows: [P; ROWS],
+    cols: [P; COLS],
+    pull: Pull,
+    strobe_rows: bool,
+    settle_cycles: u32,
+}
+
+impl<P: Pin, const ROWS: usize, const COLS: usize> Matrix<P, ROWS, COLS> {
+    pub fn new(
+        rows: [P; ROWS],
+        cols: [P; COLS],
+        diode: DiodeDirection,
+        pull: Pull,
+        settle_cycles: u32,
+    ) -> Self {
+        let strobe_rows = (diode == DiodeDirection::Row2Col) == (pull == Pull::Down);
+        let matrix = Matrix {
+            rows,
+            cols,
+            pull,
+            strobe_rows,
+            settle_cycles,
+        };
+        let (outputs, inputs) = matrix.lines();
+        for pin in outputs {
+            matrix.release(pin);
+            pin.configure_output(Mode::Output2MHz, OutputCnf::Pushpull);
+        }
+        for pin in inputs {
+            pin.configure_input(InputCnf::PullUpdown);
+            match pull {
+                Pull::Up => pin.set_high(),
+                Pull::Down => pin.set_low(),
+            }
+        }
+        matrix
+    }
+
+    fn lines(&self) -> (&[P], &[P]) {
+        if self.strobe_rows {
+            (&self.rows, &self.cols)
+        } else {
+            (&self.cols, &self.rows)
+        }
+    }
+
+    fn drive(&self, pin: &P) {
+        match self.pull {
+            Pull::Up => pin.set_low(),
+            Pull::Down => pin.set_high(),
+        }
+    }
+
+    fn release(&self, pin: &P) {
+        match self.pull {
+            Pull::Up => pin.set_high(),
+            Pull::Down => pin.set_low(),
+        }
+    }
+
+    fn is_active(&self, pin: &P) -> bool {
+        pin.is_high() == (self.pull == Pull::Down)
+    }
+
+    pub fn scan(&self) -> KeyState<ROWS, COLS> {
+        let mut state = [[false; COLS]; ROWS];
+        let (outputs, inputs) = self.lines();
+        for (out_idx, out_pin) in outputs.iter().enumerate() {
+            self.drive(out_pin);
+            cortex_m::asm::delay(self.settle_cycles);
+            for (in_idx, in_pin) in inputs.iter().enumerate() {
+                let (row, col) = if self.strobe_rows {
+                    (out_idx, in_idx)
+                } else {
+                    (in_idx, out_idx)
+                };
+                state[row][col] = self.is_active(in_pin);
+            }
+            self.release(out_pin);
+        }
+        state
+    }
+}
@@ -0,0 +1,5 @@
+// The key matrix size. build.rs includes this file too, to check the
+// keymap against it.
+
+pub const ROWS: usize = 3;
+pub const COLS: usize = 2;
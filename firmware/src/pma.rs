@@ -85,9 +85,13 @@ impl BTableRxEntry {
         }
     }
 
+    pub fn received(&self) -> u16 {
+        self.count() & 0x3ff
+    }
+
     pub unsafe fn as_slice(&self) -> &[u8] {
         let addr = self.addr() as u32 * 2 + PMA_BASE;
-        slice::from_raw_parts(addr as *mut u8, self.count() as usize)
+        slice::from_raw_parts(addr as *mut u8, self.received() as usize * 2)
     }
 }
 
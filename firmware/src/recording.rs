@@ -0,0 +1,62 @@
+// Key presses and releases for the macro player to send, in order
+
+use crate::keycode::Keycode;
+
+pub const CAPACITY: usize = 128;
+
+// Recordings dynmacro keeps. build.rs includes this file too, to check REC
+// and PLAY slots against it.
+pub const SLOTS: usize = 2;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Recording {
+    len: usize,
+    events: [(Keycode, bool); CAPACITY],
+}
+
+impl Recording {
+    pub fn new() -> Self {
+        Recording {
+            len: 0,
+            events: [(Keycode::No, false); CAPACITY],
+        }
+    }
+
+    pub fn get(&self, idx: usize) -> Option<(Keycode, bool)> {
+        self.events[..self.len].get(idx).copied()
+    }
+
+    // Events past CAPACITY are dropped.
+    pub fn push(&mut self, key: Keycode, pressed: bool) {
+        if self.len < CAPACITY {
+            self.events[self.len] = (key, pressed);
+            self.len += 1;
+        }
+    }
+
+    // One dynmacro slot: event count (u8) then CAPACITY x (usage, pressed)
+    pub fn write_to(&self, buf: &mut [u8]) {
+        buf[0] = self.len as u8;
+        for (dst, (key, pressed)) in buf[1..].chunks_exact_mut(2).zip(&self.events) {
+            dst[0] = key.usage();
+            dst[1] = *pressed as u8;
+        }
+    }
+
+    pub fn read_from(buf: &[u8]) -> Self {
+        let mut recording = Recording::new();
+        let len = buf[0] as usize;
+        for event in buf[1..].chunks_exact(2).take(len) {
+            if let Some(key) = Keycode::from_usage(event[0]) {
+                recording.push(key, event[1] != 0);
+            }
+        }
+        recording
+    }
+}
+
+impl Default for Recording {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,416 @@
+use core::cmp;
+
+use crate::keycode::{KeySet, Keycode};
+
+pub type Report = [u8; 8];
+
+pub const GET_LATENCY: u8 = 0x05;
+
+const CAPACITY: usize = 16;
+
+// Boot protocol hosts (BIOS, UEFI) only know the HID 1.11 Appendix B
+// descriptor, whose key array stops at Application (0x65). Japanese and
+// Korean boot keyboards send International1-5 (0x87-0x8B) and LANG1/LANG2
+// (0x90/0x91) as well, and firmware setups on those layouts expect them.
+fn boot_reportable(key: Keycode) -> bool {
+    matches!(key.usage(), 0x00..=0x65 | 0x87..=0x8B | 0x90..=0x91)
+}
+
+// USB frame numbers (FNR.FN) are 11 bits wide
+const FRAME_MASK: u16 = 0x7ff;
+
+// Reports waiting for EP1, oldest first. Every state change is kept, so a
+// press and release between two host polls still reach the host in order.
+// Each report remembers the frame in which it was scanned.
+pub struct ReportQueue {
+    buf: [(Report, u16); CAPACITY],
+    head: usize,
+    len: usize,
+    last: Report,
+    sent_at: u32,
+}
+
+impl ReportQueue {
+    pub fn new() -> Self {
+        ReportQueue {
+            buf: [([0; 8], 0); CAPACITY],
+            head: 0,
+            len: 0,
+            last: [0; 8],
+            sent_at: 0,
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == CAPACITY
+    }
+
+    // Queues `report` if it differs from the previously pushed one.
+    // When the queue is full the report is handed back; the caller should
+    // stop producing until the host catches up.
+    pub fn push(&mut self, report: Report, frame: u16) -> Result<(), Report> {
+        if report == self.last {
+            return Ok(());
+        }
+        if self.is_full() {
+            return Err(report);
+        }
+        self.buf[(self.head + self.len) % CAPACITY] = (report, frame);
+        self.len += 1;
+        self.last = report;
+        Ok(())
+    }
+
+    // The report to hand to EP1 next: the oldest queued change, or the
+    // current state again once the HID idle period (0 = never) has passed.
+    // Idle repeats carry no scan frame.
+    pub fn pending(&self, now: u32, idle_ms: u32) -> Option<(&Report, Option<u16>)> {
+        if self.len > 0 {
+            let (report, frame) = &self.buf[self.head];
+            Some((report, Some(*frame)))
+        } else if idle_ms != 0 && now.wrapping_sub(self.sent_at) >= idle_ms {
+            Some((&self.last, None))
+        } else {
+            None
+        }
+    }
+
+    pub fn mark_sent(&mut self, now: u32) {
+        if self.len > 0 {
+            self.head = (self.head + 1) % CAPACITY;
+            self.len -= 1;
+        }
+        self.sent_at = now;
+    }
+}
+
+impl Default for ReportQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Frames between scanning a state change and the host ACKing its report
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Latency {
+    last: u16,
+    max: u16,
+}
+
+impl Latency {
+    pub fn record(&mut self, scanned_at: u16, acked_at: u16) {
+        let frames = acked_at.wrapping_sub(scanned_at) & FRAME_MASK;
+        self.last = frames;
+        self.max = cmp::max(self.max, frames);
+    }
+
+    pub fn to_bytes(self) -> [u8; 4] {
+        let last = self.last.to_le_bytes();
+        let max = self.max.to_le_bytes();
+        [last[0], last[1], max[0], max[1]]
+    }
+}
+
+// Builds boot keyboard reports from the set of held keycodes. Keys stay in
+// the order they were pressed; keys pressed in the same scan are appended in
+// usage order.
+pub struct ReportBuilder {
+    order: [Keycode; 6],
+    len: usize,
+    boot_protocol: bool,
+}
+
+impl ReportBuilder {
+    pub fn new() -> Self {
+        ReportBuilder {
+            order: [Keycode::No; 6],
+            len: 0,
+            boot_protocol: false,
+        }
+    }
+
+    pub fn set_boot_protocol(&mut self, boot_protocol: bool) {
+        self.boot_protocol = boot_protocol;
+    }
+
+    // `phantom` marks a state the matrix could not resolve (ghosting).
+    pub fn build(&mut self, keys: &KeySet, phantom: bool) -> Report {
+        let mut report = [0u8; 8];
+        for bit in keys.iter().filter_map(Keycode::modifier_bit) {
+            report[0] |= bit;
+        }
+
+        let boot_protocol = self.boot_protocol;
+        let reportable =
+            |key: &Keycode| !key.is_modifier() && (!boot_protocol || boot_reportable(*key));
+
+        let mut kept = 0;
+        for idx in 0..self.len {
+            if keys.contains(self.order[idx]) && reportable(&self.order[idx]) {
+                self.order[kept] = self.order[idx];
+                kept += 1;
+            }
+        }
+        self.len = kept;
+
+        let mut overflow = false;
+        for key in keys.iter().filter(reportable) {
+            if self.order[0..self.len].contains(&key) {
+                continue;
+            }
+            if self.len < self.order.len() {
+                self.order[self.len] = key;
+                self.len += 1;
+            } else {
+                overflow = true;
+            }
+        }
+
+        if phantom || overflow {
+            report[2..].copy_from_slice(&[Keycode::ErrorRollOver.usage(); 6]);
+        } else {
+            for (dst, key) in report[2..].iter_mut().zip(&self.order[0..self.len]) {
+                *dst = key.usage();
+            }
+        }
+        report
+    }
+}
+
+impl Default for ReportBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(boot_protocol: bool, keys: &[Keycode]) -> Report {
+        let mut set = KeySet::new();
+        for &key in keys {
+            set.insert(key);
+        }
+        let mut builder = ReportBuilder::new();
+        builder.set_boot_protocol(boot_protocol);
+        builder.build(&set, false)
+    }
+
+    #[test]
+    fn boot_protocol_keeps_japanese_and_korean_keys() {
+        let keys = [
+            Keycode::International1,
+            Keycode::International5,
+            Keycode::Lang1,
+            Keycode::Lang2,
+            Keycode::Application,
+        ];
+        assert_eq!(build(true, &keys), [0, 0, 0x65, 0x87, 0x8B, 0x90, 0x91, 0]);
+    }
+
+    #[test]
+    fn boot_protocol_drops_keys_beyond_the_boot_descriptor() {
+        let keys = [
+            Keycode::A,
+            Keycode::F13,
+            Keycode::International6,
+            Keycode::Lang3,
+        ];
+        assert_eq!(build(true, &keys), [0, 0, 0x04, 0, 0, 0, 0, 0]);
+        assert_eq!(build(false, &keys), [0, 0, 0x04, 0x68, 0x8C, 0x92, 0, 0]);
+    }
+
+    // Feeds one builder the key sets held in turn
+    fn build_each(sets: &[&[Keycode]]) -> Vec<Report> {
+        let mut builder = ReportBuilder::new();
+        sets.iter()
+            .map(|keys| {
+                let mut set = KeySet::new();
+                for &key in keys.iter() {
+                    set.insert(key);
+                }
+                builder.build(&set, false)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keys_keep_their_press_order() {
+        use Keycode::*;
+        let reports = build_each(&[&[C], &[C, A], &[C, A, B], &[A, B], &[A, B, C]]);
+        assert_eq!(
+            reports,
+            [
+                [0, 0, 0x06, 0, 0, 0, 0, 0],
+                [0, 0, 0x06, 0x04, 0, 0, 0, 0],
+                [0, 0, 0x06, 0x04, 0x05, 0, 0, 0],
+                [0, 0, 0x04, 0x05, 0, 0, 0, 0],
+                [0, 0, 0x04, 0x05, 0x06, 0, 0, 0],
+            ]
+        );
+    }
+
+    #[test]
+    fn modifiers_go_in_the_bitfield() {
+        use Keycode::*;
+        let reports = build_each(&[
+            &[LeftCtrl, RightShift, A],
+            &[
+                LeftCtrl, LeftShift, LeftAlt, LeftGui, RightCtrl, RightShift, RightAlt, RightGui,
+            ],
+            &[LeftGui, A, B, C, D, E, F],
+        ]);
+        assert_eq!(
+            reports,
+            [
+                [0x21, 0, 0x04, 0, 0, 0, 0, 0],
+                [0xFF, 0, 0, 0, 0, 0, 0, 0],
+                [0x08, 0, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09],
+            ]
+        );
+    }
+
+    #[test]
+    fn seventh_key_rolls_over_until_released() {
+        use Keycode::*;
+        let rollover = [0x02, 0, 1, 1, 1, 1, 1, 1];
+        let reports = build_each(&[
+            &[LeftShift, A, B, C, D, E, F],
+            &[LeftShift, A, B, C, D, E, F, G],
+            &[LeftShift, B, C, D, E, F, G],
+            &[LeftShift, B, C, D, E, F, G, H],
+            &[B, C],
+        ]);
+        assert_eq!(
+            reports,
+            [
+                [0x02, 0, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09],
+                rollover,
+                [0x02, 0, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A],
+                rollover,
+                [0, 0, 0x05, 0x06, 0, 0, 0, 0],
+            ]
+        );
+    }
+
+    #[test]
+    fn phantom_state_rolls_over() {
+        let mut set = KeySet::new();
+        set.insert(Keycode::A);
+        set.insert(Keycode::LeftCtrl);
+        let mut builder = ReportBuilder::new();
+        assert_eq!(builder.build(&set, true), [0x01, 0, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(builder.build(&set, false), [0x01, 0, 0x04, 0, 0, 0, 0, 0]);
+    }
+
+    fn key_report(usage: u8) -> Report {
+        [0, 0, usage, 0, 0, 0, 0, 0]
+    }
+
+    // Pops every queued report, as the host polling EP1 would
+    fn drain(queue: &mut ReportQueue, now: u32) -> Vec<(Report, Option<u16>)> {
+        let mut sent = Vec::new();
+        while queue.len > 0 {
+            let (report, frame) = queue.pending(now, 0).unwrap();
+            sent.push((*report, frame));
+            queue.mark_sent(now);
+        }
+        sent
+    }
+
+    #[test]
+    fn queue_is_fifo() {
+        let mut queue = ReportQueue::new();
+        queue.push(key_report(0x04), 1).unwrap();
+        queue.push(key_report(0x05), 2).unwrap();
+        queue.push([0; 8], 3).unwrap();
+        assert_eq!(
+            drain(&mut queue, 0),
+            [
+                (key_report(0x04), Some(1)),
+                (key_report(0x05), Some(2)),
+                ([0; 8], Some(3)),
+            ]
+        );
+        assert_eq!(queue.pending(0, 0), None);
+    }
+
+    #[test]
+    fn queue_drops_repeats_of_the_last_push() {
+        let mut queue = ReportQueue::new();
+        // The host starts out seeing no keys
+        queue.push([0; 8], 1).unwrap();
+        queue.push(key_report(0x04), 2).unwrap();
+        queue.push(key_report(0x04), 3).unwrap();
+        assert_eq!(drain(&mut queue, 0), [(key_report(0x04), Some(2))]);
+        // Sending does not forget the last report
+        queue.push(key_report(0x04), 4).unwrap();
+        assert_eq!(queue.pending(0, 0), None);
+        queue.push([0; 8], 5).unwrap();
+        queue.push(key_report(0x04), 6).unwrap();
+        assert_eq!(
+            drain(&mut queue, 0),
+            [([0; 8], Some(5)), (key_report(0x04), Some(6))]
+        );
+    }
+
+    #[test]
+    fn full_queue_holds_back_instead_of_dropping() {
+        // Taps of A..=Z as press/release pairs, produced as fast as the
+        // queue takes them, as the main loop does
+        let produced: Vec<Report> = (0x04..=0x1D)
+            .flat_map(|usage| [key_report(usage), [0; 8]])
+            .collect();
+        let mut queue = ReportQueue::new();
+        let mut next = produced.iter();
+        let mut sent = Vec::new();
+        let mut now = 0;
+        loop {
+            let mut more = false;
+            while !queue.is_full() {
+                match next.next() {
+                    Some(report) => queue.push(*report, now as u16).unwrap(),
+                    None => break,
+                }
+                more = true;
+            }
+            if queue.is_full() {
+                let report = key_report(0x27);
+                assert_eq!(queue.push(report, 0), Err(report));
+            }
+            // One report per host poll
+            match queue.pending(now, 0) {
+                Some((report, _)) => {
+                    sent.push(*report);
+                    queue.mark_sent(now);
+                }
+                None if !more => break,
+                None => {}
+            }
+            now += 1;
+        }
+        assert_eq!(sent, produced);
+    }
+
+    #[test]
+    fn idle_rate_resends_the_current_state() {
+        let mut queue = ReportQueue::new();
+        queue.push(key_report(0x04), 7).unwrap();
+        assert_eq!(queue.pending(10, 40), Some((&key_report(0x04), Some(7))));
+        queue.mark_sent(10);
+        assert_eq!(queue.pending(49, 40), None);
+        assert_eq!(queue.pending(50, 40), Some((&key_report(0x04), None)));
+        queue.mark_sent(50);
+        assert_eq!(queue.pending(89, 40), None);
+        assert_eq!(queue.pending(90, 40), Some((&key_report(0x04), None)));
+        // Idle 0 means reports are only sent on change
+        assert_eq!(queue.pending(1000, 0), None);
+        // A change goes out at once, and restarts the idle period
+        queue.push([0; 8], 8).unwrap();
+        assert_eq!(queue.pending(60, 40), Some((&[0; 8], Some(8))));
+        queue.mark_sent(60);
+        assert_eq!(queue.pending(99, 40), None);
+        assert_eq!(queue.pending(100, 40), Some((&[0; 8], None)));
+    }
+}
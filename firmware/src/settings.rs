@@ -0,0 +1,119 @@
+// User settings kept across power cycles.
+//
+// Saved page layout (storage::SETTINGS_PAGE):
+//   0  magic "SET1"
+//   4  CRC-32 of the body
+//   8  body: Unicode input mode (u8)
+
+use crate::crc;
+use crate::storage::{self, Storage, SETTINGS_PAGE};
+use crate::unicode;
+
+const MAGIC: u32 = 0x3154_4553;
+const HEADER_SIZE: usize = 8;
+const BODY_SIZE: usize = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Settings {
+    pub unicode_mode: unicode::Mode,
+}
+
+impl Settings {
+    pub fn new() -> Self {
+        Settings {
+            unicode_mode: unicode::Mode::Linux,
+        }
+    }
+
+    // The saved settings, or the defaults if the page is blank or damaged.
+    // Values this firmware does not know keep their defaults.
+    pub fn load<S: Storage>(flash: &S) -> Self {
+        let page = flash.read(SETTINGS_PAGE, HEADER_SIZE + BODY_SIZE);
+        let (header, body) = page.split_at(HEADER_SIZE);
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let mut settings = Settings::new();
+        if magic != MAGIC || crc != crc::crc32(body) {
+            return settings;
+        }
+        if let Some(mode) = unicode::Mode::from_u8(body[0]) {
+            settings.unicode_mode = mode;
+        }
+        settings
+    }
+
+    // Erases and rewrites the settings page, stalling the CPU for the
+    // ~20 ms page erase.
+    pub fn save<S: Storage>(&self, flash: &mut S) -> Result<(), storage::Error> {
+        let mut page = [0u8; HEADER_SIZE + BODY_SIZE];
+        let (header, body) = page.split_at_mut(HEADER_SIZE);
+        body[0] = self.unicode_mode.to_u8();
+        header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&crc::crc32(body).to_le_bytes());
+        flash.erase_page(SETTINGS_PAGE)?;
+        flash.program(SETTINGS_PAGE, &page)
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{RamStorage, PAGE_SIZE};
+
+    fn flash() -> RamStorage {
+        RamStorage::new(SETTINGS_PAGE, PAGE_SIZE as usize)
+    }
+
+    fn saved(unicode_mode: unicode::Mode) -> RamStorage {
+        let mut flash = flash();
+        Settings { unicode_mode }.save(&mut flash).unwrap();
+        flash
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut flash = flash();
+        for mode in [
+            unicode::Mode::MacOs,
+            unicode::Mode::WinCompose,
+            unicode::Mode::Linux,
+        ] {
+            // Saving again replaces the page
+            let settings = Settings { unicode_mode: mode };
+            settings.save(&mut flash).unwrap();
+            assert_eq!(Settings::load(&flash), settings);
+        }
+        let page = flash.read(SETTINGS_PAGE, HEADER_SIZE + BODY_SIZE);
+        assert_eq!(&page[0..4], b"SET1");
+        assert_eq!(page[4..8], crc::crc32(&page[HEADER_SIZE..]).to_le_bytes());
+    }
+
+    #[test]
+    fn corrupt_crc_loads_defaults() {
+        let mut flash = saved(unicode::Mode::MacOs);
+        flash.bytes_mut(SETTINGS_PAGE + 4, 1)[0] ^= 0x01;
+        assert_eq!(Settings::load(&flash), Settings::new());
+    }
+
+    #[test]
+    fn erased_page_loads_defaults() {
+        assert_eq!(Settings::load(&flash()), Settings::new());
+    }
+
+    #[test]
+    fn unknown_mode_keeps_the_default() {
+        // As saved by a newer firmware with a fourth mode
+        let mut flash = saved(unicode::Mode::MacOs);
+        let page = flash.bytes_mut(SETTINGS_PAGE, HEADER_SIZE + BODY_SIZE);
+        page[HEADER_SIZE] = 3;
+        let crc = crc::crc32(&page[HEADER_SIZE..]);
+        page[4..8].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(Settings::load(&flash).unicode_mode, unicode::Mode::Linux);
+    }
+}
@@ -0,0 +1,83 @@
+// Flash as the modules that keep data in it see it. The firmware's
+// flash::Flash programs the MCU's flash; tests use RamStorage.
+
+pub const PAGE_SIZE: u32 = 1024;
+
+// Data pages above the update staging area (see memory.x). Firmware
+// updates leave them alone.
+pub const MACRO_PAGE: u32 = 0x0801_F800;
+pub const SETTINGS_PAGE: u32 = 0x0801_FC00;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    Program,
+    WriteProtected,
+    Verify,
+}
+
+pub trait Storage {
+    // Sets the page at `addr`, a multiple of PAGE_SIZE, to 0xFF.
+    fn erase_page(&mut self, addr: u32) -> Result<(), Error>;
+
+    // Programs `data` at the even address `addr` one half-word at a time.
+    // An odd last byte is padded with 0xFF.
+    fn program(&mut self, addr: u32, data: &[u8]) -> Result<(), Error>;
+
+    // The `len` bytes at `addr`, as they are in flash now
+    fn read(&self, addr: u32, len: usize) -> &[u8];
+}
+
+// Flash simulated in RAM, covering `len` bytes from `base`
+#[cfg(test)]
+pub struct RamStorage {
+    base: u32,
+    bytes: Vec<u8>,
+}
+
+#[cfg(test)]
+impl RamStorage {
+    pub fn new(base: u32, len: usize) -> Self {
+        RamStorage {
+            base,
+            bytes: vec![0xFF; len],
+        }
+    }
+
+    fn offset(&self, addr: u32) -> usize {
+        (addr - self.base) as usize
+    }
+
+    pub fn bytes_mut(&mut self, addr: u32, len: usize) -> &mut [u8] {
+        let start = self.offset(addr);
+        &mut self.bytes[start..start + len]
+    }
+}
+
+#[cfg(test)]
+impl Storage for RamStorage {
+    fn erase_page(&mut self, addr: u32) -> Result<(), Error> {
+        assert!(addr.is_multiple_of(PAGE_SIZE));
+        self.bytes_mut(addr, PAGE_SIZE as usize).fill(0xFF);
+        Ok(())
+    }
+
+    // Like the real flash, a half-word can only be programmed once after
+    // an erase.
+    fn program(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        assert!(addr & 1 == 0);
+        let dst = self.bytes_mut(addr, (data.len() + 1) & !1);
+        for (dst, src) in dst.chunks_exact_mut(2).zip(data.chunks(2)) {
+            if dst != [0xFF, 0xFF] {
+                return Err(Error::Program);
+            }
+            dst[0] = src[0];
+            dst[1] = src.get(1).copied().unwrap_or(0xFF);
+        }
+        Ok(())
+    }
+
+    fn read(&self, addr: u32, len: usize) -> &[u8] {
+        let start = self.offset(addr);
+        &self.bytes[start..start + len]
+    }
+}
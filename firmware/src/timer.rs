@@ -0,0 +1,28 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use cortex_m::peripheral::syst::SystClkSource;
+use cortex_m::peripheral::SYST;
+use cortex_m_rt::exception;
+
+// see setup_clock()
+const CORE_CLOCK_HZ: u32 = 72_000_000;
+
+static MILLIS: AtomicU32 = AtomicU32::new(0);
+
+pub fn init(mut syst: SYST) {
+    syst.set_clock_source(SystClkSource::Core);
+    syst.set_reload(CORE_CLOCK_HZ / 1000 - 1);
+    syst.clear_current();
+    syst.enable_interrupt();
+    syst.enable_counter();
+}
+
+// Milliseconds since init(). Wraps after ~49 days; compare with wrapping_sub.
+pub fn now() -> u32 {
+    MILLIS.load(Ordering::Relaxed)
+}
+
+#[exception]
+fn SysTick() {
+    MILLIS.fetch_add(1, Ordering::Relaxed);
+}
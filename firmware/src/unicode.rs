@@ -0,0 +1,159 @@
+// Types Unicode code points through the host's own input method. Each mode
+// is the key sequence one host turns into a character; the host has to be
+// set up for it.
+
+use crate::keycode::Keycode;
+use crate::recording::Recording;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    // IBus: Ctrl+Shift+U, the hex digits, then Space
+    Linux,
+    // The "Unicode Hex Input" source: hex digits typed with Option held,
+    // four per UTF-16 code unit
+    MacOs,
+    // WinCompose with its default compose key, Right Alt: compose, U, the
+    // hex digits, then Enter
+    WinCompose,
+}
+
+impl Mode {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Mode::Linux => 0,
+            Mode::MacOs => 1,
+            Mode::WinCompose => 2,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Mode::Linux),
+            1 => Some(Mode::MacOs),
+            2 => Some(Mode::WinCompose),
+            _ => None,
+        }
+    }
+}
+
+fn tap(events: &mut Recording, key: Keycode) {
+    events.push(key, true);
+    events.push(key, false);
+}
+
+// Lowercase, zero-padded to at least `digits` digits
+fn hex(events: &mut Recording, value: u32, digits: usize) {
+    let len = (8 - value.leading_zeros() as usize / 4).max(digits);
+    for shift in (0..len).rev() {
+        let digit = b"0123456789abcdef"[(value >> (shift * 4)) as usize & 0xf];
+        if let Some((key, _)) = Keycode::from_ascii(digit) {
+            tap(events, key);
+        }
+    }
+}
+
+// The presses and releases that type `c` in `mode`, for the macro player.
+pub fn sequence(c: char, mode: Mode) -> Recording {
+    let mut events = Recording::new();
+    match mode {
+        Mode::Linux => {
+            events.push(Keycode::LeftCtrl, true);
+            events.push(Keycode::LeftShift, true);
+            tap(&mut events, Keycode::U);
+            events.push(Keycode::LeftShift, false);
+            events.push(Keycode::LeftCtrl, false);
+            hex(&mut events, c as u32, 1);
+            tap(&mut events, Keycode::Space);
+        }
+        Mode::MacOs => {
+            events.push(Keycode::LeftAlt, true);
+            for unit in c.encode_utf16(&mut [0; 2]) {
+                hex(&mut events, *unit as u32, 4);
+            }
+            events.push(Keycode::LeftAlt, false);
+        }
+        Mode::WinCompose => {
+            tap(&mut events, Keycode::RightAlt);
+            tap(&mut events, Keycode::U);
+            hex(&mut events, c as u32, 1);
+            tap(&mut events, Keycode::Enter);
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events(c: char, mode: Mode) -> Vec<(Keycode, bool)> {
+        let recording = sequence(c, mode);
+        (0..).map_while(|idx| recording.get(idx)).collect()
+    }
+
+    fn taps(keys: &[Keycode]) -> Vec<(Keycode, bool)> {
+        keys.iter()
+            .flat_map(|&key| vec![(key, true), (key, false)])
+            .collect()
+    }
+
+    #[test]
+    fn linux_types_ctrl_shift_u() {
+        let mut expected = vec![
+            (Keycode::LeftCtrl, true),
+            (Keycode::LeftShift, true),
+            (Keycode::U, true),
+            (Keycode::U, false),
+            (Keycode::LeftShift, false),
+            (Keycode::LeftCtrl, false),
+        ];
+        expected.extend(taps(&[Keycode::E, Keycode::N9, Keycode::Space]));
+        assert_eq!(events('é', Mode::Linux), expected);
+    }
+
+    #[test]
+    fn macos_types_utf16_units_with_option_held() {
+        let mut expected = vec![(Keycode::LeftAlt, true)];
+        expected.extend(taps(&[Keycode::N0, Keycode::N0, Keycode::E, Keycode::N9]));
+        expected.push((Keycode::LeftAlt, false));
+        assert_eq!(events('é', Mode::MacOs), expected);
+
+        // U+1F600 is the surrogate pair D83D DE00
+        let mut expected = vec![(Keycode::LeftAlt, true)];
+        expected.extend(taps(&[
+            Keycode::D,
+            Keycode::N8,
+            Keycode::N3,
+            Keycode::D,
+            Keycode::D,
+            Keycode::E,
+            Keycode::N0,
+            Keycode::N0,
+        ]));
+        expected.push((Keycode::LeftAlt, false));
+        assert_eq!(events('\u{1F600}', Mode::MacOs), expected);
+    }
+
+    #[test]
+    fn wincompose_types_compose_u() {
+        let expected = taps(&[
+            Keycode::RightAlt,
+            Keycode::U,
+            Keycode::N1,
+            Keycode::F,
+            Keycode::N6,
+            Keycode::N0,
+            Keycode::N0,
+            Keycode::Enter,
+        ]);
+        assert_eq!(events('\u{1F600}', Mode::WinCompose), expected);
+    }
+
+    #[test]
+    fn mode_round_trips_through_u8() {
+        for &mode in &[Mode::Linux, Mode::MacOs, Mode::WinCompose] {
+            assert_eq!(Mode::from_u8(mode.to_u8()), Some(mode));
+        }
+        assert_eq!(Mode::from_u8(3), None);
+    }
+}
@@ -0,0 +1,367 @@
+// In-application firmware update over vendor control requests.
+//
+// UPDATE_BEGIN  (OUT, 8 bytes)  image length (u32 LE) and CRC-32 (u32 LE)
+// UPDATE_WRITE  (OUT, <= 64 bytes) image data at offset wIndex << 16 | wValue
+// UPDATE_COMMIT (OUT, no data) verify the staged image
+// UPDATE_STATUS (IN, 6 bytes)   state, error, bytes written (u32 LE)
+//
+// The image is the application slot from APP_BASE, that is the firmware
+// binary without its first page, the boot stub (objcopy -R .boot). It is
+// written into the staging area first, so a transfer that is cut off leaves
+// the running application untouched. Once UPDATE_COMMIT has verified it,
+// install() records it on INSTALL_PAGE and the MCU is reset.
+//
+// Copying is left to the boot stub at 0x08002000 (see boot.rs in the
+// firmware), which dapboot starts instead of the application and which
+// updates never erase. While the record is there, the stub copies the
+// staged image over the application slot on every reset, and it erases
+// the record only when the copy is complete. Power loss during the copy
+// just makes the next boot start it again.
+//
+// Install record (INSTALL_PAGE):
+//   0  magic "INS1", programmed last
+//   4  image length (u32)
+
+use crate::crc;
+use crate::storage::{self, Storage, PAGE_SIZE};
+
+pub const UPDATE_BEGIN: u8 = 0x01;
+pub const UPDATE_WRITE: u8 = 0x02;
+pub const UPDATE_COMMIT: u8 = 0x03;
+pub const UPDATE_STATUS: u8 = 0x04;
+
+// must match FLASH in memory.x
+pub const APP_BASE: u32 = 0x0800_2400;
+pub const SLOT_SIZE: u32 = 58 * 1024;
+pub const STAGING_BASE: u32 = APP_BASE + SLOT_SIZE;
+pub const INSTALL_PAGE: u32 = STAGING_BASE + SLOT_SIZE;
+pub const INSTALL_MAGIC: u32 = 0x3153_4E49;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    Idle,
+    Receiving,
+    Verified,
+}
+impl State {
+    pub fn bits(&self) -> u8 {
+        use State::*;
+        match self {
+            Idle => 0,
+            Receiving => 1,
+            Verified => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    BadRequest,
+    TooLarge,
+    OutOfOrder,
+    Incomplete,
+    CrcMismatch,
+    Flash(storage::Error),
+}
+impl Error {
+    pub fn bits(&self) -> u8 {
+        use Error::*;
+        match self {
+            BadRequest => 1,
+            TooLarge => 2,
+            OutOfOrder => 3,
+            Incomplete => 4,
+            CrcMismatch => 5,
+            Flash(storage::Error::Program) => 6,
+            Flash(storage::Error::WriteProtected) => 7,
+            Flash(storage::Error::Verify) => 8,
+        }
+    }
+}
+impl From<storage::Error> for Error {
+    fn from(err: storage::Error) -> Self {
+        Error::Flash(err)
+    }
+}
+
+pub struct Updater<S: Storage> {
+    flash: S,
+    state: State,
+    error: Option<Error>,
+    len: u32,
+    crc: u32,
+    written: u32,
+}
+
+impl<S: Storage> Updater<S> {
+    pub fn new(flash: S) -> Self {
+        Updater {
+            flash,
+            state: State::Idle,
+            error: None,
+            len: 0,
+            crc: 0,
+            written: 0,
+        }
+    }
+
+    pub fn flash(&mut self) -> &mut S {
+        &mut self.flash
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn status(&self) -> [u8; 6] {
+        let written = self.written.to_le_bytes();
+        [
+            self.state.bits(),
+            self.error.map_or(0, |err| err.bits()),
+            written[0],
+            written[1],
+            written[2],
+            written[3],
+        ]
+    }
+
+    fn record(&mut self, res: Result<(), Error>) -> Result<(), Error> {
+        if let Err(err) = res {
+            self.state = State::Idle;
+            self.error = Some(err);
+        }
+        res
+    }
+
+    pub fn begin(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.error = None;
+        let res = self.try_begin(data);
+        self.record(res)
+    }
+
+    fn try_begin(&mut self, data: &[u8]) -> Result<(), Error> {
+        if data.len() != 8 {
+            return Err(Error::BadRequest);
+        }
+        let len = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        let crc = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+        if len == 0 || len > SLOT_SIZE {
+            return Err(Error::TooLarge);
+        }
+        self.state = State::Receiving;
+        self.len = len;
+        self.crc = crc;
+        self.written = 0;
+        Ok(())
+    }
+
+    pub fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Error> {
+        let res = self.try_write(offset, data);
+        self.record(res)
+    }
+
+    fn try_write(&mut self, offset: u32, data: &[u8]) -> Result<(), Error> {
+        if self.state != State::Receiving || offset != self.written {
+            return Err(Error::OutOfOrder);
+        }
+        let end = offset + data.len() as u32;
+        if end > self.len {
+            return Err(Error::TooLarge);
+        }
+        if data.len() & 1 == 1 && end != self.len {
+            return Err(Error::BadRequest);
+        }
+
+        let mut page = offset.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        while page < end {
+            self.flash.erase_page(STAGING_BASE + page)?;
+            page += PAGE_SIZE;
+        }
+        self.flash.program(STAGING_BASE + offset, data)?;
+        self.written = end;
+        Ok(())
+    }
+
+    pub fn commit(&mut self) -> Result<(), Error> {
+        let res = self.try_commit();
+        self.record(res)
+    }
+
+    fn try_commit(&mut self) -> Result<(), Error> {
+        if self.state != State::Receiving {
+            return Err(Error::OutOfOrder);
+        }
+        if self.written != self.len {
+            return Err(Error::Incomplete);
+        }
+        let image = self.flash.read(STAGING_BASE, self.len as usize);
+        if crc::crc32(image) != self.crc {
+            return Err(Error::CrcMismatch);
+        }
+        self.state = State::Verified;
+        Ok(())
+    }
+
+    // Records the verified image for the boot stub to install on the next
+    // reset
+    pub fn install(&mut self) -> Result<(), Error> {
+        let res = self.try_install();
+        self.record(res)
+    }
+
+    fn try_install(&mut self) -> Result<(), Error> {
+        if self.state != State::Verified {
+            return Err(Error::OutOfOrder);
+        }
+        self.flash.erase_page(INSTALL_PAGE)?;
+        self.flash
+            .program(INSTALL_PAGE + 4, &self.len.to_le_bytes())?;
+        self.flash
+            .program(INSTALL_PAGE, &INSTALL_MAGIC.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::RamStorage;
+
+    fn updater() -> Updater<RamStorage> {
+        Updater::new(RamStorage::new(
+            STAGING_BASE,
+            (SLOT_SIZE + PAGE_SIZE) as usize,
+        ))
+    }
+
+    fn image(len: usize) -> Vec<u8> {
+        (0..len).map(|idx| (idx * 7 + idx / 256) as u8).collect()
+    }
+
+    fn begin_data(len: u32, crc: u32) -> [u8; 8] {
+        let mut data = [0; 8];
+        data[..4].copy_from_slice(&len.to_le_bytes());
+        data[4..].copy_from_slice(&crc.to_le_bytes());
+        data
+    }
+
+    fn send(updater: &mut Updater<RamStorage>, image: &[u8]) -> Result<(), Error> {
+        for (idx, chunk) in image.chunks(64).enumerate() {
+            updater.write(idx as u32 * 64, chunk)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn verifies_a_complete_image() {
+        // Spans a page boundary and ends on an odd byte
+        let image = image(3001);
+        let mut updater = updater();
+        updater
+            .begin(&begin_data(image.len() as u32, crc::crc32(&image)))
+            .unwrap();
+        send(&mut updater, &image).unwrap();
+        updater.commit().unwrap();
+        assert_eq!(updater.state(), State::Verified);
+        assert_eq!(updater.status(), [2, 0, 0xB9, 0x0B, 0, 0]);
+        assert_eq!(updater.flash().read(STAGING_BASE, image.len()), &image[..]);
+    }
+
+    #[test]
+    fn install_records_the_verified_image() {
+        let image = image(3001);
+        let mut updater = updater();
+        updater
+            .begin(&begin_data(image.len() as u32, crc::crc32(&image)))
+            .unwrap();
+        send(&mut updater, &image).unwrap();
+        updater.commit().unwrap();
+        // A record left by an earlier install is replaced
+        updater.flash().bytes_mut(INSTALL_PAGE, 8).fill(0);
+        updater.install().unwrap();
+        assert_eq!(
+            updater.flash().read(INSTALL_PAGE, 8),
+            [b'I', b'N', b'S', b'1', 0xB9, 0x0B, 0, 0]
+        );
+    }
+
+    #[test]
+    fn rejects_an_out_of_order_offset() {
+        let image = image(256);
+        let mut updater = updater();
+        updater
+            .begin(&begin_data(image.len() as u32, crc::crc32(&image)))
+            .unwrap();
+        updater.write(0, &image[..64]).unwrap();
+        assert_eq!(updater.write(128, &image[128..192]), Err(Error::OutOfOrder));
+        assert_eq!(updater.state(), State::Idle);
+        assert_eq!(updater.status()[..2], [0, 3]);
+        // Nothing is accepted until the next UPDATE_BEGIN
+        assert_eq!(updater.write(64, &image[64..128]), Err(Error::OutOfOrder));
+        assert_eq!(updater.commit(), Err(Error::OutOfOrder));
+    }
+
+    #[test]
+    fn rejects_an_oversize_image() {
+        let mut updater = updater();
+        assert_eq!(
+            updater.begin(&begin_data(SLOT_SIZE + 1, 0)),
+            Err(Error::TooLarge)
+        );
+        assert_eq!(updater.state(), State::Idle);
+        assert_eq!(updater.begin(&begin_data(0, 0)), Err(Error::TooLarge));
+
+        let image = image(64);
+        updater
+            .begin(&begin_data(32, crc::crc32(&image[..32])))
+            .unwrap();
+        assert_eq!(updater.write(0, &image), Err(Error::TooLarge));
+        assert_eq!(updater.status()[..2], [0, 2]);
+    }
+
+    #[test]
+    fn rejects_a_bad_crc_before_install() {
+        let image = image(1500);
+        let mut updater = updater();
+        updater
+            .begin(&begin_data(image.len() as u32, !crc::crc32(&image)))
+            .unwrap();
+        send(&mut updater, &image).unwrap();
+        assert_eq!(updater.commit(), Err(Error::CrcMismatch));
+        assert_eq!(updater.state(), State::Idle);
+        assert_eq!(updater.status()[..2], [0, 5]);
+        // Nothing is recorded for the boot stub
+        assert_eq!(updater.install(), Err(Error::OutOfOrder));
+        assert_eq!(updater.flash().read(INSTALL_PAGE, 4), [0xFF; 4]);
+    }
+
+    #[test]
+    fn rejects_an_incomplete_image() {
+        let image = image(1500);
+        let mut updater = updater();
+        updater
+            .begin(&begin_data(image.len() as u32, crc::crc32(&image)))
+            .unwrap();
+        send(&mut updater, &image[..1024]).unwrap();
+        assert_eq!(updater.commit(), Err(Error::Incomplete));
+        assert_eq!(updater.install(), Err(Error::OutOfOrder));
+        assert_eq!(updater.flash().read(INSTALL_PAGE, 4), [0xFF; 4]);
+    }
+
+    #[test]
+    fn rejects_an_odd_chunk_before_the_end() {
+        let image = image(256);
+        let mut updater = updater();
+        updater
+            .begin(&begin_data(image.len() as u32, crc::crc32(&image)))
+            .unwrap();
+        assert_eq!(updater.write(0, &image[..63]), Err(Error::BadRequest));
+    }
+
+    #[test]
+    fn begin_needs_length_and_crc() {
+        let mut updater = updater();
+        assert_eq!(updater.begin(&[0; 4]), Err(Error::BadRequest));
+        assert_eq!(updater.status()[..2], [0, 1]);
+    }
+}
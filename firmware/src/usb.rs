@@ -0,0 +1,1134 @@
+// The control pipe on EP0, shared by every interface of the device.
+// Register and packet memory access go through `Endpoints`, so the same
+// state machine runs against the USB peripheral and against the simulated
+// one in the tests below.
+
+use core::cmp;
+
+use crate::cursor::{ReadCursor, WriteCursor};
+use crate::descr;
+
+#[derive(Debug, PartialEq)]
+pub enum Direction {
+    HostToDevice,
+    DeviceToHost,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Type {
+    Standard,
+    Class,
+    Vendor,
+    Reserved,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Recipient {
+    Device,
+    Interface,
+    Endpoint,
+    Other,
+    Reserved,
+}
+
+#[repr(C, packed)]
+#[derive(Debug)]
+pub struct BmRequestType(u8);
+impl BmRequestType {
+    #[inline]
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    #[inline]
+    pub fn direction(&self) -> Direction {
+        if self.bits() & 0x80 == 0 {
+            Direction::HostToDevice
+        } else {
+            Direction::DeviceToHost
+        }
+    }
+
+    #[inline]
+    pub fn request_type(&self) -> Type {
+        match (self.bits() >> 5) & 0b11 {
+            0 => Type::Standard,
+            1 => Type::Class,
+            2 => Type::Vendor,
+            3 => Type::Reserved,
+            _ => unreachable!(),
+        }
+    }
+
+    #[inline]
+    pub fn recipient(&self) -> Recipient {
+        match self.bits() & 0b11111 {
+            0 => Recipient::Device,
+            1 => Recipient::Interface,
+            2 => Recipient::Endpoint,
+            3 => Recipient::Other,
+            _ => Recipient::Reserved,
+        }
+    }
+}
+
+#[repr(C, packed)]
+#[allow(non_snake_case)]
+pub struct DeviceRequest {
+    pub bmRequestType: BmRequestType,
+    pub bRequest: u8,
+    pub wValue: u16,
+    pub wIndex: u16,
+    pub wLength: u16,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct EPAddr(u8);
+impl EPAddr {
+    pub fn new(bits: u8) -> Self {
+        EPAddr(bits)
+    }
+
+    pub fn from(dir: Direction, ep_id: u8) -> Self {
+        match dir {
+            Direction::DeviceToHost => Self::new(ep_id | 0x80),
+            Direction::HostToDevice => Self::new(ep_id),
+        }
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub fn dir(&self) -> Direction {
+        if self.bits() & 0x80 == 0 {
+            Direction::HostToDevice
+        } else {
+            Direction::DeviceToHost
+        }
+    }
+
+    pub fn ep_id(&self) -> u8 {
+        self.bits() & 0x7f
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EPType {
+    Bulk,
+    Control,
+    Isochronous,
+    Interrupt,
+}
+impl EPType {
+    pub fn bits(&self) -> u8 {
+        use EPType::*;
+        match self {
+            Bulk => 0b00,
+            Control => 0b01,
+            Isochronous => 0b10,
+            Interrupt => 0b11,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EPStat {
+    Disabled,
+    Stall,
+    Nak,
+    Valid,
+}
+impl EPStat {
+    pub fn bits(&self) -> u8 {
+        use EPStat::*;
+        match self {
+            Disabled => 0b00,
+            Stall => 0b01,
+            Nak => 0b10,
+            Valid => 0b11,
+        }
+    }
+
+    pub fn from_bits(bits: u8) -> Self {
+        use EPStat::*;
+        match bits & 0b11 {
+            0b00 => Disabled,
+            0b01 => Stall,
+            0b10 => Nak,
+            _ => Valid,
+        }
+    }
+}
+
+// Endpoint registers and packet memory of one USB peripheral
+pub trait Endpoints {
+    // Allocates packet memory for the endpoint, then NAKs IN tokens (IN and
+    // control endpoints) and accepts OUT tokens (OUT endpoints).
+    fn configure(&mut self, addr: EPAddr, ep_type: EPType, size: u16);
+    fn set_address(&mut self, addr: u8);
+    // Whether the last packet received on the endpoint was a SETUP
+    fn is_setup(&self, ep_id: u8) -> bool;
+    fn stat_tx(&self, ep_id: u8) -> EPStat;
+    fn stat_rx(&self, ep_id: u8) -> EPStat;
+    fn set_stat_tx(&mut self, ep_id: u8, stat: EPStat);
+    fn set_stat_rx(&mut self, ep_id: u8, stat: EPStat);
+    fn clear_ctr_tx(&mut self, ep_id: u8);
+    fn clear_ctr_rx(&mut self, ep_id: u8);
+    // Copies `buf` into the endpoint's TX buffer and sets its count
+    fn write_pm(&mut self, ep_id: u8, buf: &[u8]);
+    // Copies the received packet into `buf` as far as it fits and returns
+    // the received length
+    fn read_pm(&mut self, ep_id: u8, buf: &mut [u8]) -> usize;
+
+    // Fails while the previous packet is still waiting for the host.
+    fn write_packet(&mut self, addr: EPAddr, buf: &[u8]) -> Option<()> {
+        if self.stat_tx(addr.ep_id()) == EPStat::Valid {
+            return None;
+        }
+        self.write_pm(addr.ep_id(), buf);
+        self.set_stat_tx(addr.ep_id(), EPStat::Valid);
+        Some(())
+    }
+
+    // Fails while no packet has been received.
+    fn read_packet(&mut self, addr: EPAddr, buf: &mut [u8]) -> Option<usize> {
+        if self.stat_rx(addr.ep_id()) == EPStat::Valid {
+            return None;
+        }
+        let len = cmp::min(buf.len(), self.read_pm(addr.ep_id(), buf));
+        self.clear_ctr_rx(addr.ep_id());
+        self.set_stat_rx(addr.ep_id(), EPStat::Valid);
+        Some(len)
+    }
+
+    fn stall(&mut self, addr: EPAddr) {
+        if addr.ep_id() == 0 {
+            self.set_stat_tx(0, EPStat::Stall);
+        }
+        match addr.dir() {
+            Direction::HostToDevice => self.set_stat_rx(addr.ep_id(), EPStat::Stall),
+            Direction::DeviceToHost => self.set_stat_tx(addr.ep_id(), EPStat::Stall),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RequestStatus {
+    NotSupported,
+    Handled,
+}
+
+// The requests Control leaves to the device: everything but SET_ADDRESS and
+// GET_DESCRIPTOR.
+pub trait Handler<E: Endpoints> {
+    // A request to the host; the reply goes in `wcur`.
+    fn read_request(&mut self, req: &DeviceRequest, wcur: &mut WriteCursor) -> RequestStatus;
+    // A request from the host, with its data stage in `data`, which is
+    // empty for requests without one.
+    fn write_request(&mut self, ep: &mut E, req: &DeviceRequest, data: &[u8]) -> RequestStatus;
+    // The status stage of a handled request from the host has completed.
+    fn status_complete(&mut self);
+}
+
+pub struct Descriptors<'a> {
+    pub device: &'a descr::DeviceDescriptor,
+    pub config: &'a [u8],
+    pub hid_report: &'a [u8],
+    // String descriptors 1, 2, ...
+    pub strings: &'a [&'a str],
+}
+
+unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
+    core::slice::from_raw_parts((p as *const T) as *const u8, core::mem::size_of::<T>())
+}
+
+// EP0 recovery:
+// - a SETUP aborts whatever EP0 was doing, including Stalled. The pending IN
+//   packet is dropped, STAT_TX goes back to NAK and the request starts from Idle.
+// - an OUT ZLP during DataIn/LastDataIn is an early status stage and ends the
+//   transfer. An OUT packet with data there, or in StatusOut, stalls EP0.
+// - any other token that does not fit the state stalls EP0 until the next SETUP.
+// - a bus reset returns the buffer and drops a pending SET_ADDRESS.
+#[allow(dead_code)]
+enum ControlState<'a> {
+    Idle {
+        buf: &'a mut [u8],
+    },
+    Stalled {
+        buf: &'a mut [u8],
+    },
+    DataIn {
+        cur: ReadCursor<'a>,
+        req: DeviceRequest,
+    },
+    LastDataIn {
+        cur: ReadCursor<'a>,
+        req: DeviceRequest,
+    },
+    StatusIn {
+        buf: &'a mut [u8],
+    },
+    DataOut {
+        cur: WriteCursor<'a>,
+        req: DeviceRequest,
+    },
+    LastDataOut {
+        cur: WriteCursor<'a>,
+        req: DeviceRequest,
+    },
+    StatusOut {
+        buf: &'a mut [u8],
+    },
+}
+impl<'a> ControlState<'a> {
+    fn into_buf(self) -> &'a mut [u8] {
+        use ControlState::*;
+        match self {
+            Idle { buf } => buf,
+            Stalled { buf } => buf,
+            DataIn { cur, .. } => cur.into(),
+            LastDataIn { cur, .. } => cur.into(),
+            StatusIn { buf } => buf,
+            DataOut { cur, .. } => cur.into(),
+            LastDataOut { cur, .. } => cur.into(),
+            StatusOut { buf } => buf,
+        }
+    }
+}
+
+pub struct Control<'a> {
+    descriptors: Descriptors<'a>,
+    state: ControlState<'a>,
+    pending_addr: Option<u8>,
+}
+
+impl<'a> Control<'a> {
+    pub fn new(descriptors: Descriptors<'a>, buf: &'a mut [u8]) -> Self {
+        Control {
+            descriptors,
+            state: ControlState::Idle { buf },
+            pending_addr: None,
+        }
+    }
+
+    fn max_packet_size(&self) -> usize {
+        self.descriptors.device.bMaxPacketSize0 as usize
+    }
+
+    // After a bus reset: EP0 is set up again and the device is back at
+    // address 0.
+    pub fn reset<E: Endpoints>(&mut self, ep: &mut E) {
+        self.transition(|_this, state| ControlState::Idle {
+            buf: state.into_buf(),
+        });
+        self.pending_addr = None;
+        ep.configure(
+            EPAddr::new(0),
+            EPType::Control,
+            self.max_packet_size() as u16,
+        );
+        ep.set_address(0);
+    }
+
+    fn transition<F>(&mut self, cb: F)
+    where
+        F: FnOnce(&mut Self, ControlState<'a>) -> ControlState<'a>,
+    {
+        let state = core::mem::replace(&mut self.state, ControlState::Idle { buf: &mut [] });
+        self.state = cb(self, state);
+    }
+
+    pub fn handle_out<E: Endpoints, H: Handler<E>>(&mut self, ep: &mut E, handler: &mut H) {
+        if ep.is_setup(0) {
+            self.abort(ep);
+            self.handle_setup(ep, handler);
+            return;
+        }
+
+        use ControlState::*;
+        self.transition(|this, state| match state {
+            DataIn { cur, .. } | LastDataIn { cur, .. } => {
+                ep.set_stat_tx(0, EPStat::Nak);
+                Self::status_out(ep, cur.into_buf())
+            }
+            DataOut { cur, req } => this.recv_chunk(ep, handler, cur, req),
+            StatusOut { buf, .. } => Self::status_out(ep, buf),
+            _ => Self::stall(ep, state.into_buf()),
+        });
+    }
+
+    // Only a ZLP is a status stage from the host.
+    fn status_out<E: Endpoints>(ep: &mut E, buf: &'a mut [u8]) -> ControlState<'a> {
+        let mut data = [0u8; 1];
+        match ep.read_packet(EPAddr::new(0), &mut data) {
+            Some(0) => ControlState::Idle { buf },
+            _ => Self::stall(ep, buf),
+        }
+    }
+
+    fn abort<E: Endpoints>(&mut self, ep: &mut E) {
+        ep.set_stat_tx(0, EPStat::Nak);
+        ep.clear_ctr_tx(0);
+        self.pending_addr = None;
+        self.transition(|_this, state| ControlState::Idle {
+            buf: state.into_buf(),
+        });
+    }
+
+    fn read_req<E: Endpoints>(ep: &mut E) -> Option<DeviceRequest> {
+        let mut buf = [0u8; core::mem::size_of::<DeviceRequest>()];
+        let len = ep.read_packet(EPAddr::new(0), &mut buf)?;
+        if len != buf.len() {
+            return None;
+        }
+        Some(unsafe { core::mem::transmute::<[u8; 8], DeviceRequest>(buf) })
+    }
+
+    fn stall<E: Endpoints>(ep: &mut E, buf: &'a mut [u8]) -> ControlState<'a> {
+        ep.stall(EPAddr::new(0));
+        ControlState::Stalled { buf }
+    }
+
+    fn handle_setup<E: Endpoints, H: Handler<E>>(&mut self, ep: &mut E, handler: &mut H) {
+        let req = match Self::read_req(ep) {
+            Some(req) => req,
+            None => {
+                self.transition(|_this, state| Self::stall(ep, state.into_buf()));
+                return;
+            }
+        };
+        match req.bmRequestType.direction() {
+            Direction::DeviceToHost => self.setup_read(ep, handler, req),
+            Direction::HostToDevice if req.wLength == 0 => self.setup_no_data(ep, handler, req),
+            Direction::HostToDevice => self.setup_write(req),
+        }
+    }
+
+    fn setup_read<E: Endpoints, H: Handler<E>>(
+        &mut self,
+        ep: &mut E,
+        handler: &mut H,
+        req: DeviceRequest,
+    ) {
+        self.transition(|this, state| {
+            let buf = state.into_buf();
+            let mut wcur = WriteCursor::new(buf);
+            match this.read_request(handler, &req, &mut wcur) {
+                RequestStatus::NotSupported => Self::stall(ep, wcur.into_buf()),
+                RequestStatus::Handled => {
+                    if req.wLength == 0 {
+                        if ep.write_packet(EPAddr::new(0), &[]).is_none() {
+                            return Self::stall(ep, wcur.into_buf());
+                        }
+                        ControlState::StatusIn {
+                            buf: wcur.into_buf(),
+                        }
+                    } else {
+                        let cur = wcur.into_read();
+                        this.send_chunk(ep, cur, req)
+                    }
+                }
+            }
+        });
+    }
+
+    fn setup_no_data<E: Endpoints, H: Handler<E>>(
+        &mut self,
+        ep: &mut E,
+        handler: &mut H,
+        req: DeviceRequest,
+    ) {
+        self.transition(|this, state| this.finish_write(ep, handler, state.into_buf(), 0, req));
+    }
+
+    fn setup_write(&mut self, req: DeviceRequest) {
+        self.transition(|_this, state| {
+            let buf = state.into_buf();
+            ControlState::DataOut {
+                cur: WriteCursor::new(buf),
+                req,
+            }
+        });
+    }
+
+    fn recv_chunk<E: Endpoints, H: Handler<E>>(
+        &mut self,
+        ep: &mut E,
+        handler: &mut H,
+        mut cur: WriteCursor<'a>,
+        req: DeviceRequest,
+    ) -> ControlState<'a> {
+        let mut chunk = [0u8; 64];
+        let len = ep.read_packet(EPAddr::new(0), &mut chunk).unwrap_or(0);
+        let written = cur.write(&chunk[0..len]);
+
+        if written < len {
+            // wLength does not fit in the control buffer
+            return Self::stall(ep, cur.into_buf());
+        }
+        if cur.len() < req.wLength as usize && len == self.max_packet_size() {
+            return ControlState::DataOut { cur, req };
+        }
+
+        let data_len = cur.len();
+        self.finish_write(ep, handler, cur.into_buf(), data_len, req)
+    }
+
+    // Handles a request from the host once its data, if any, is in
+    // buf[0..data_len], and starts the status stage.
+    fn finish_write<E: Endpoints, H: Handler<E>>(
+        &mut self,
+        ep: &mut E,
+        handler: &mut H,
+        buf: &'a mut [u8],
+        data_len: usize,
+        req: DeviceRequest,
+    ) -> ControlState<'a> {
+        match self.write_request(ep, handler, &req, &buf[0..data_len]) {
+            RequestStatus::NotSupported => Self::stall(ep, buf),
+            RequestStatus::Handled => {
+                if ep.write_packet(EPAddr::new(0), &[]).is_none() {
+                    return Self::stall(ep, buf);
+                }
+                ControlState::StatusIn { buf }
+            }
+        }
+    }
+
+    fn read_request<E: Endpoints, H: Handler<E>>(
+        &self,
+        handler: &mut H,
+        req: &DeviceRequest,
+        wcur: &mut WriteCursor,
+    ) -> RequestStatus {
+        match (req.bmRequestType.request_type(), req.bRequest) {
+            // GET_DESCRIPTOR
+            (Type::Standard, 0x06) => self.get_descriptor(req, wcur),
+            _ => handler.read_request(req, wcur),
+        }
+    }
+
+    fn get_descriptor(&self, req: &DeviceRequest, wcur: &mut WriteCursor) -> RequestStatus {
+        let mut str_buf = [0u8; 64];
+        let descr_index = req.wValue & 0xff;
+        let bytes = match req.wValue & 0xff00 {
+            0x0100 => unsafe { any_as_u8_slice(self.descriptors.device) },
+            0x0200 => self.descriptors.config,
+            0x0300 => {
+                if descr_index == 0 {
+                    descr::STRING_DESCR0
+                } else {
+                    let str_data = match self.descriptors.strings.get(descr_index as usize - 1) {
+                        Some(str_data) => str_data,
+                        None => return RequestStatus::NotSupported,
+                    };
+                    match descr::build_string_descr(&mut str_buf, str_data) {
+                        Some(len) => &str_buf[0..len],
+                        None => return RequestStatus::NotSupported,
+                    }
+                }
+            }
+            0x2200 => self.descriptors.hid_report,
+            _ => {
+                return RequestStatus::NotSupported;
+            }
+        };
+        let len = cmp::min(req.wLength as usize, bytes.len());
+        wcur.write(&bytes[0..len]);
+        RequestStatus::Handled
+    }
+
+    fn write_request<E: Endpoints, H: Handler<E>>(
+        &mut self,
+        ep: &mut E,
+        handler: &mut H,
+        req: &DeviceRequest,
+        data: &[u8],
+    ) -> RequestStatus {
+        match (req.bmRequestType.request_type(), req.bRequest) {
+            (Type::Standard, 0x05) => {
+                // SET_ADDRESS
+                self.pending_addr = Some(req.wValue as u8);
+                RequestStatus::Handled
+            }
+            _ => handler.write_request(ep, req, data),
+        }
+    }
+
+    pub fn handle_in<E: Endpoints, H: Handler<E>>(&mut self, ep: &mut E, handler: &mut H) {
+        use ControlState::*;
+        self.transition(|this, state| match state {
+            DataIn { cur, req } => this.send_chunk(ep, cur, req),
+            LastDataIn { cur, .. } => {
+                ep.set_stat_rx(0, EPStat::Valid);
+                let buf = cur.into_buf();
+                ControlState::StatusOut { buf }
+            }
+            StatusIn { buf, .. } => {
+                if let Some(addr) = this.pending_addr.take() {
+                    ep.set_address(addr);
+                }
+                handler.status_complete();
+                ControlState::Idle { buf }
+            }
+            _ => Self::stall(ep, state.into_buf()),
+        });
+    }
+
+    fn send_chunk<E: Endpoints>(
+        &mut self,
+        ep: &mut E,
+        mut cur: ReadCursor<'a>,
+        req: DeviceRequest,
+    ) -> ControlState<'a> {
+        let max_packet_size = self.max_packet_size();
+        let chunk = cur.read(max_packet_size);
+        let chunk_len = chunk.len();
+        if ep.write_packet(EPAddr::new(0), chunk).is_none() {
+            return Self::stall(ep, cur.into_buf());
+        }
+
+        if max_packet_size > chunk_len {
+            ControlState::LastDataIn { cur, req }
+        } else {
+            ControlState::DataIn { cur, req }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const BUF_SIZE: usize = 128;
+
+    static DEVICE: descr::DeviceDescriptor = descr::DeviceDescriptor {
+        bLength: 18,
+        bDescriptorType: 1,
+        bcdUSB: 0x0200,
+        bDeviceClass: 0,
+        bDeviceSubClass: 0,
+        bDeviceProtocol: 0,
+        bMaxPacketSize0: 64,
+        idVendor: 0x0483,
+        idProduct: 0x5710,
+        bcdDevice: 0x0200,
+        iManufacturer: 1,
+        iProduct: 2,
+        iSerialNumber: 3,
+        bNumConfigurations: 1,
+    };
+    // Longer than one packet, so GET_DESCRIPTOR has a DataIn stage
+    static CONFIG: [u8; 100] = [0x5A; 100];
+    static HID_REPORT: [u8; 64] = [0xA5; 64];
+    static STRINGS: [&str; 3] = ["KOBA789", "KB789 MK-C", "789"];
+
+    fn descriptors() -> Descriptors<'static> {
+        Descriptors {
+            device: &DEVICE,
+            config: &CONFIG,
+            hid_report: &HID_REPORT,
+            strings: &STRINGS,
+        }
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    struct SimEp {
+        stat_tx: EPStat,
+        stat_rx: EPStat,
+        setup: bool,
+        tx: [u8; 64],
+        tx_len: usize,
+        rx: [u8; 64],
+        rx_len: usize,
+    }
+
+    impl SimEp {
+        fn new() -> Self {
+            SimEp {
+                stat_tx: EPStat::Disabled,
+                stat_rx: EPStat::Disabled,
+                setup: false,
+                tx: [0; 64],
+                tx_len: 0,
+                rx: [0; 64],
+                rx_len: 0,
+            }
+        }
+    }
+
+    // The peripheral as the host sees it. Tokens change the endpoint status
+    // the way the hardware does: an accepted OUT or SETUP leaves STAT_RX at
+    // NAK, a sent IN packet leaves STAT_TX at NAK, and a SETUP is accepted
+    // whatever STAT_RX is.
+    struct Sim {
+        eps: [SimEp; 8],
+        addr: u8,
+    }
+
+    impl Sim {
+        fn new() -> Self {
+            Sim {
+                eps: [SimEp::new(); 8],
+                addr: 0,
+            }
+        }
+
+        fn bus_reset(&mut self) {
+            *self = Sim::new();
+        }
+
+        fn receive(&mut self, data: &[u8], setup: bool) {
+            let ep = &mut self.eps[0];
+            let len = cmp::min(data.len(), ep.rx.len());
+            ep.rx[..len].copy_from_slice(&data[..len]);
+            ep.rx_len = len;
+            ep.setup = setup;
+            ep.stat_rx = EPStat::Nak;
+        }
+
+        fn setup(&mut self, data: &[u8]) {
+            self.receive(data, true);
+        }
+
+        // Whether the device took the packet rather than NAKing or stalling
+        fn out(&mut self, data: &[u8]) -> bool {
+            if self.eps[0].stat_rx != EPStat::Valid {
+                return false;
+            }
+            self.receive(data, false);
+            true
+        }
+
+        fn in_token(&mut self) -> Option<Vec<u8>> {
+            let ep = &mut self.eps[0];
+            if ep.stat_tx != EPStat::Valid {
+                return None;
+            }
+            ep.stat_tx = EPStat::Nak;
+            Some(ep.tx[..ep.tx_len].to_vec())
+        }
+    }
+
+    impl Endpoints for Sim {
+        fn configure(&mut self, addr: EPAddr, ep_type: EPType, _size: u16) {
+            let ep = &mut self.eps[addr.ep_id() as usize];
+            if addr.dir() == Direction::DeviceToHost || ep_type == EPType::Control {
+                ep.stat_tx = EPStat::Nak;
+            }
+            if addr.dir() == Direction::HostToDevice {
+                ep.stat_rx = EPStat::Valid;
+            }
+        }
+
+        fn set_address(&mut self, addr: u8) {
+            self.addr = addr;
+        }
+
+        fn is_setup(&self, ep_id: u8) -> bool {
+            self.eps[ep_id as usize].setup
+        }
+
+        fn stat_tx(&self, ep_id: u8) -> EPStat {
+            self.eps[ep_id as usize].stat_tx
+        }
+
+        fn stat_rx(&self, ep_id: u8) -> EPStat {
+            self.eps[ep_id as usize].stat_rx
+        }
+
+        fn set_stat_tx(&mut self, ep_id: u8, stat: EPStat) {
+            self.eps[ep_id as usize].stat_tx = stat;
+        }
+
+        fn set_stat_rx(&mut self, ep_id: u8, stat: EPStat) {
+            self.eps[ep_id as usize].stat_rx = stat;
+        }
+
+        fn clear_ctr_tx(&mut self, _ep_id: u8) {}
+
+        fn clear_ctr_rx(&mut self, _ep_id: u8) {}
+
+        fn write_pm(&mut self, ep_id: u8, buf: &[u8]) {
+            let ep = &mut self.eps[ep_id as usize];
+            assert!(buf.len() <= ep.tx.len(), "packet larger than the TX buffer");
+            ep.tx[..buf.len()].copy_from_slice(buf);
+            ep.tx_len = buf.len();
+        }
+
+        fn read_pm(&mut self, ep_id: u8, buf: &mut [u8]) -> usize {
+            let ep = &self.eps[ep_id as usize];
+            let len = cmp::min(buf.len(), ep.rx_len);
+            buf[..len].copy_from_slice(&ep.rx[..len]);
+            ep.rx_len
+        }
+    }
+
+    // Class and vendor requests 0-3 reply with 0, 1, 64 and 200 bytes and
+    // requests from the host are taken when bRequest is even, so every
+    // path through the state machine is reachable.
+    struct TestHandler {
+        completed: usize,
+    }
+
+    impl Handler<Sim> for TestHandler {
+        fn read_request(&mut self, req: &DeviceRequest, wcur: &mut WriteCursor) -> RequestStatus {
+            let reply_len = match (req.bmRequestType.request_type(), req.bRequest) {
+                (Type::Standard, _) => return RequestStatus::NotSupported,
+                (_, 0) => 0,
+                (_, 1) => 1,
+                (_, 2) => 64,
+                (_, 3) => 200,
+                _ => return RequestStatus::NotSupported,
+            };
+            let reply = [0x33; 200];
+            wcur.write(&reply[..cmp::min(reply_len, req.wLength as usize)]);
+            RequestStatus::Handled
+        }
+
+        fn write_request(
+            &mut self,
+            ep: &mut Sim,
+            req: &DeviceRequest,
+            _data: &[u8],
+        ) -> RequestStatus {
+            if req.bmRequestType.request_type() == Type::Standard && req.bRequest == 0x09 {
+                // SET_CONFIGURATION
+                ep.configure(EPAddr::new(0x81), EPType::Interrupt, 8);
+            }
+            if req.bRequest & 1 == 0 {
+                RequestStatus::Handled
+            } else {
+                RequestStatus::NotSupported
+            }
+        }
+
+        fn status_complete(&mut self) {
+            self.completed += 1;
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    enum Token {
+        Setup(Vec<u8>),
+        Out(Vec<u8>),
+        In,
+        BusReset,
+    }
+
+    struct Device<'a> {
+        sim: Sim,
+        ctrl: Control<'a>,
+        handler: TestHandler,
+    }
+
+    impl<'a> Device<'a> {
+        fn new(buf: &'a mut [u8]) -> Self {
+            let mut device = Device {
+                sim: Sim::new(),
+                ctrl: Control::new(descriptors(), buf),
+                handler: TestHandler { completed: 0 },
+            };
+            device.ctrl.reset(&mut device.sim);
+            device
+        }
+
+        // Feeds one token the way usb_poll dispatches the interrupt it raises.
+        // Returns false when the device NAKed or stalled it.
+        fn token(&mut self, token: &Token) -> bool {
+            match token {
+                Token::Setup(data) => {
+                    self.sim.setup(data);
+                    self.ctrl.handle_out(&mut self.sim, &mut self.handler);
+                }
+                Token::Out(data) => {
+                    if !self.sim.out(data) {
+                        return false;
+                    }
+                    self.ctrl.handle_out(&mut self.sim, &mut self.handler);
+                }
+                Token::In => {
+                    if self.sim.in_token().is_none() {
+                        return false;
+                    }
+                    self.sim.clear_ctr_tx(0);
+                    self.ctrl.handle_in(&mut self.sim, &mut self.handler);
+                }
+                Token::BusReset => {
+                    self.sim.bus_reset();
+                    self.ctrl.reset(&mut self.sim);
+                }
+            }
+            true
+        }
+
+        fn state(&self) -> &'static str {
+            use ControlState::*;
+            match self.ctrl.state {
+                Idle { .. } => "Idle",
+                Stalled { .. } => "Stalled",
+                DataIn { .. } => "DataIn",
+                LastDataIn { .. } => "LastDataIn",
+                StatusIn { .. } => "StatusIn",
+                DataOut { .. } => "DataOut",
+                LastDataOut { .. } => "LastDataOut",
+                StatusOut { .. } => "StatusOut",
+            }
+        }
+
+        // Whatever the state, the host can make progress: the token the
+        // transfer waits for is not NAKed, and a stalled EP0 stalls both
+        // directions until the next SETUP.
+        fn check_not_wedged(&self) {
+            let ep0 = &self.sim.eps[0];
+            match self.state() {
+                "DataIn" | "LastDataIn" | "StatusIn" => assert_eq!(ep0.stat_tx, EPStat::Valid),
+                "DataOut" | "StatusOut" => assert_eq!(ep0.stat_rx, EPStat::Valid),
+                "Stalled" => {
+                    assert_eq!(ep0.stat_tx, EPStat::Stall);
+                    assert_eq!(ep0.stat_rx, EPStat::Stall);
+                }
+                "Idle" => {}
+                state => panic!("unexpected state {}", state),
+            }
+        }
+
+        // Acts as a host finishing the transfer in progress: IN tokens for
+        // the data and status stages to the host, ZLPs from the host.
+        fn finish_transfer(&mut self) {
+            for _ in 0..BUF_SIZE / 64 + 3 {
+                let token = match self.state() {
+                    "Idle" | "Stalled" => return,
+                    "DataIn" | "LastDataIn" | "StatusIn" => Token::In,
+                    _ => Token::Out(Vec::new()),
+                };
+                assert!(
+                    self.token(&token),
+                    "{:?} refused in {}",
+                    token,
+                    self.state()
+                );
+                self.check_not_wedged();
+            }
+            panic!("transfer did not finish, still in {}", self.state());
+        }
+
+        fn buf_len(&self) -> usize {
+            match &self.ctrl.state {
+                ControlState::Idle { buf } | ControlState::Stalled { buf } => buf.len(),
+                _ => panic!("buffer is still in use in {}", self.state()),
+            }
+        }
+    }
+
+    fn setup_packet(request_type: u8, request: u8, value: u16, index: u16, length: u16) -> Vec<u8> {
+        let mut packet = vec![request_type, request];
+        packet.extend_from_slice(&value.to_le_bytes());
+        packet.extend_from_slice(&index.to_le_bytes());
+        packet.extend_from_slice(&length.to_le_bytes());
+        packet
+    }
+
+    fn get_descriptor(value: u16, length: u16) -> Token {
+        Token::Setup(setup_packet(0x80, 0x06, value, 0, length))
+    }
+
+    // Reads a descriptor the way a host does after attaching
+    fn read_device_descriptor(device: &mut Device) -> Vec<u8> {
+        assert!(device.token(&get_descriptor(0x0100, 18)));
+        let data = device.sim.in_token().expect("no data stage");
+        device.sim.clear_ctr_tx(0);
+        device.ctrl.handle_in(&mut device.sim, &mut device.handler);
+        assert!(device.token(&Token::Out(Vec::new())));
+        assert_eq!(device.state(), "Idle");
+        data
+    }
+
+    #[test]
+    fn get_descriptor_in_two_packets() {
+        let mut buf = [0u8; BUF_SIZE];
+        let mut device = Device::new(&mut buf);
+        device.token(&get_descriptor(0x0200, 255));
+        assert_eq!(device.state(), "DataIn");
+        assert_eq!(device.sim.in_token().unwrap(), &CONFIG[..64]);
+        device.ctrl.handle_in(&mut device.sim, &mut device.handler);
+        assert_eq!(device.state(), "LastDataIn");
+        assert_eq!(device.sim.in_token().unwrap(), &CONFIG[64..]);
+        device.ctrl.handle_in(&mut device.sim, &mut device.handler);
+        assert_eq!(device.state(), "StatusOut");
+        assert!(device.token(&Token::Out(Vec::new())));
+        assert_eq!(device.state(), "Idle");
+    }
+
+    #[test]
+    fn string_descriptors() {
+        let mut buf = [0u8; BUF_SIZE];
+        let mut device = Device::new(&mut buf);
+        device.token(&get_descriptor(0x0303, 255));
+        assert_eq!(
+            device.sim.in_token().unwrap(),
+            [8, 3, b'7', 0, b'8', 0, b'9', 0]
+        );
+        // Past the last string
+        device.token(&get_descriptor(0x0304, 255));
+        assert_eq!(device.state(), "Stalled");
+        device.token(&get_descriptor(0x03FF, 255));
+        assert_eq!(device.state(), "Stalled");
+    }
+
+    #[test]
+    fn short_setup_stalls() {
+        let mut buf = [0u8; BUF_SIZE];
+        let mut device = Device::new(&mut buf);
+        device.token(&Token::Setup(vec![0x80, 0x06, 0x00, 0x01]));
+        assert_eq!(device.state(), "Stalled");
+        assert_eq!(read_device_descriptor(&mut device).len(), 18);
+    }
+
+    #[test]
+    fn set_address_after_status_stage() {
+        let mut buf = [0u8; BUF_SIZE];
+        let mut device = Device::new(&mut buf);
+        device.token(&Token::Setup(setup_packet(0x00, 0x05, 7, 0, 0)));
+        assert_eq!(device.state(), "StatusIn");
+        assert_eq!(device.sim.addr, 0);
+        assert!(device.token(&Token::In));
+        assert_eq!(device.sim.addr, 7);
+        assert_eq!(device.state(), "Idle");
+    }
+
+    #[test]
+    fn setup_drops_pending_address() {
+        let mut buf = [0u8; BUF_SIZE];
+        let mut device = Device::new(&mut buf);
+        device.token(&Token::Setup(setup_packet(0x00, 0x05, 7, 0, 0)));
+        read_device_descriptor(&mut device);
+        assert_eq!(device.sim.addr, 0);
+    }
+
+    #[test]
+    fn early_status_ends_data_in() {
+        let mut buf = [0u8; BUF_SIZE];
+        let mut device = Device::new(&mut buf);
+        device.token(&get_descriptor(0x0200, 255));
+        assert!(device.token(&Token::Out(Vec::new())));
+        assert_eq!(device.state(), "Idle");
+        assert_eq!(device.sim.eps[0].stat_tx, EPStat::Nak);
+    }
+
+    #[test]
+    fn data_out_larger_than_buffer_stalls() {
+        let mut buf = [0u8; BUF_SIZE];
+        let mut device = Device::new(&mut buf);
+        device.token(&Token::Setup(setup_packet(0x40, 0x02, 0, 0, 192)));
+        for _ in 0..2 {
+            assert!(device.token(&Token::Out(vec![0; 64])));
+            assert_eq!(device.state(), "DataOut");
+        }
+        assert!(device.token(&Token::Out(vec![0; 64])));
+        assert_eq!(device.state(), "Stalled");
+        assert_eq!(device.buf_len(), BUF_SIZE);
+    }
+
+    #[test]
+    fn no_data_request_completes() {
+        let mut buf = [0u8; BUF_SIZE];
+        let mut device = Device::new(&mut buf);
+        device.token(&Token::Setup(setup_packet(0x40, 0x02, 0, 0, 0)));
+        assert!(device.token(&Token::In));
+        assert_eq!(device.handler.completed, 1);
+        // Odd requests are refused
+        device.token(&Token::Setup(setup_packet(0x40, 0x03, 0, 0, 0)));
+        assert_eq!(device.state(), "Stalled");
+        assert_eq!(device.handler.completed, 1);
+    }
+
+    fn setup_token() -> impl Strategy<Value = Token> {
+        let request = (
+            prop::sample::select(vec![0x00, 0x01, 0x21, 0x41, 0x80, 0x81, 0xA1, 0xC0, 0xE0]),
+            0u8..12,
+            prop_oneof![
+                prop::sample::select(vec![0x0100, 0x0200, 0x0300, 0x0301, 0x0303, 0x0304, 0x2200]),
+                any::<u16>(),
+            ],
+            0u16..3,
+            prop_oneof![0u16..300, any::<u16>()],
+        )
+            .prop_map(|(request_type, request, value, index, length)| {
+                Token::Setup(setup_packet(request_type, request, value, index, length))
+            });
+        prop_oneof![
+            4 => request,
+            1 => prop::collection::vec(any::<u8>(), 0..=12).prop_map(Token::Setup),
+        ]
+    }
+
+    fn token() -> impl Strategy<Value = Token> {
+        prop_oneof![
+            3 => setup_token(),
+            3 => prop::collection::vec(any::<u8>(), 0..=64).prop_map(Token::Out),
+            3 => Just(Token::In),
+            1 => Just(Token::BusReset),
+        ]
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(2000))]
+
+        #[test]
+        fn control_pipe_survives_any_token_sequence(
+            tokens in prop::collection::vec(token(), 0..40),
+        ) {
+            let mut buf = [0u8; BUF_SIZE];
+            let mut device = Device::new(&mut buf);
+            for token in &tokens {
+                device.token(token);
+                device.check_not_wedged();
+            }
+            device.finish_transfer();
+            prop_assert_eq!(device.buf_len(), BUF_SIZE);
+            // EP0 still answers the first request of an enumeration
+            let descr = read_device_descriptor(&mut device);
+            prop_assert_eq!(descr.len(), 18);
+            prop_assert_eq!(descr[0], 18);
+            prop_assert_eq!(device.buf_len(), BUF_SIZE);
+        }
+
+        #[test]
+        fn setup_recovers_from_any_token_sequence(
+            tokens in prop::collection::vec(token(), 0..40),
+        ) {
+            let mut buf = [0u8; BUF_SIZE];
+            let mut device = Device::new(&mut buf);
+            for token in &tokens {
+                device.token(token);
+            }
+            // A host that gives up on a transfer just sends the next SETUP
+            let descr = read_device_descriptor(&mut device);
+            prop_assert_eq!(descr.len(), 18);
+            prop_assert_eq!(device.buf_len(), BUF_SIZE);
+        }
+
+        #[test]
+        fn out_data_before_status_stalls(
+            in_packets in 0usize..=2,
+            data in prop::collection::vec(any::<u8>(), 1..=64),
+        ) {
+            let mut buf = [0u8; BUF_SIZE];
+            let mut device = Device::new(&mut buf);
+            // DataIn, LastDataIn or StatusOut
+            device.token(&get_descriptor(0x0200, 255));
+            for _ in 0..in_packets {
+                prop_assert!(device.token(&Token::In));
+            }
+            prop_assert!(device.token(&Token::Out(data)));
+            prop_assert_eq!(device.state(), "Stalled");
+            device.check_not_wedged();
+            let descr = read_device_descriptor(&mut device);
+            prop_assert_eq!(descr.len(), 18);
+        }
+    }
+}